@@ -0,0 +1,196 @@
+//! Throughput of the map against lock-protected `HashMap`s, for the workloads
+//! sync.Map is designed for and one it is not.
+//!
+//! Run with `cargo bench`, optionally followed by `-- <filter>` to only run
+//! the workloads or maps whose name contains the filter. Each run does a fixed
+//! number of operations split across the threads, and reports the wall time
+//! per operation.
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    sync::{Barrier, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use sync_map::{map::SyncMap, sharded::ShardedSyncMap};
+
+const KEYS: u64 = 1 << 16;
+const OPS: u64 = 1 << 22;
+const THREADS: [u64; 4] = [1, 4, 16, 64];
+
+trait Map: Sync + Default {
+    const NAME: &'static str;
+
+    fn load(&self, key: u64) -> bool;
+    fn store(&self, key: u64, value: u64);
+}
+
+impl Map for SyncMap<u64, u64> {
+    const NAME: &'static str = "SyncMap";
+
+    fn load(&self, key: u64) -> bool {
+        SyncMap::load(self, &key).is_some()
+    }
+
+    fn store(&self, key: u64, value: u64) {
+        SyncMap::store(self, key, value);
+    }
+}
+
+impl Map for ShardedSyncMap<u64, u64> {
+    const NAME: &'static str = "ShardedSyncMap";
+
+    fn load(&self, key: u64) -> bool {
+        ShardedSyncMap::load(self, &key).is_some()
+    }
+
+    fn store(&self, key: u64, value: u64) {
+        ShardedSyncMap::store(self, key, value);
+    }
+}
+
+impl Map for Mutex<HashMap<u64, u64>> {
+    const NAME: &'static str = "Mutex<HashMap>";
+
+    fn load(&self, key: u64) -> bool {
+        self.lock().unwrap().contains_key(&key)
+    }
+
+    fn store(&self, key: u64, value: u64) {
+        self.lock().unwrap().insert(key, value);
+    }
+}
+
+impl Map for RwLock<HashMap<u64, u64>> {
+    const NAME: &'static str = "RwLock<HashMap>";
+
+    fn load(&self, key: u64) -> bool {
+        self.read().unwrap().contains_key(&key)
+    }
+
+    fn store(&self, key: u64, value: u64) {
+        self.write().unwrap().insert(key, value);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Workload {
+    // Keys written once and then only read, as in a cache that only grows.
+    ReadHeavy,
+    // Every thread reads and overwrites its own disjoint set of keys.
+    DisjointWrites,
+    // One store for every nine loads, over keys shared by all threads.
+    Mixed,
+}
+
+impl Workload {
+    const ALL: [Workload; 3] = [
+        Workload::ReadHeavy,
+        Workload::DisjointWrites,
+        Workload::Mixed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Workload::ReadHeavy => "read-heavy",
+            Workload::DisjointWrites => "disjoint-writes",
+            Workload::Mixed => "mixed",
+        }
+    }
+
+    fn op(self, map: &impl Map, thread: u64, threads: u64, rng: &mut u64) {
+        let r = xorshift(rng);
+        match self {
+            Workload::ReadHeavy => {
+                black_box(map.load(r % KEYS));
+            }
+            Workload::DisjointWrites => {
+                // Thread t owns the keys equal to t modulo the thread count.
+                let key = (r % KEYS) / threads * threads + thread;
+                if r >> 32 & 1 == 0 {
+                    map.store(key, r);
+                } else {
+                    black_box(map.load(key));
+                }
+            }
+            Workload::Mixed => {
+                if (r >> 32).is_multiple_of(10) {
+                    map.store(r % KEYS, r);
+                } else {
+                    black_box(map.load(r % KEYS));
+                }
+            }
+        }
+    }
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+// Runs the workload on a prefilled map and returns the time per operation.
+fn run<M: Map>(workload: Workload, threads: u64) -> Duration {
+    let map = M::default();
+    for key in 0..KEYS {
+        map.store(key, key);
+    }
+    // Get the keys promoted, as a long-running map would have them.
+    for key in 0..KEYS {
+        map.load(key);
+    }
+
+    let barrier = Barrier::new(threads as usize + 1);
+    let elapsed = thread::scope(|s| {
+        for t in 0..threads {
+            let (map, barrier) = (&map, &barrier);
+            s.spawn(move || {
+                let mut rng = (t + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+                barrier.wait();
+                for _ in 0..OPS / threads {
+                    workload.op(map, t, threads, &mut rng);
+                }
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        let start = Instant::now();
+        barrier.wait();
+        start.elapsed()
+    });
+    elapsed / (OPS / threads * threads) as u32
+}
+
+fn bench<M: Map>(workload: Workload, filter: Option<&str>) {
+    if let Some(filter) = filter {
+        if !workload.name().contains(filter) && !M::NAME.contains(filter) {
+            return;
+        }
+    }
+    print!("{:<16} {:<16}", workload.name(), M::NAME);
+    for threads in THREADS {
+        print!(" {:>9.1?}", run::<M>(workload, threads));
+    }
+    println!();
+}
+
+fn main() {
+    // Cargo passes `--bench`; anything else is a filter.
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let filter = filter.as_deref();
+
+    print!("{:<16} {:<16}", "workload", "map");
+    for threads in THREADS {
+        print!(" {:>9}", format!("{threads} thr"));
+    }
+    println!();
+    for workload in Workload::ALL {
+        bench::<SyncMap<u64, u64>>(workload, filter);
+        bench::<ShardedSyncMap<u64, u64>>(workload, filter);
+        bench::<Mutex<HashMap<u64, u64>>>(workload, filter);
+        bench::<RwLock<HashMap<u64, u64>>>(workload, filter);
+    }
+}
@@ -0,0 +1,84 @@
+//! Values updated in place, for maps of counters.
+use std::sync::atomic::{
+    AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32, AtomicU64,
+    AtomicU8, AtomicUsize,
+};
+
+use crate::order::{ACQUIRE, ACQ_REL};
+
+/// An atomic integer that a [`SyncMap`] can update in place, through
+/// [`SyncMap::fetch_add`] and friends, rather than storing a new value for
+/// every update.
+///
+/// Updates are acquire-release operations, and arithmetic wraps around on
+/// overflow, as with the std atomics this is implemented for.
+///
+/// [`SyncMap`]: crate::map::SyncMap
+/// [`SyncMap::fetch_add`]: crate::map::SyncMap::fetch_add
+pub trait AtomicValue: Default {
+    /// The integer the atomic holds.
+    type Value: Copy;
+
+    /// Returns the current value.
+    fn load(&self) -> Self::Value;
+
+    /// Adds `delta`, returning the previous value.
+    fn fetch_add(&self, delta: Self::Value) -> Self::Value;
+
+    /// Adds `delta`, returning the new value.
+    fn add_fetch(&self, delta: Self::Value) -> Self::Value;
+
+    /// Replaces the value with `f(value)` until no concurrent update got in
+    /// between, unless `f` returns `None`. Returns the previous value, as
+    /// `Err` if `f` returned `None`.
+    fn fetch_update(
+        &self,
+        f: impl FnMut(Self::Value) -> Option<Self::Value>,
+    ) -> Result<Self::Value, Self::Value>;
+}
+
+macro_rules! atomic_value {
+    ($($atomic:ty => $value:ty),* $(,)?) => {
+        $(
+            impl AtomicValue for $atomic {
+                type Value = $value;
+
+                #[inline]
+                fn load(&self) -> $value {
+                    <$atomic>::load(self, ACQUIRE)
+                }
+
+                #[inline]
+                fn fetch_add(&self, delta: $value) -> $value {
+                    <$atomic>::fetch_add(self, delta, ACQ_REL)
+                }
+
+                #[inline]
+                fn add_fetch(&self, delta: $value) -> $value {
+                    <$atomic>::fetch_add(self, delta, ACQ_REL).wrapping_add(delta)
+                }
+
+                #[inline]
+                fn fetch_update(
+                    &self,
+                    f: impl FnMut($value) -> Option<$value>,
+                ) -> Result<$value, $value> {
+                    <$atomic>::fetch_update(self, ACQ_REL, ACQUIRE, f)
+                }
+            }
+        )*
+    };
+}
+
+atomic_value! {
+    AtomicU8 => u8,
+    AtomicU16 => u16,
+    AtomicU32 => u32,
+    AtomicU64 => u64,
+    AtomicUsize => usize,
+    AtomicI8 => i8,
+    AtomicI16 => i16,
+    AtomicI32 => i32,
+    AtomicI64 => i64,
+    AtomicIsize => isize,
+}
@@ -0,0 +1,536 @@
+//! A concurrent ordered map, with the same design as [`SyncMap`].
+//!
+//! [`SyncMap`]: crate::map::SyncMap
+use std::{
+    borrow::Borrow,
+    collections::{btree_map, BTreeMap},
+    fmt,
+    ops::{Bound, RangeBounds},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU64},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    entry::Entry,
+    key::{Key, KeyQuery, Query},
+    map::{Ref, RefPair},
+    order::{ACQUIRE, RELAXED, RELEASE},
+    policy::{MissThreshold, PromotionPolicy},
+    reclaim::{self, Collector, Guard},
+};
+
+type Map<K, V> = BTreeMap<Key<K>, Arc<Entry<V>>>;
+
+struct ReadOnly<K, V> {
+    m: Map<K, V>,
+
+    // True if the dirty map contains some key not in m. Only set with the
+    // dirty lock held.
+    amended: AtomicBool,
+}
+
+/// A concurrent map ordered by key, for range scans over e.g. time-bucketed
+/// keys.
+///
+/// It works like [`SyncMap`]: lookups and range scans go through a read map
+/// without taking a lock, and new keys go to a dirty map, which is promoted
+/// to the read map once lookups have missed the read map often enough. Both
+/// maps are B-trees. A range scan promotes the dirty map first, so it sees
+/// every key present when it starts.
+///
+/// [`SyncMap`]: crate::map::SyncMap
+pub struct SyncBTreeMap<K, V> {
+    // Always safe to load, but only stored with the dirty lock held. Points
+    // to a boxed read map; replaced ones are retired into `collector`.
+    read: AtomicPtr<ReadOnly<K, V>>,
+
+    // Holds every non-expunged entry of the read map, plus the keys added
+    // since the last promotion.
+    dirty: Mutex<Option<Map<K, V>>>,
+
+    misses: AtomicU64,
+    policy: MissThreshold,
+    collector: Collector,
+}
+
+unsafe impl<K, V> Send for SyncBTreeMap<K, V>
+where
+    K: Send,
+    V: Send,
+{
+}
+
+unsafe impl<K, V> Sync for SyncBTreeMap<K, V>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+{
+}
+
+impl<K: Ord, V> Default for SyncBTreeMap<K, V> {
+    fn default() -> Self {
+        SyncBTreeMap::new()
+    }
+}
+
+impl<K: Ord, V> SyncBTreeMap<K, V> {
+    pub fn new() -> Self {
+        SyncBTreeMap::with_read(BTreeMap::new())
+    }
+
+    fn with_read(m: Map<K, V>) -> Self {
+        SyncBTreeMap {
+            read: AtomicPtr::new(Self::new_readonly(m)),
+            dirty: Mutex::new(None),
+            misses: AtomicU64::new(0),
+            policy: MissThreshold::default(),
+            collector: Collector::new(),
+        }
+    }
+
+    fn new_readonly(m: Map<K, V>) -> *mut ReadOnly<K, V> {
+        Box::into_raw(Box::new(ReadOnly {
+            m,
+            amended: AtomicBool::new(false),
+        }))
+    }
+
+    #[inline]
+    fn load_readonly<'g>(&self, _guard: &'g Guard) -> &'g ReadOnly<K, V> {
+        // Retired read maps outlive every guard that could have loaded them.
+        unsafe { &*self.read.load(ACQUIRE) }
+    }
+
+    // Entries only leave the maps along with a retired read map, so an entry
+    // found under the lock stays valid after it is released.
+    #[inline]
+    fn entry_ref<'g>(e: &Arc<Entry<V>>, _guard: &'g Guard) -> &'g Entry<V> {
+        unsafe { &*Arc::as_ptr(e) }
+    }
+
+    /// Returns the value stored in the map for a key.
+    pub fn load<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let value: *const V = self.find_entry(key, &guard)?.load(&guard)?;
+        Some(unsafe { Ref::new(guard, value) })
+    }
+
+    /// Returns whether the map holds a value for a key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let guard = reclaim::pin();
+        self.find_entry(key, &guard)
+            .is_some_and(|e| e.load(&guard).is_some())
+    }
+
+    // Looks up the entry for a key, falling back to the dirty map if the read
+    // map is amended.
+    fn find_entry<'g, Q>(&self, key: &Q, guard: &'g Guard) -> Option<&'g Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let read = self.load_readonly(guard);
+        if let Some(e) = read.m.get(Query(key).as_dyn()) {
+            return Some(Self::entry_ref(e, guard));
+        }
+        if !read.amended.load(RELAXED) {
+            return None;
+        }
+
+        let mut dirty = self.dirty.lock();
+        // Avoid a spurious miss if the dirty map got promoted while we were
+        // blocked on the lock.
+        let read = self.load_readonly(guard);
+        if let Some(e) = read.m.get(Query(key).as_dyn()) {
+            return Some(Self::entry_ref(e, guard));
+        }
+        if !read.amended.load(RELAXED) {
+            return None;
+        }
+        let e = dirty
+            .as_ref()
+            .and_then(|d| d.get(Query(key).as_dyn()))
+            .map(|e| Self::entry_ref(e, guard));
+        self.miss_locked(&mut dirty);
+        e
+    }
+
+    /// Sets the value for a key.
+    pub fn store(&self, key: K, value: V) {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_swap(value, &guard, &self.collector) {
+                    Ok(_) => break,
+                    Err(v) => value = v,
+                }
+            }
+
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard) else {
+                self.insert_locked(key, Entry::new(value), &mut dirty, &guard);
+                break;
+            };
+            drop(dirty);
+
+            match e.try_swap(value, &guard, &self.collector) {
+                Ok(_) => break,
+                // Expunged again by a promotion since we released the lock.
+                Err(v) => value = v,
+            }
+        }
+        drop(guard);
+
+        self.collector.collect();
+    }
+
+    /// Returns the existing value for the key if present. Otherwise, stores
+    /// and returns the given value. The boolean is true if the value was
+    /// loaded, false if stored.
+    pub fn load_or_store(&self, key: K, value: V) -> (Ref<'_, V>, bool) {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_load_or_store(value, &guard) {
+                    Ok((actual, loaded)) => {
+                        let actual: *const V = actual;
+                        return (unsafe { Ref::new(guard, actual) }, loaded);
+                    }
+                    Err(v) => value = v,
+                }
+            }
+
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard) else {
+                let e = self.insert_locked(key, Entry::new(value), &mut dirty, &guard);
+                let actual: *const V = e.load(&guard).unwrap();
+                return (unsafe { Ref::new(guard, actual) }, false);
+            };
+            drop(dirty);
+
+            match e.try_load_or_store(value, &guard) {
+                Ok((actual, loaded)) => {
+                    let actual: *const V = actual;
+                    return (unsafe { Ref::new(guard, actual) }, loaded);
+                }
+                Err(v) => value = v,
+            }
+        }
+    }
+
+    /// Deletes the value for a key, returning it if there was one.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let previous = self
+            .find_entry(key, &guard)?
+            .delete(&guard, &self.collector)
+            .map(ptr::from_ref);
+        let res = previous.map(|v| unsafe { Ref::new(guard, v) });
+        self.collector.collect();
+        res
+    }
+
+    /// Returns an iterator over the keys in `range` that hold a value, in
+    /// order.
+    ///
+    /// The dirty map is promoted first, so every key present at the time of
+    /// the call is visited. As with [`SyncMap::iter`], a value stored or
+    /// deleted concurrently may or may not be reflected.
+    ///
+    /// # Panics
+    ///
+    /// Panics, as [`BTreeMap::range`] does, if the range starts after it
+    /// ends, or starts and ends at the same excluded bound.
+    ///
+    /// [`SyncMap::iter`]: crate::map::SyncMap::iter
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let guard = reclaim::pin();
+        let read: *const ReadOnly<K, V> = self.load_promoted(&guard);
+        let start = range.start_bound().map(Query);
+        let end = range.end_bound().map(Query);
+        let bounds: (Bound<&dyn KeyQuery<Q>>, _) = (
+            start.as_ref().map(Query::as_dyn),
+            end.as_ref().map(Query::as_dyn),
+        );
+        Range {
+            // The read map is kept alive by `guard`, which moves along with
+            // the iterator.
+            inner: unsafe { (*read).m.range::<dyn KeyQuery<Q>, _>(bounds) },
+            _guard: guard,
+        }
+    }
+
+    /// Returns an iterator over the keys that hold a value, in order. See
+    /// [`SyncBTreeMap::range`].
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range::<K, _>(..)
+    }
+
+    // Loads the read map after promoting the dirty map if needed, so that it
+    // holds every key that was present at the time of the call.
+    fn load_promoted<'g>(&self, guard: &'g Guard) -> &'g ReadOnly<K, V> {
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            return read;
+        }
+
+        let mut dirty = self.dirty.lock();
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            return read;
+        }
+        self.promote_locked(&mut dirty);
+        self.load_readonly(guard)
+    }
+
+    fn miss_locked(&self, dirty: &mut Option<Map<K, V>>) {
+        let misses = self.misses.fetch_add(1, RELAXED) + 1;
+        let dirty_len = dirty.as_ref().map_or(0, |d| d.len());
+        if self.policy.should_promote(misses, dirty_len) {
+            self.promote_locked(dirty);
+        }
+    }
+
+    // Promotes the dirty map to be the new read map.
+    fn promote_locked(&self, dirty: &mut Option<Map<K, V>>) {
+        let new = Self::new_readonly(dirty.take().unwrap_or_default());
+        let old = self.read.swap(new, RELEASE);
+        unsafe { self.collector.retire(old) };
+        self.misses.store(0, RELAXED);
+    }
+
+    // Looks up the entry for a key with the lock held, unexpunging it into
+    // the dirty map if needed.
+    fn entry_locked<'g>(
+        &self,
+        key: &K,
+        dirty: &mut Option<Map<K, V>>,
+        guard: &'g Guard,
+    ) -> Option<&'g Entry<V>> {
+        let read = self.load_readonly(guard);
+        if let Some((k, e)) = read.m.get_key_value(Query(key).as_dyn()) {
+            if e.unexpunge_locked() {
+                // The entry was expunged, which implies that there is a dirty
+                // map and the entry is not in it.
+                dirty.as_mut().unwrap().insert(k.clone(), e.clone());
+            }
+            return Some(Self::entry_ref(e, guard));
+        }
+
+        let e = dirty.as_ref()?.get(Query(key).as_dyn())?;
+        let e = Self::entry_ref(e, guard);
+        self.miss_locked(dirty);
+        Some(e)
+    }
+
+    // Adds an entry for a key missing from both maps.
+    fn insert_locked<'g>(
+        &self,
+        key: K,
+        e: Entry<V>,
+        dirty: &mut Option<Map<K, V>>,
+        guard: &'g Guard,
+    ) -> &'g Entry<V> {
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            // We're adding the first new key to the dirty map. Make sure it
+            // exists and mark the read map as incomplete.
+            self.dirty_locked(dirty, guard);
+            read.amended.store(true, RELAXED);
+        }
+        let e = Arc::new(e);
+        let r = Self::entry_ref(&e, guard);
+        dirty.as_mut().unwrap().insert(Key::new(key), e);
+        r
+    }
+
+    // Copies the read map into a new dirty map, expunging deleted entries.
+    fn dirty_locked(&self, dirty: &mut Option<Map<K, V>>, guard: &Guard) {
+        if dirty.is_some() {
+            return;
+        }
+
+        let read = self.load_readonly(guard);
+        // Copy every entry before expunging any, so that a panicking `Ord`
+        // leaves no entry expunged without a dirty map to unexpunge it into.
+        let mut m: Map<K, V> = read.m.iter().map(|(k, e)| (k.clone(), e.clone())).collect();
+        m.retain(|_, e| !e.try_expunge_locked());
+        *dirty = Some(m);
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for SyncBTreeMap<K, V> {
+    /// Builds the read map directly from the iterator, so the new map starts
+    /// out fully promoted.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let m = iter
+            .into_iter()
+            .map(|(k, v)| (Key::new(k), Arc::new(Entry::new(v))))
+            .collect();
+        SyncBTreeMap::with_read(m)
+    }
+}
+
+impl<K, V> fmt::Debug for SyncBTreeMap<K, V>
+where
+    K: Ord + fmt::Debug,
+    V: fmt::Debug,
+{
+    /// Prints the entries present in the map, in order.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        for r in self.iter() {
+            let (k, v) = r.pair();
+            m.entry(k, v);
+        }
+        m.finish()
+    }
+}
+
+impl<K, V> Drop for SyncBTreeMap<K, V> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(*self.read.get_mut())) };
+    }
+}
+
+/// An iterator over a range of entries of a [`SyncBTreeMap`], created by
+/// [`SyncBTreeMap::range`].
+pub struct Range<'a, K, V> {
+    inner: btree_map::Range<'a, Key<K>, Arc<Entry<V>>>,
+    _guard: Guard,
+}
+
+impl<'a, K, V> Range<'a, K, V> {
+    fn yield_next(
+        mut next: impl FnMut() -> Option<(&'a Key<K>, &'a Arc<Entry<V>>)>,
+    ) -> Option<RefPair<'a, K, V>> {
+        // Every item pins on its own, so it may outlive the iterator.
+        let guard = reclaim::pin();
+        while let Some((k, e)) = next() {
+            if let Some(v) = e.load(&guard) {
+                let v: *const V = v;
+                return Some(unsafe { RefPair::new(guard, k.get(), v) });
+            }
+        }
+        None
+    }
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = RefPair<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Self::yield_next(|| self.inner.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<K, V> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Self::yield_next(|| self.inner.next_back())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn load() {
+        let map = SyncBTreeMap::new();
+        assert!(map.load(&1).is_none());
+        map.store(1, "a");
+        map.store(1, "b");
+        assert_eq!(*map.load(&1).unwrap(), "b");
+        let (v, loaded) = map.load_or_store(1, "c");
+        assert_eq!((*v, loaded), ("b", true));
+        assert!(!map.load_or_store(2, "c").1);
+        assert!(map.contains_key(&2));
+
+        assert_eq!(*map.remove(&1).unwrap(), "b");
+        assert!(map.remove(&1).is_none());
+        assert!(!map.contains_key(&1));
+    }
+
+    #[test]
+    fn range() {
+        let map = SyncBTreeMap::new();
+        for i in (0..100).rev() {
+            map.store(format!("{i:03}"), i);
+        }
+        map.remove("050");
+
+        let values: Vec<_> = map
+            .range::<str, _>((Bound::Included("045"), Bound::Excluded("055")))
+            .map(|r| *r)
+            .collect();
+        assert_eq!(values, [45, 46, 47, 48, 49, 51, 52, 53, 54]);
+        let last: Vec<_> = map.iter().rev().take(2).map(|r| *r).collect();
+        assert_eq!(last, [99, 98]);
+        let first = map.range(..=String::from("001")).next().unwrap();
+        assert_eq!(first.pair(), (&String::from("000"), &0));
+    }
+
+    #[test]
+    fn from_iter() {
+        let map: SyncBTreeMap<_, _> = [(3, "c"), (1, "a"), (2, "b")].into_iter().collect();
+        assert_eq!(format!("{map:?}"), r#"{1: "a", 2: "b", 3: "c"}"#);
+        map.store(0, "z");
+        assert_eq!(*map.iter().next().unwrap(), "z");
+    }
+
+    #[test]
+    fn concurrent() {
+        let map = SyncBTreeMap::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.store(i * 4 + t, i);
+                        map.load(&(i * 4));
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..100 {
+                    // Scans stay sorted while keys are added.
+                    let keys: Vec<_> = map.iter().map(|r| *r.key()).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                }
+            });
+        });
+        assert_eq!(map.iter().count(), 4000);
+    }
+}
@@ -0,0 +1,531 @@
+//! Configuring a [`SyncMap`] before creating it.
+use std::{collections::hash_map::RandomState, hash::BuildHasher, sync::Arc};
+
+use crate::{
+    hooks::{Hooks, IntoHooks, Weigher},
+    map::SyncMap,
+    policy::{DirtyOverflow, EvictionPolicy, MissThreshold, PromotionPolicy},
+    sharded::ShardedSyncMap,
+};
+
+/// A builder for [`SyncMap`], for settings beyond the capacity and the
+/// hasher.
+///
+/// ```
+/// use sync_map::builder::SyncMapBuilder;
+///
+/// let map = SyncMapBuilder::new()
+///     .capacity(1024)
+///     .miss_threshold_factor(0.25)
+///     .build();
+/// map.store(1, "a");
+/// ```
+///
+/// Callbacks run as entries change, e.g. to keep external counts in sync:
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use sync_map::builder::SyncMapBuilder;
+///
+/// let live = Arc::new(AtomicUsize::new(0));
+/// let map = SyncMapBuilder::new()
+///     .on_insert({
+///         let live = live.clone();
+///         move |_: &u64, _: &String| {
+///             live.fetch_add(1, Ordering::Relaxed);
+///         }
+///     })
+///     .on_remove({
+///         let live = live.clone();
+///         move |_, _| {
+///             live.fetch_sub(1, Ordering::Relaxed);
+///         }
+///     })
+///     .build();
+/// map.store(1, String::from("a"));
+/// map.remove(&1);
+/// assert_eq!(live.load(Ordering::Relaxed), 0);
+/// ```
+pub struct SyncMapBuilder<S = RandomState, L = ()> {
+    capacity: usize,
+    hash_builder: S,
+    policy: Arc<dyn PromotionPolicy>,
+    compaction: Option<f64>,
+    dirty_limit: Option<(usize, DirtyOverflow)>,
+
+    // `()` or the `Hooks` set so far.
+    hooks: L,
+}
+
+impl SyncMapBuilder<RandomState> {
+    pub fn new() -> Self {
+        SyncMapBuilder {
+            capacity: 0,
+            hash_builder: RandomState::new(),
+            policy: Arc::new(MissThreshold::default()),
+            compaction: None,
+            dirty_limit: None,
+            hooks: (),
+        }
+    }
+}
+
+impl Default for SyncMapBuilder<RandomState> {
+    fn default() -> Self {
+        SyncMapBuilder::new()
+    }
+}
+
+impl<S, L> SyncMapBuilder<S, L> {
+    /// Sets the minimum capacity of the dirty map, as for
+    /// [`SyncMap::with_capacity`].
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets the hash builder used to hash keys.
+    pub fn hasher<H>(self, hash_builder: H) -> SyncMapBuilder<H, L> {
+        SyncMapBuilder {
+            capacity: self.capacity,
+            hash_builder,
+            policy: self.policy,
+            compaction: self.compaction,
+            dirty_limit: self.dirty_limit,
+            hooks: self.hooks,
+        }
+    }
+
+    /// Promotes the dirty map once the misses reach `factor` times its size.
+    /// See [`MissThreshold`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factor` is negative or NaN.
+    pub fn miss_threshold_factor(self, factor: f64) -> Self {
+        self.promotion_policy(MissThreshold::new(factor))
+    }
+
+    /// Compacts the map, as [`SyncMap::shrink_to_fit`] does, once the values
+    /// deleted since its tables were last cleaned reach `ratio` times the
+    /// number of entries in the read map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ratio` is not positive.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        assert!(ratio > 0.0, "the compaction ratio must be positive");
+        self.compaction = Some(ratio);
+        self
+    }
+
+    /// Lets the dirty map gain at most `max_len` keys between promotions.
+    /// Past that, a new key either promotes the dirty map first or is
+    /// rejected by [`SyncMap::store_with_limit`], as `overflow` says.
+    ///
+    /// The dirty map otherwise grows until misses get it promoted, which
+    /// under a burst of new keys can take long, since every load of them
+    /// falls back to the lock meanwhile. Each shard of a sharded map gets
+    /// the same limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is zero.
+    pub fn max_dirty_len(mut self, max_len: usize, overflow: DirtyOverflow) -> Self {
+        assert!(max_len > 0, "the dirty map must be able to hold a new key");
+        self.dirty_limit = Some((max_len, overflow));
+        self
+    }
+
+    /// Sets the policy that decides when the dirty map gets promoted.
+    pub fn promotion_policy(mut self, policy: impl PromotionPolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Runs `f` with the key and value after a value is stored for a key
+    /// that held none. See [`Hooks`].
+    pub fn on_insert<K, V>(
+        self,
+        f: impl Fn(&K, &V) + Send + Sync + 'static,
+    ) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        self.with_hooks(|hooks| hooks.on_insert = Some(Arc::new(f)))
+    }
+
+    /// Runs `f` with the key, the previous value and the new one after a
+    /// value is replaced. See [`Hooks`].
+    pub fn on_update<K, V>(
+        self,
+        f: impl Fn(&K, &V, &V) + Send + Sync + 'static,
+    ) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        self.with_hooks(|hooks| hooks.on_update = Some(Arc::new(f)))
+    }
+
+    /// Runs `f` with the key and value after a value is removed, including
+    /// by [`SyncMap::retain`] and [`SyncMap::remove_all`]. See [`Hooks`].
+    pub fn on_remove<K, V>(
+        self,
+        f: impl Fn(&K, &V) + Send + Sync + 'static,
+    ) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        self.with_hooks(|hooks| hooks.on_remove = Some(Arc::new(f)))
+    }
+
+    /// Runs `f` with the key and value after a bounded map evicts a key to
+    /// make room. See [`Hooks`].
+    pub fn on_evict<K, V>(
+        self,
+        f: impl Fn(&K, &V) + Send + Sync + 'static,
+    ) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        self.with_hooks(|hooks| hooks.on_evict = Some(Arc::new(f)))
+    }
+
+    /// Keeps the total weight of the map's values, as given by `weigher`,
+    /// e.g. to bound a cache of blobs by their size with
+    /// [`build_weighted`](SyncMapBuilder::build_weighted). See
+    /// [`SyncMap::weight`].
+    pub fn weigher<K, V>(self, weigher: Weigher<K, V>) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        self.with_hooks(|hooks| hooks.weigher = Some(weigher))
+    }
+
+    fn with_hooks<K, V>(self, set: impl FnOnce(&mut Hooks<K, V>)) -> SyncMapBuilder<S, Hooks<K, V>>
+    where
+        L: IntoHooks<K, V>,
+    {
+        let mut hooks = self.hooks.into_hooks();
+        set(&mut hooks);
+        SyncMapBuilder {
+            capacity: self.capacity,
+            hash_builder: self.hash_builder,
+            policy: self.policy,
+            compaction: self.compaction,
+            dirty_limit: self.dirty_limit,
+            hooks,
+        }
+    }
+
+    pub fn build<K, V>(self) -> SyncMap<K, V, S>
+    where
+        K: std::cmp::Eq + std::hash::Hash,
+        S: BuildHasher + Clone,
+        L: IntoHooks<K, V>,
+    {
+        let mut map = SyncMap::with_policy(self.capacity, self.hash_builder, self.policy);
+        map.set_hooks(self.hooks.into_hooks());
+        if let Some(ratio) = self.compaction {
+            map.set_compaction(ratio);
+        }
+        if let Some((max_len, overflow)) = self.dirty_limit {
+            map.set_dirty_limit(max_len, overflow);
+        }
+        map
+    }
+
+    /// Builds a map that holds at most `max_entries` keys, evicting the keys
+    /// `policy` picks when a new key would go past the bound.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_entries` is zero.
+    pub fn build_bounded<K, V>(
+        self,
+        max_entries: usize,
+        policy: impl EvictionPolicy<K> + 'static,
+    ) -> SyncMap<K, V, S>
+    where
+        K: std::cmp::Eq + std::hash::Hash,
+        S: BuildHasher + Clone,
+        L: IntoHooks<K, V>,
+    {
+        let capacity = self.capacity.max(max_entries);
+        let mut map = SyncMap::with_policy(capacity, self.hash_builder, self.policy);
+        map.set_bound(max_entries, Box::new(policy));
+        if let Some(ratio) = self.compaction {
+            map.set_compaction(ratio);
+        }
+        if let Some((max_len, overflow)) = self.dirty_limit {
+            map.set_dirty_limit(max_len, overflow);
+        }
+        map.set_hooks(self.hooks.into_hooks());
+        map
+    }
+
+    /// Builds a map whose values weigh at most `max_weight` in total, as given
+    /// by the weigher, evicting the keys `policy` picks once a value stored
+    /// takes the map past the bound.
+    ///
+    /// The bound is checked once a store has taken effect, so the map may
+    /// briefly weigh more. A single value heavier than the bound may end up
+    /// evicted itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no weigher is set, or if `max_weight` is zero.
+    pub fn build_weighted<K, V>(
+        self,
+        max_weight: usize,
+        policy: impl EvictionPolicy<K> + 'static,
+    ) -> SyncMap<K, V, S>
+    where
+        K: std::cmp::Eq + std::hash::Hash,
+        S: BuildHasher + Clone,
+        L: IntoHooks<K, V>,
+    {
+        assert!(max_weight > 0, "a bounded map must hold some weight");
+        let mut map = SyncMap::with_policy(self.capacity, self.hash_builder, self.policy);
+        map.set_hooks(self.hooks.into_hooks());
+        map.set_weight_bound(max_weight, Box::new(policy));
+        if let Some(ratio) = self.compaction {
+            map.set_compaction(ratio);
+        }
+        if let Some((max_len, overflow)) = self.dirty_limit {
+            map.set_dirty_limit(max_len, overflow);
+        }
+        map
+    }
+
+    /// Builds a [`ShardedSyncMap`] with at least `shards` shards, rounded up
+    /// to a power of two. Every shard uses the promotion policy, and the
+    /// capacity is split evenly between them.
+    pub fn build_sharded<K, V>(self, shards: usize) -> ShardedSyncMap<K, V, S>
+    where
+        K: std::cmp::Eq + std::hash::Hash,
+        S: BuildHasher + Clone,
+        L: IntoHooks<K, V>,
+    {
+        let mut map =
+            ShardedSyncMap::with_policy(shards, self.capacity, self.hash_builder, self.policy);
+        map.set_hooks(self.hooks.into_hooks());
+        if let Some(ratio) = self.compaction {
+            map.set_compaction(ratio);
+        }
+        if let Some((max_len, overflow)) = self.dirty_limit {
+            map.set_dirty_limit(max_len, overflow);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stores a key into the dirty map, then counts the loads it takes to get
+    // it promoted to the read map.
+    fn misses_to_promote(map: &SyncMap<u64, u64>) -> u64 {
+        for i in 0..8 {
+            map.store(i, i);
+        }
+        map.range(|_, _| true);
+        map.store(100, 100);
+
+        let mut misses = 0;
+        while !map.is_promoted(&100) {
+            map.load(&100);
+            misses += 1;
+        }
+        misses
+    }
+
+    #[test]
+    fn miss_threshold_factor() {
+        let map = SyncMapBuilder::new().build();
+        assert_eq!(misses_to_promote(&map), 9);
+
+        let map = SyncMapBuilder::new().miss_threshold_factor(0.0).build();
+        assert_eq!(misses_to_promote(&map), 1);
+
+        let map = SyncMapBuilder::new().miss_threshold_factor(2.0).build();
+        assert_eq!(misses_to_promote(&map), 18);
+    }
+
+    #[test]
+    fn promotion_policy() {
+        struct Never;
+        impl PromotionPolicy for Never {
+            fn should_promote(&self, _: u64, _: usize) -> bool {
+                false
+            }
+        }
+
+        let map = SyncMapBuilder::new().promotion_policy(Never).build();
+        map.store(1, 1);
+        for _ in 0..100 {
+            assert_eq!(*map.load(&1).unwrap(), 1);
+        }
+        assert!(!map.is_promoted(&1));
+    }
+
+    #[test]
+    fn build_sharded() {
+        let map = SyncMapBuilder::new()
+            .capacity(1000)
+            .miss_threshold_factor(0.0)
+            .build_sharded(4);
+        assert_eq!(map.shards().len(), 4);
+        for i in 0..100 {
+            map.store(i, i);
+            map.load(&i);
+            assert!(map.shards().iter().any(|shard| shard.is_promoted(&i)));
+        }
+    }
+
+    #[test]
+    fn hasher() {
+        type Hasher = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+
+        let map: SyncMap<u64, u64, Hasher> = SyncMapBuilder::new()
+            .capacity(16)
+            .hasher(Hasher::default())
+            .build();
+        map.store(1, 1);
+        assert_eq!(*map.load(&1).unwrap(), 1);
+    }
+
+    #[test]
+    fn hooks() {
+        use std::sync::Mutex;
+
+        use crate::{map::MapEntry, policy::Fifo};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let push = |log: &Arc<Mutex<Vec<String>>>| {
+            let log = log.clone();
+            move |s: String| log.lock().unwrap().push(s)
+        };
+        let (insert, update, remove, evict) = (push(&log), push(&log), push(&log), push(&log));
+        let map = SyncMapBuilder::new()
+            .on_insert(move |k: &u64, v: &u64| insert(format!("insert {k} {v}")))
+            .on_update(move |k: &u64, old: &u64, v: &u64| update(format!("update {k} {old} {v}")))
+            .on_remove(move |k: &u64, v: &u64| remove(format!("remove {k} {v}")))
+            .on_evict(move |k: &u64, v: &u64| evict(format!("evict {k} {v}")))
+            .build_bounded(2, Fifo::new());
+
+        map.store(1, 10);
+        map.store(1, 11);
+        map.load_or_store(2, 20);
+        map.load_or_store(2, 21);
+        assert!(map.compare_and_swap(&2, &20, 22));
+        map.store(3, 30);
+        assert!(map.remove(&2).is_some());
+        assert!(map.remove(&2).is_none());
+        if let MapEntry::Vacant(e) = map.entry(4) {
+            e.insert(40);
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "insert 1 10",
+                "update 1 10 11",
+                "insert 2 20",
+                "update 2 20 22",
+                "insert 3 30",
+                "evict 1 11",
+                "remove 2 22",
+                "insert 4 40",
+            ]
+        );
+
+        // Clones keep the callbacks without reporting the copied entries.
+        log.lock().unwrap().clear();
+        let clone = map.clone();
+        assert!(log.lock().unwrap().is_empty());
+        clone.update(&3, |v| v + 1);
+        assert_eq!(*log.lock().unwrap(), ["update 3 30 31"]);
+    }
+
+    #[test]
+    fn compaction_ratio() {
+        let map = SyncMapBuilder::new().compaction_ratio(0.5).build();
+        for i in 0..100 {
+            map.store(i, i);
+        }
+        map.range(|_, _| true);
+        for i in 0..49 {
+            map.remove(&i);
+        }
+        assert!(map.is_promoted(&0));
+
+        // The 50th deletion reaches half the size of the read map.
+        map.remove(&49);
+        assert!(!map.is_promoted(&0));
+        assert!(map.is_promoted(&50));
+        assert_eq!(*map.load(&50).unwrap(), 50);
+    }
+
+    #[test]
+    fn max_dirty_len() {
+        let map = SyncMapBuilder::new()
+            .max_dirty_len(4, DirtyOverflow::Promote)
+            .build();
+        for i in 0..4 {
+            map.store(i, i);
+        }
+        assert!(!map.is_promoted(&0));
+        map.store(4, 4);
+        assert!((0..4).all(|i| map.is_promoted(&i)));
+        assert!(!map.is_promoted(&4));
+
+        let map = SyncMapBuilder::new()
+            .max_dirty_len(2, DirtyOverflow::Reject)
+            .build();
+        assert!(map.store_with_limit(0, 0).is_ok());
+        assert!(map.store_with_limit(1, 1).is_ok());
+        assert_eq!(map.store_with_limit(2, 2).unwrap_err().0, (2, 2));
+        assert!(map.load(&2).is_none());
+        // Keys already in the dirty map can still be written.
+        assert!(map.store_with_limit(0, 10).is_ok());
+        // Writes that cannot fail promote instead.
+        map.store(2, 2);
+        assert!(map.is_promoted(&0));
+        assert!(map.store_with_limit(3, 3).is_ok());
+        assert_eq!(*map.load(&0).unwrap(), 10);
+    }
+
+    #[test]
+    fn weigher() {
+        use crate::policy::Fifo;
+
+        let map = SyncMapBuilder::new()
+            .weigher(|_: &u64, v: &String| v.len())
+            .build();
+        map.store(1, String::from("abc"));
+        map.store(2, String::from("de"));
+        assert_eq!(map.weight(), 5);
+        map.store(1, String::from("a"));
+        map.remove(&2);
+        assert_eq!(map.weight(), 1);
+        assert_eq!(map.clone().weight(), 1);
+
+        let map = SyncMapBuilder::new()
+            .weigher(|_: &u64, v: &Vec<u8>| v.len())
+            .build_weighted(100, Fifo::new());
+        for i in 0..10 {
+            map.store(i, vec![0; 30]);
+        }
+        assert!(map.weight() <= 100);
+        assert!(map.load(&0).is_none());
+        assert!(map.load(&9).is_some());
+
+        // Growing a value evicts others too.
+        map.store(9, vec![0; 90]);
+        assert_eq!(map.weight(), 90);
+        assert!(map.load(&8).is_none());
+    }
+}
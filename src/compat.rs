@@ -0,0 +1,273 @@
+//! A facade over [`SyncMap`] with the method names and guard types of
+//! DashMap, e.g. to measure both under the same call sites.
+use std::{borrow::Borrow, collections::hash_map::RandomState, fmt, hash::BuildHasher};
+
+use crate::map::{self, MapEntry, RefPair, SyncMap};
+
+/// A reference to a key and its value, returned by [`DashMap::get`].
+pub type Ref<'a, K, V> = RefPair<'a, K, V>;
+
+/// A reference to a key and its value, yielded by [`DashMap::iter`].
+pub type RefMulti<'a, K, V> = RefPair<'a, K, V>;
+
+/// A reference to a key and a copy of its value to change, returned by
+/// [`DashMap::get_mut`].
+pub type RefMut<'a, K, V, S = RandomState> = map::RefMut<'a, K, V, S>;
+
+/// An entry, returned by [`DashMap::entry`].
+pub type Entry<'a, K, V, S = RandomState> = MapEntry<'a, K, V, S>;
+
+/// A [`SyncMap`] behind DashMap's API, so that switching between the two
+/// only takes changing an import.
+///
+/// Values are never mutated in place, so [`DashMap::get_mut`] changes a copy
+/// of the value, there is no `iter_mut`, and entries hand out shared
+/// references. Values that DashMap hands back owned are cloned, since readers
+/// may still hold the originals.
+pub struct DashMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<K, V, S>,
+}
+
+impl<K, V> DashMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new() -> Self {
+        DashMap::with_hasher(RandomState::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        DashMap::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<K, V> Default for DashMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        DashMap::new()
+    }
+}
+
+impl<K, V, S> DashMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        DashMap {
+            map: SyncMap::with_hasher(hash_builder),
+        }
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        DashMap {
+            map: SyncMap::with_capacity_and_hasher(capacity, hash_builder),
+        }
+    }
+
+    /// Returns the underlying map.
+    pub fn as_sync_map(&self) -> &SyncMap<K, V, S> {
+        &self.map
+    }
+
+    /// Returns a reference to the key and value for a key.
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.get_key_value(key)
+    }
+
+    /// Locks a key and returns a guard to change its value. See
+    /// [`SyncMap::get_mut`].
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+        V: Clone,
+    {
+        self.map.get_mut(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.contains_key(key)
+    }
+
+    /// Stores a value for a key, returning a clone of the previous one.
+    pub fn insert(&self, key: K, value: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.map
+            .swap(key, value)
+            .map(|previous| (*previous).clone())
+    }
+
+    /// Removes a key, returning clones of the key and value removed.
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.remove_if(key, |_, _| true)
+    }
+
+    /// Removes a key if `f` returns true for its key and value, returning
+    /// clones of the key and value removed.
+    ///
+    /// The value is only removed if it is still the one `f` was called with,
+    /// so `f` may be called again if a concurrent write replaces it.
+    pub fn remove_if<Q>(&self, key: &Q, mut f: impl FnMut(&K, &V) -> bool) -> Option<(K, V)>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        loop {
+            let r = self.map.get_key_value(key)?;
+            if !f(r.key(), r.value()) {
+                return None;
+            }
+            if self.map.remove_same(key, r.value()) {
+                return Some((r.key().clone(), r.value().clone()));
+            }
+        }
+    }
+
+    /// Locks the entry for a key. See [`SyncMap::entry`].
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S> {
+        self.map.entry(key)
+    }
+
+    /// Returns an iterator over the keys and values present in the map. See
+    /// [`SyncMap::iter`].
+    pub fn iter(&self) -> map::Iter<'_, K, V, S> {
+        self.map.iter()
+    }
+
+    /// Retains only the entries for which `f` returns true. See
+    /// [`SyncMap::retain`].
+    pub fn retain(&self, f: impl FnMut(&K, &V) -> bool) {
+        self.map.retain(f);
+    }
+
+    /// Returns the number of keys present in the map.
+    ///
+    /// This visits every entry, as [`SyncMap::range`] does, so it is linear
+    /// in the size of the map rather than constant as with DashMap.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.map.range(|_, _| {
+            len += 1;
+            true
+        });
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let mut empty = true;
+        self.map.range(|_, _| {
+            empty = false;
+            false
+        });
+        empty
+    }
+
+    /// Removes every key present at the start of the call.
+    pub fn clear(&self) {
+        self.map.retain(|_, _| false);
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for DashMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        DashMap {
+            map: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<K, V, S> From<SyncMap<K, V, S>> for DashMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn from(map: SyncMap<K, V, S>) -> Self {
+        DashMap { map }
+    }
+}
+
+impl<K, V, S> fmt::Debug for DashMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn dashmap_api() {
+        let map = DashMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.insert("a", 1), None);
+        assert_eq!(map.insert("a", 2), Some(1));
+        map.insert("b", 3);
+        assert_eq!(*map.get("a").unwrap(), 2);
+        assert_eq!(map.get("b").unwrap().pair(), (&"b", &3));
+        assert_eq!(map.len(), 2);
+        *map.get_mut("b").unwrap() += 1;
+        assert_eq!(*map.get("b").unwrap(), 4);
+
+        assert_eq!(*map.entry("c").or_insert(4), 4);
+        map.entry("c").and_modify(|v| v + 1);
+        assert_eq!(*map.get("c").unwrap(), 5);
+
+        assert_eq!(map.remove_if("c", |_, v| *v > 5), None);
+        assert_eq!(map.remove("c"), Some(("c", 5)));
+        assert_eq!(map.remove("c"), None);
+        let mut keys: Vec<_> = map.iter().map(|r| *r.key()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+
+        map.retain(|_, v| *v > 3);
+        assert!(!map.contains_key("a"));
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn remove_once() {
+        let map: DashMap<u64, u64> = (0..1000).map(|i| (i, i)).collect();
+        let removed: usize = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..1000).filter(|i| map.remove(i).is_some()).count()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(removed, 1000);
+        assert!(map.is_empty());
+    }
+}
@@ -1,117 +1,805 @@
 use std::{
     marker::PhantomData,
-    sync::atomic::{AtomicPtr, Ordering},
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
 };
 
-enum EntryState<V> {
-    // The pointer can't be null, because
-    // `SoftDelete` represent pointer null situation
-    Present(AtomicPtr<V>, PhantomData<V>),
+use parking_lot_core::{DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
 
+use crate::{
+    order::{ACQUIRE, ACQ_REL, RELAXED, RELEASE},
+    reclaim::{Collector, Guard},
+};
+
+// The entry is locked against writers other than the lock holder.
+const LOCKED: usize = 0b01;
+
+// Some writer is parked waiting for the entry to be unlocked.
+const PARKED: usize = 0b10;
+
+const TAG: usize = LOCKED | PARKED;
+
+// Values are boxed in a slot aligned enough to leave the tag bits of the
+// entry pointer free. The value sits at offset 0, so a `&V` into a slot
+// converts back to the slot pointer.
+#[repr(C, align(4))]
+struct Slot<V>(V);
+
+impl<V> Slot<V> {
+    fn boxed(val: V) -> *mut Slot<V> {
+        Box::into_raw(Box::new(Slot(val)))
+    }
+
+    unsafe fn unbox(p: *mut Slot<V>) -> V {
+        Box::from_raw(p).0
+    }
+}
+
+// Only its address is used, as a pointer that can never be a live value. It
+// is not zero-sized, so it cannot share the dangling address of a zero-sized
+// value's box.
+static EXPUNGED: u32 = 0;
+
+#[inline(always)]
+fn expunged<V>() -> *mut Slot<V> {
+    &EXPUNGED as *const u32 as *mut Slot<V>
+}
+
+#[inline(always)]
+fn untagged<V>(p: *mut Slot<V>) -> *mut Slot<V> {
+    p.map_addr(|addr| addr & !TAG)
+}
+
+#[inline(always)]
+fn is_locked<V>(p: *mut Slot<V>) -> bool {
+    p.addr() & LOCKED != 0
+}
+
+pub(crate) enum TrySwap<'g, V> {
+    // The previous value, if any, and the one stored.
+    Swapped(Option<&'g V>, &'g V),
+
+    // The value to store is handed back.
+    Expunged(V),
+    Locked(V),
+}
+
+pub(crate) enum TryInsert<'g, V> {
+    Stored(&'g V),
+
+    // The entry already held a value; the one to insert is handed back.
+    Occupied(&'g V, V),
+
+    // The value to insert is handed back.
+    Expunged(V),
+    Locked(V),
+}
+
+/// A value unlinked from its entry but not retired yet, so it stays valid
+/// until it is.
+pub(crate) struct Unlinked<V>(*mut Slot<V>);
+
+impl<V> Unlinked<V> {
+    pub(crate) fn get(&self) -> &V {
+        unsafe { &(*self.0).0 }
+    }
+
+    /// Hands the value over to `collector`, to be dropped once no reader can
+    /// still observe it.
+    pub(crate) fn retire(self, collector: &Collector) {
+        unsafe { retire(self.0, collector) };
+    }
+}
+
+pub(crate) enum EntryState<'g, V> {
+    Present(&'g V),
+
+    // Deleted, but still in the dirty map (or there is no dirty map).
     SoftDelete,
 
-    // Expunged
+    // Expunged: deleted and missing from the dirty map.
     HardDelete,
 }
 
 /// The container of the value, controls the lifetime of the value and
 /// is responsible for value deallocation.
+///
+/// Replaced and deleted values are retired into the map's collector rather
+/// than freed, so a value loaded under a guard stays valid until that guard
+/// is dropped.
+///
+/// An entry can be locked against writers: readers are never blocked, but
+/// every other write waits until the lock holder unlocks it.
 pub struct Entry<V> {
-    state: EntryState<V>,
+    // Null when soft deleted, `expunged()` when hard deleted, otherwise a
+    // pointer obtained from `Slot::boxed`. The low bits carry the lock tags,
+    // which are never set on an expunged entry.
+    p: AtomicPtr<Slot<V>>,
+
+    // The map's clock as of the last access, only kept by bounded maps.
+    accessed: AtomicU64,
+
+    _marker: PhantomData<V>,
 }
 
 impl<V> Entry<V> {
     pub fn new(val: V) -> Self {
-        let boxed_val = Box::new(val);
-        let ptr = Box::into_raw(boxed_val);
         Self {
-            state: EntryState::Present(AtomicPtr::new(ptr), PhantomData),
+            p: AtomicPtr::new(Slot::boxed(val)),
+            accessed: AtomicU64::new(0),
+            _marker: PhantomData,
         }
     }
 
-    pub fn new_null_entry() -> Self {
+    /// Creates a soft deleted entry.
+    pub(crate) fn new_deleted() -> Self {
         Self {
-            state: EntryState::HardDelete,
+            p: AtomicPtr::new(ptr::null_mut()),
+            accessed: AtomicU64::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn state<'g>(&self, _guard: &'g Guard) -> EntryState<'g, V> {
+        let p = untagged(self.p.load(ACQUIRE));
+        if p.is_null() {
+            EntryState::SoftDelete
+        } else if p == expunged() {
+            EntryState::HardDelete
+        } else {
+            EntryState::Present(unsafe { &(*p).0 })
         }
     }
 
     /// Loads a reference to the value if present.
-    pub fn load(&self) -> Option<&V> {
-        match &self.state {
-            EntryState::Present(atomic_ptr, _) => {
-                let ptr = atomic_ptr.load(Ordering::Acquire);
-                unsafe { Some(&*ptr) }
-            }
+    pub(crate) fn load<'g>(&self, guard: &'g Guard) -> Option<&'g V> {
+        match self.state(guard) {
+            EntryState::Present(val) => Some(val),
             EntryState::SoftDelete | EntryState::HardDelete => None,
         }
     }
 
-    /// Swaps a value if the entry has not been expunged
+    /// Swaps a value if the entry has not been expunged, returning the
+    /// previous value and the one stored.
     ///
     /// If the entry is expunged, trySwap returns the value and leaves the entry unchanged
-    pub fn try_swap(&self, val: V) -> Option<V> {
-        if let EntryState::Present(ref ptr, _) = self.state {
-            let new_ptr = Box::into_raw(Box::new(val));
-            loop {
-                let old_ptr = ptr.load(Ordering::Acquire);
-
-                match ptr.compare_exchange_weak(
-                    old_ptr,
-                    new_ptr,
-                    Ordering::AcqRel,
-                    Ordering::Acquire,
-                ) {
-                    Ok(ptr) => {
-                        // Convert the old pointer back to a box and return the value
-                        return Some(unsafe { *Box::from_raw(ptr) });
-                    }
-                    // Swap failed; retry the loop with the current `old_ptr`
-                    Err(_) => continue,
+    pub(crate) fn try_swap<'g>(
+        &self,
+        val: V,
+        guard: &'g Guard,
+        collector: &Collector,
+    ) -> Result<(Option<&'g V>, &'g V), V> {
+        match self.swap_impl(val, true, guard, collector) {
+            TrySwap::Swapped(previous, current) => Ok((previous, current)),
+            TrySwap::Expunged(val) | TrySwap::Locked(val) => Err(val),
+        }
+    }
+
+    /// Like [`Entry::try_swap`], but hands the value back instead of waiting
+    /// if the entry is locked.
+    pub(crate) fn try_swap_nowait<'g>(
+        &self,
+        val: V,
+        guard: &'g Guard,
+        collector: &Collector,
+    ) -> TrySwap<'g, V> {
+        self.swap_impl(val, false, guard, collector)
+    }
+
+    fn swap_impl<'g>(
+        &self,
+        val: V,
+        wait: bool,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> TrySwap<'g, V> {
+        let new_ptr = Slot::boxed(val);
+        let mut old_ptr = self.p.load(ACQUIRE);
+        loop {
+            if old_ptr == expunged() {
+                return TrySwap::Expunged(unsafe { Slot::unbox(new_ptr) });
+            }
+            if is_locked(old_ptr) {
+                if !wait {
+                    return TrySwap::Locked(unsafe { Slot::unbox(new_ptr) });
                 }
+                old_ptr = self.wait(old_ptr);
+                continue;
             }
-        }
 
-        Some(val)
+            match self
+                .p
+                .compare_exchange_weak(old_ptr, new_ptr, ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => {
+                    let previous = unsafe { retire(old_ptr, collector) };
+                    return TrySwap::Swapped(previous, unsafe { &(*new_ptr).0 });
+                }
+                // Swap failed; retry the loop with the current `old_ptr`
+                Err(current) => old_ptr = current,
+            }
+        }
     }
 
     /// Ensures that the entry is not marked as expunged. Return if the entry was previously expunged
     //
     /// If the entry was previously expunged, it must be added to the dirty map before mu is unlocked.
-    pub fn unexpunge_locked(&mut self) -> bool {
-        match &self.state {
-            EntryState::Present(_, _) | EntryState::SoftDelete => false,
-            EntryState::HardDelete => {
-                self.state = EntryState::SoftDelete;
-                true
+    pub(crate) fn unexpunge_locked(&self) -> bool {
+        self.p
+            .compare_exchange(expunged(), ptr::null_mut(), ACQ_REL, ACQUIRE)
+            .is_ok()
+    }
+
+    /// Atomically loads or stores a value if the entry is not expunged.
+    ///
+    /// Returns the actual value and whether it was loaded. If the entry is
+    /// expunged, the value is handed back and the entry is left unchanged.
+    pub(crate) fn try_load_or_store<'g>(
+        &self,
+        val: V,
+        guard: &'g Guard,
+    ) -> Result<(&'g V, bool), V> {
+        match self.try_insert(val, guard) {
+            TryInsert::Stored(actual) => Ok((actual, false)),
+            TryInsert::Occupied(actual, _) => Ok((actual, true)),
+            TryInsert::Expunged(val) | TryInsert::Locked(val) => Err(val),
+        }
+    }
+
+    /// Atomically stores a value if the entry is deleted but not expunged.
+    ///
+    /// If the entry holds a value, or is expunged, the value is handed back
+    /// and the entry is left unchanged.
+    pub(crate) fn try_insert<'g>(&self, val: V, guard: &'g Guard) -> TryInsert<'g, V> {
+        self.insert_impl(val, true, guard)
+    }
+
+    /// Like [`Entry::try_insert`], but hands the value back instead of
+    /// waiting if the entry is locked.
+    pub(crate) fn try_insert_nowait<'g>(&self, val: V, guard: &'g Guard) -> TryInsert<'g, V> {
+        self.insert_impl(val, false, guard)
+    }
+
+    fn insert_impl<'g>(&self, val: V, wait: bool, _guard: &'g Guard) -> TryInsert<'g, V> {
+        let mut val = Some(val);
+        let mut new_ptr: *mut Slot<V> = ptr::null_mut();
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return TryInsert::Expunged(val.unwrap_or_else(|| unsafe { Slot::unbox(new_ptr) }));
+            }
+            if !untagged(p).is_null() {
+                let val = val.unwrap_or_else(|| unsafe { Slot::unbox(new_ptr) });
+                return TryInsert::Occupied(unsafe { &(*untagged(p)).0 }, val);
+            }
+            if is_locked(p) {
+                if !wait {
+                    let val = val.unwrap_or_else(|| unsafe { Slot::unbox(new_ptr) });
+                    return TryInsert::Locked(val);
+                }
+                p = self.wait(p);
+                continue;
+            }
+
+            // Box the value only once we know we are likely to store it.
+            if let Some(val) = val.take() {
+                new_ptr = Slot::boxed(val);
+            }
+            match self
+                .p
+                .compare_exchange_weak(ptr::null_mut(), new_ptr, ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return TryInsert::Stored(unsafe { &(*new_ptr).0 }),
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Replaces the value with `f(&value)` until the swap sticks, returning
+    /// the previous value and the one stored. Returns `None` without calling
+    /// `f` if the entry is deleted.
+    pub(crate) fn update<'g>(
+        &self,
+        mut f: impl FnMut(&V) -> V,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Option<(&'g V, &'g V)> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+            if p.is_null() || p == expunged() {
+                return None;
+            }
+
+            let new_ptr = Slot::boxed(f(unsafe { &(*p).0 }));
+            match self.p.compare_exchange(p, new_ptr, ACQ_REL, ACQUIRE) {
+                Ok(_) => {
+                    let previous = unsafe { retire(p, collector) }.unwrap();
+                    return Some((previous, unsafe { &(*new_ptr).0 }));
+                }
+                Err(current) => {
+                    drop(unsafe { Box::from_raw(new_ptr) });
+                    p = current;
+                }
+            }
+        }
+    }
+
+    /// Stores a value if the entry is deleted, or replaces it with
+    /// `modify(&value)` if present, returning the previous value, if any, and
+    /// the one now stored.
+    ///
+    /// The value to insert is taken from `pending`, or made by `insert` if
+    /// `pending` is empty; a value that could not be stored is put back into
+    /// `pending`, so `insert` is called at most once across retries. If the
+    /// entry is expunged, the entry is left unchanged.
+    pub(crate) fn try_upsert<'g>(
+        &self,
+        pending: &mut Option<V>,
+        insert: &mut impl FnMut() -> V,
+        modify: &mut impl FnMut(&V) -> V,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Result<(Option<&'g V>, &'g V), ()> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return Err(());
+            }
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+
+            let new_ptr = if p.is_null() {
+                Slot::boxed(pending.take().unwrap_or_else(&mut *insert))
+            } else {
+                Slot::boxed(modify(unsafe { &(*p).0 }))
+            };
+            match self.p.compare_exchange(p, new_ptr, ACQ_REL, ACQUIRE) {
+                Ok(_) => {
+                    let previous = unsafe { retire(p, collector) };
+                    return Ok((previous, unsafe { &(*new_ptr).0 }));
+                }
+                Err(current) => {
+                    let val = unsafe { Slot::unbox(new_ptr) };
+                    if p.is_null() {
+                        *pending = Some(val);
+                    }
+                    p = current;
+                }
+            }
+        }
+    }
+
+    /// Swaps in `new` if the entry holds a value equal to `old`, returning
+    /// the previous value and the one stored.
+    pub(crate) fn try_compare_and_swap<'g>(
+        &self,
+        old: &V,
+        new: V,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Option<(&'g V, &'g V)>
+    where
+        V: PartialEq,
+    {
+        let mut new = Some(new);
+        let mut new_ptr: *mut Slot<V> = ptr::null_mut();
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+            if p.is_null() || p == expunged() || unsafe { &(*p).0 } != old {
+                if !new_ptr.is_null() {
+                    drop(unsafe { Box::from_raw(new_ptr) });
+                }
+                return None;
+            }
+
+            if let Some(new) = new.take() {
+                new_ptr = Slot::boxed(new);
+            }
+            match self.p.compare_exchange_weak(p, new_ptr, ACQ_REL, ACQUIRE) {
+                Ok(_) => {
+                    let previous = unsafe { retire(p, collector) }.unwrap();
+                    return Some((previous, unsafe { &(*new_ptr).0 }));
+                }
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Soft deletes the value if it is equal to `old`, returning it.
+    pub(crate) fn try_compare_and_delete<'g>(
+        &self,
+        old: &V,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Option<&'g V>
+    where
+        V: PartialEq,
+    {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+            if p.is_null() || p == expunged() || unsafe { &(*p).0 } != old {
+                return None;
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, ptr::null_mut(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return unsafe { retire(p, collector) },
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Soft deletes the value, returning it if it was present.
+    pub(crate) fn delete<'g>(&self, guard: &'g Guard, collector: &Collector) -> Option<&'g V> {
+        self.delete_impl(true, guard, collector).unwrap()
+    }
+
+    /// Like [`Entry::delete`], but returns `Err` instead of waiting if the
+    /// entry is locked.
+    pub(crate) fn delete_nowait<'g>(
+        &self,
+        guard: &'g Guard,
+        collector: &Collector,
+    ) -> Result<Option<&'g V>, ()> {
+        self.delete_impl(false, guard, collector)
+    }
+
+    fn delete_impl<'g>(
+        &self,
+        wait: bool,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Result<Option<&'g V>, ()> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if is_locked(p) {
+                if !wait {
+                    return Err(());
+                }
+                p = self.wait(p);
+                continue;
+            }
+            if p.is_null() || p == expunged() {
+                return Ok(None);
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, ptr::null_mut(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return Ok(unsafe { retire(p, collector) }),
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Soft deletes the value only if it is still `old`. Returns whether it
+    /// was deleted.
+    pub(crate) fn delete_if_same(&self, old: &V, _guard: &Guard, collector: &Collector) -> bool {
+        let old = old as *const V as *mut Slot<V>;
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+            if p != old {
+                return false;
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, ptr::null_mut(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => {
+                    unsafe { retire(p, collector) };
+                    return true;
+                }
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Marks a soft deleted entry as expunged. Returns whether the entry is
+    /// expunged afterwards.
+    ///
+    /// A locked entry is never expunged, so it stays in the dirty map.
+    pub(crate) fn try_expunge_locked(&self) -> bool {
+        let mut p = self.p.load(ACQUIRE);
+        while p.is_null() {
+            match self
+                .p
+                .compare_exchange(ptr::null_mut(), expunged(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return true,
+                Err(current) => p = current,
+            }
+        }
+
+        p == expunged()
+    }
+
+    /// Like [`Entry::try_expunge_locked`], but also deletes and expunges the
+    /// entry if it holds a value for which `stale` returns true.
+    pub(crate) fn try_prune_locked(
+        &self,
+        stale: fn(&V) -> bool,
+        _guard: &Guard,
+        collector: &Collector,
+    ) -> bool {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return true;
+            }
+            if is_locked(p) || !p.is_null() && !stale(unsafe { &(*p).0 }) {
+                return false;
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, expunged(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => {
+                    unsafe { retire(p, collector) };
+                    return true;
+                }
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Deletes the value, if any, and marks the entry as expunged so it can be
+    /// dropped from the dirty map. Returns `Err`, leaving the entry unchanged,
+    /// if it is locked.
+    ///
+    /// The value is handed back rather than retired, and must be retired by
+    /// the caller. Never waits, so it is safe to call with the dirty lock
+    /// held.
+    pub(crate) fn try_evict_locked(&self) -> Result<Option<Unlinked<V>>, ()> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return Ok(None);
+            }
+            if is_locked(p) {
+                return Err(());
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, expunged(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return Ok((!p.is_null()).then_some(Unlinked(p))),
+                Err(current) => p = current,
             }
         }
     }
 
+    /// Deletes the value and marks the entry as expunged for good, returning
+    /// the value if it was present. Waits for the lock holder, if any.
+    ///
+    /// Used on entries unlinked from the map, so that writers still holding
+    /// them fall back to the lock and find the key missing.
+    pub(crate) fn expunge<'g>(&self, _guard: &'g Guard, collector: &Collector) -> Option<&'g V> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return None;
+            }
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+
+            match self
+                .p
+                .compare_exchange_weak(p, expunged(), ACQ_REL, ACQUIRE)
+            {
+                Ok(_) => return unsafe { retire(p, collector) },
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Records an access at `now` on the map's clock.
+    pub(crate) fn touch(&self, now: u64) {
+        // Skip the write, and the cache line bouncing between readers that
+        // comes with it, if the entry was already accessed at `now`.
+        if self.accessed.load(Ordering::Relaxed) < now {
+            self.accessed.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the map's clock as of the last access.
+    pub(crate) fn accessed(&self) -> u64 {
+        self.accessed.load(Ordering::Relaxed)
+    }
+
+    /// Locks the entry against other writers, waiting for the current holder
+    /// if any. Returns false, without locking, if the entry is expunged.
+    pub(crate) fn lock(&self) -> bool {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            if p == expunged() {
+                return false;
+            }
+            if is_locked(p) {
+                p = self.wait(p);
+                continue;
+            }
+
+            match self.p.compare_exchange_weak(
+                p,
+                p.map_addr(|addr| addr | LOCKED),
+                ACQUIRE,
+                ACQUIRE,
+            ) {
+                Ok(_) => return true,
+                Err(current) => p = current,
+            }
+        }
+    }
+
+    /// Releases the lock taken by [`Entry::lock`], waking parked writers.
+    pub(crate) fn unlock(&self) {
+        let mut p = self.p.load(RELAXED);
+        loop {
+            debug_assert!(is_locked(p));
+            match self
+                .p
+                .compare_exchange_weak(p, untagged(p), RELEASE, RELAXED)
+            {
+                Ok(_) => break,
+                Err(current) => p = current,
+            }
+        }
+
+        if p.addr() & PARKED != 0 {
+            unsafe { parking_lot_core::unpark_all(self.park_key(), DEFAULT_UNPARK_TOKEN) };
+        }
+    }
+
     // Unconditionally swaps a value into the entry.
     //
-    // The entry must be known not to be expunged.
-    pub fn swap_locked(&mut self, val: V) -> Option<V> {
-        match &self.state {
-            EntryState::Present(atomic_ptr, _) => {
-                let ptr = Box::into_raw(Box::new(val));
-                let old = atomic_ptr.swap(ptr, Ordering::Acquire);
-                Some(unsafe { *Box::from_raw(old) })
+    // The caller must hold the entry lock.
+    pub(crate) fn swap_held<'g>(
+        &self,
+        val: V,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Option<&'g V> {
+        self.replace_held(Slot::boxed(val), collector)
+    }
+
+    // Soft deletes the value, returning it if it was present.
+    //
+    // The caller must hold the entry lock.
+    pub(crate) fn delete_held<'g>(
+        &self,
+        _guard: &'g Guard,
+        collector: &Collector,
+    ) -> Option<&'g V> {
+        self.replace_held(ptr::null_mut(), collector)
+    }
+
+    fn replace_held<'g>(&self, new_ptr: *mut Slot<V>, collector: &Collector) -> Option<&'g V> {
+        let mut p = self.p.load(ACQUIRE);
+        loop {
+            debug_assert!(is_locked(p));
+            // Keep the tags: we still hold the lock, and writers may be parked.
+            let tagged = new_ptr.map_addr(|addr| addr | (p.addr() & TAG));
+            match self.p.compare_exchange_weak(p, tagged, ACQ_REL, ACQUIRE) {
+                Ok(_) => return unsafe { retire(untagged(p), collector) },
+                Err(current) => p = current,
             }
-            EntryState::SoftDelete => {
-                let ptr = Box::into_raw(Box::new(val));
-                self.state = EntryState::Present(AtomicPtr::new(ptr), PhantomData);
-                None
+        }
+    }
+
+    // Parks until the entry is unlocked, given that `p` was observed locked,
+    // and returns the entry pointer observed afterwards.
+    #[cold]
+    fn wait(&self, mut p: *mut Slot<V>) -> *mut Slot<V> {
+        while is_locked(p) {
+            if p.addr() & PARKED == 0 {
+                let parked = p.map_addr(|addr| addr | PARKED);
+                if let Err(current) = self.p.compare_exchange_weak(p, parked, RELAXED, RELAXED) {
+                    p = current;
+                    continue;
+                }
             }
-            EntryState::HardDelete => unreachable!(),
+
+            unsafe {
+                parking_lot_core::park(
+                    self.park_key(),
+                    || self.p.load(RELAXED).addr() & PARKED != 0,
+                    || {},
+                    |_, _| {},
+                    DEFAULT_PARK_TOKEN,
+                    None,
+                )
+            };
+            p = self.p.load(ACQUIRE);
         }
+
+        p
     }
+
+    fn park_key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Returns a mutable reference to the value if present.
+    ///
+    /// Exclusive access means no reader or lock holder is left, so the value
+    /// can be modified in place.
+    pub(crate) fn get_mut(&mut self) -> Option<&mut V> {
+        let p = untagged(*self.p.get_mut());
+        if p.is_null() || p == expunged() {
+            None
+        } else {
+            Some(unsafe { &mut (*p).0 })
+        }
+    }
+
+    /// Takes the value out, leaving the entry soft deleted.
+    pub(crate) fn take_mut(&mut self) -> Option<V> {
+        let p = untagged(std::mem::replace(self.p.get_mut(), ptr::null_mut()));
+        if p.is_null() || p == expunged() {
+            None
+        } else {
+            Some(unsafe { Slot::unbox(p) })
+        }
+    }
+
+    /// Stores a value in place, returning the previous one if any.
+    pub(crate) fn replace_mut(&mut self, val: V) -> Option<V> {
+        let p = untagged(std::mem::replace(self.p.get_mut(), Slot::boxed(val)));
+        if p.is_null() || p == expunged() {
+            None
+        } else {
+            Some(unsafe { Slot::unbox(p) })
+        }
+    }
+}
+
+// Retires a value that was just unlinked from an entry, handing back a
+// reference that stays valid for as long as the caller is pinned.
+unsafe fn retire<'g, V>(p: *mut Slot<V>, collector: &Collector) -> Option<&'g V> {
+    if p.is_null() || p == expunged() {
+        return None;
+    }
+
+    // A zero-sized value without a destructor was never allocated and has
+    // nothing to tear down, so there is nothing to defer.
+    if std::mem::size_of::<Slot<V>>() == 0 && !std::mem::needs_drop::<V>() {
+        return Some(&(*p).0);
+    }
+
+    collector.retire(p);
+    Some(&(*p).0)
 }
 
 impl<V> Drop for Entry<V> {
     fn drop(&mut self) {
-        dbg!("Drop entry");
-        if let EntryState::Present(atomic_ptr, _) = &self.state {
-            let ptr = atomic_ptr.load(Ordering::Acquire);
+        let ptr = untagged(*self.p.get_mut());
+        if !ptr.is_null() && ptr != expunged() {
             unsafe { drop(Box::from_raw(ptr)) };
         }
     }
@@ -119,22 +807,171 @@ impl<V> Drop for Entry<V> {
 
 #[cfg(test)]
 mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::reclaim::{self, Collector};
+
     #[test]
     fn load() {
+        let guard = reclaim::pin();
         let s = String::from("this will put on the heap");
         let e = super::Entry::new(s);
-        let res = e.load();
+        let res = e.load(&guard);
         assert!(res.is_some());
         assert_eq!(res.unwrap(), "this will put on the heap")
     }
 
     #[test]
     fn try_swap() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
         let s = String::from("this will put on the heap");
         let e = super::Entry::new(s);
         let new_s = String::from("try swap");
-        assert!(e.try_swap(new_s).is_some());
-        assert_eq!(e.load().unwrap(), "try swap")
+        let (old, new) = e.try_swap(new_s, &guard, &collector).ok().unwrap();
+        assert_eq!(old.unwrap(), "this will put on the heap");
+        assert_eq!(new, "try swap");
+        assert_eq!(e.load(&guard).unwrap(), "try swap")
+    }
+
+    #[test]
+    fn expunge() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(1);
+        assert!(!e.try_expunge_locked());
+        assert_eq!(e.delete(&guard, &collector), Some(&1));
+        assert!(e.try_expunge_locked());
+        assert!(e.load(&guard).is_none());
+        assert_eq!(e.try_swap(2, &guard, &collector), Err(2));
+        assert_eq!(e.try_load_or_store(3, &guard), Err(3));
+        assert!(!e.lock());
+
+        assert!(e.unexpunge_locked());
+        assert_eq!(e.try_load_or_store(4, &guard), Ok((&4, false)));
+        assert_eq!(e.try_load_or_store(5, &guard), Ok((&4, true)));
+    }
+
+    #[test]
+    fn evict() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(1);
+        assert!(e.lock());
+        assert!(e.try_evict_locked().is_err());
+        e.unlock();
+        let evicted = e.try_evict_locked().unwrap().unwrap();
+        assert_eq!(*evicted.get(), 1);
+        evicted.retire(&collector);
+        assert!(e.load(&guard).is_none());
+        assert!(e.try_evict_locked().unwrap().is_none());
+        assert!(e.unexpunge_locked());
+    }
+
+    #[test]
+    fn expunge_unlinked() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(1);
+        assert_eq!(e.expunge(&guard, &collector), Some(&1));
+        assert_eq!(e.expunge(&guard, &collector), None);
+        assert_eq!(e.try_swap(2, &guard, &collector), Err(2));
+        assert!(!e.lock());
+
+        let e = super::Entry::<i32>::new_deleted();
+        assert_eq!(e.expunge(&guard, &collector), None);
+        assert_eq!(e.try_swap(2, &guard, &collector), Err(2));
+    }
+
+    #[test]
+    fn prune() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(1);
+        assert!(!e.try_prune_locked(|v| *v == 2, &guard, &collector));
+        assert!(e.try_prune_locked(|v| *v == 1, &guard, &collector));
+        assert!(e.load(&guard).is_none());
+
+        let e = super::Entry::new(1);
+        assert!(e.lock());
+        assert!(!e.try_prune_locked(|_| true, &guard, &collector));
+        e.unlock();
+        assert!(super::Entry::<i32>::new_deleted().try_prune_locked(|_| false, &guard, &collector));
+    }
+
+    #[test]
+    fn nowait() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(1);
+        assert!(e.lock());
+        assert!(matches!(
+            e.try_swap_nowait(2, &guard, &collector),
+            super::TrySwap::Locked(2)
+        ));
+        assert_eq!(e.delete_nowait(&guard, &collector), Err(()));
+        e.unlock();
+
+        assert!(matches!(
+            e.try_swap_nowait(2, &guard, &collector),
+            super::TrySwap::Swapped(Some(&1), &2)
+        ));
+        assert_eq!(e.delete_nowait(&guard, &collector), Ok(Some(&2)));
+        assert!(e.try_expunge_locked());
+        assert!(matches!(
+            e.try_swap_nowait(3, &guard, &collector),
+            super::TrySwap::Expunged(3)
+        ));
+    }
+
+    #[test]
+    fn lock() {
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new_deleted();
+        assert!(e.lock());
+        // A locked entry is never expunged, even when deleted.
+        assert!(!e.try_expunge_locked());
+        assert_eq!(e.swap_held(1, &guard, &collector), None);
+        assert_eq!(e.load(&guard), Some(&1));
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                let guard = reclaim::pin();
+                e.try_swap(2, &guard, &collector).unwrap().0.copied()
+            });
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(e.swap_held(3, &guard, &collector), Some(&1));
+            e.unlock();
+            assert_eq!(writer.join().unwrap(), Some(3));
+        });
+        assert_eq!(e.load(&guard), Some(&2));
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut e = super::Entry::new_deleted();
+        assert_eq!(e.get_mut(), None);
+        assert_eq!(e.replace_mut(1), None);
+        *e.get_mut().unwrap() += 1;
+        assert_eq!(e.replace_mut(3), Some(2));
+        assert_eq!(e.load(&reclaim::pin()), Some(&3));
+        assert_eq!(e.take_mut(), Some(3));
+        assert_eq!(e.take_mut(), None);
+    }
+
+    #[test]
+    fn unit() {
+        // Sets store `()`, which must neither allocate nor be retired.
+        assert_eq!(std::mem::size_of::<super::Slot<()>>(), 0);
+
+        let guard = reclaim::pin();
+        let collector = Collector::new();
+        let e = super::Entry::new(());
+        assert_eq!(e.try_swap((), &guard, &collector), Ok((Some(&()), &())));
+        assert_eq!(e.delete(&guard, &collector), Some(&()));
+        assert!(e.try_expunge_locked());
+        assert!(collector.is_empty());
     }
 
     #[test]
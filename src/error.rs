@@ -0,0 +1,77 @@
+//! The error returned by the fallible `try_` methods of the maps.
+use std::{convert::Infallible, fmt};
+
+use crate::map::{Full, WouldBlock};
+
+/// Why a `try_` method of a map, such as [`SyncMap::try_swap`], did not take
+/// effect.
+///
+/// `T` is whatever the call was given to store, handed back so that it can
+/// be retried, and `E` the error of a function computing the value.
+///
+/// [`SyncMap::try_swap`]: crate::map::SyncMap::try_swap
+pub enum Error<T = (), E = Infallible> {
+    /// The call would have had to wait for a lock.
+    WouldBlock(T),
+    /// The call would have added a key to a dirty map already holding as
+    /// many new keys as it may. See
+    /// [`SyncMapBuilder::max_dirty_len`](crate::builder::SyncMapBuilder::max_dirty_len).
+    Full(T),
+    /// The function computing the value to store failed.
+    ValueInitFailed(E),
+}
+
+impl<T, E> Error<T, E> {
+    /// Returns what the call was given to store, unless computing the value
+    /// failed.
+    pub fn into_inner(self) -> Option<T> {
+        match self {
+            Error::WouldBlock(t) | Error::Full(t) => Some(t),
+            Error::ValueInitFailed(_) => None,
+        }
+    }
+}
+
+impl<T, E> From<WouldBlock<T>> for Error<T, E> {
+    fn from(WouldBlock(t): WouldBlock<T>) -> Self {
+        Error::WouldBlock(t)
+    }
+}
+
+impl<T, E> From<Full<T>> for Error<T, E> {
+    fn from(Full(t): Full<T>) -> Self {
+        Error::Full(t)
+    }
+}
+
+impl<T, E: fmt::Debug> fmt::Debug for Error<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WouldBlock(_) => f.write_str("WouldBlock(..)"),
+            Error::Full(_) => f.write_str("Full(..)"),
+            Error::ValueInitFailed(e) => f.debug_tuple("ValueInitFailed").field(e).finish(),
+        }
+    }
+}
+
+impl<T, E: fmt::Display> fmt::Display for Error<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WouldBlock(t) => fmt::Display::fmt(&WouldBlock(t), f),
+            Error::Full(t) => fmt::Display::fmt(&Full(t), f),
+            Error::ValueInitFailed(e) => write!(f, "failed to compute the value: {e}"),
+        }
+    }
+}
+
+impl<T, E> std::error::Error for Error<T, E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ValueInitFailed(e) => Some(e),
+            Error::WouldBlock(_) | Error::Full(_) => None,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! A map whose entries can expire.
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    time::{Duration, Instant},
+};
+
+use crate::map::{Maintenance, Ref, SyncMap};
+
+// A value and the instant it stops being visible, if any.
+struct Expiring<V> {
+    value: V,
+    deadline: Option<Instant>,
+}
+
+impl<V> Expiring<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// A [`SyncMap`] where every store may carry a time to live.
+///
+/// An expired entry is treated as absent by every read. Its value is only
+/// deleted, though, when a load comes across it, or by [`sweep`], which is
+/// meant to be called periodically, e.g. from a background task.
+///
+/// [`sweep`]: ExpiringSyncMap::sweep
+pub struct ExpiringSyncMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<K, Expiring<V>, S>,
+
+    // Applied by `store`, if set.
+    default_ttl: Option<Duration>,
+}
+
+impl<K, V> ExpiringSyncMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    /// Creates an empty map whose entries do not expire unless stored with a
+    /// time to live.
+    pub fn new() -> Self {
+        ExpiringSyncMap::with_hasher(RandomState::new())
+    }
+
+    /// Creates an empty map that applies `ttl` to every [`store`].
+    ///
+    /// [`store`]: ExpiringSyncMap::store
+    pub fn with_default_ttl(ttl: Duration) -> Self {
+        let mut map = ExpiringSyncMap::new();
+        map.default_ttl = Some(ttl);
+        map
+    }
+}
+
+impl<K, V> Default for ExpiringSyncMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        ExpiringSyncMap::new()
+    }
+}
+
+impl<K, V, S> ExpiringSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        ExpiringSyncMap {
+            map: SyncMap::with_hasher(hash_builder),
+            default_ttl: None,
+        }
+    }
+
+    /// Returns the value stored for a key, unless it has expired.
+    pub fn load<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let v = self.map.load(key)?;
+        if v.is_expired(Instant::now()) {
+            // Only delete the expired value itself, not one stored since.
+            self.map.remove_same(key, &v);
+            return None;
+        }
+        Some(Ref::map(v, |v| &v.value))
+    }
+
+    /// Sets the value for a key, expiring after the default time to live if
+    /// the map has one.
+    pub fn store(&self, key: K, value: V) {
+        let deadline = self.default_ttl.map(|ttl| Instant::now() + ttl);
+        self.map.store(key, Expiring { value, deadline });
+    }
+
+    /// Sets the value for a key, expiring after `ttl`.
+    pub fn store_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let deadline = Some(Instant::now() + ttl);
+        self.map.store(key, Expiring { value, deadline });
+    }
+
+    /// Deletes the value for a key, returning it if it had not expired.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let v = self.map.remove(key)?;
+        if v.is_expired(Instant::now()) {
+            return None;
+        }
+        Some(Ref::map(v, |v| &v.value))
+    }
+
+    /// Returns how long the value for a key has left to live, or `None` if it
+    /// is absent or does not expire.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let now = Instant::now();
+        let v = self.map.load(key)?;
+        if v.is_expired(now) {
+            return None;
+        }
+        Some(v.deadline?.duration_since(now))
+    }
+
+    /// Calls `f` for each key and value present and not expired, until it
+    /// returns false. See [`SyncMap::range`].
+    pub fn range(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        let now = Instant::now();
+        self.map.range(|k, v| v.is_expired(now) || f(k, &v.value));
+    }
+
+    /// Deletes every expired entry, and returns how many were deleted.
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut swept = 0;
+        self.map.retain(|_, v| {
+            let expired = v.is_expired(now);
+            swept += usize::from(expired);
+            !expired
+        });
+        swept
+    }
+
+    /// Returns a handle to delete expired entries a batch at a time, from
+    /// wherever suits the application, instead of sweeping the whole map at
+    /// once. See [`SyncMap::maintenance`].
+    pub fn maintenance(&self) -> Maintenance<'_> {
+        self.map
+            .maintenance_with(|v: &Expiring<V>| v.is_expired(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn expire() {
+        let map = ExpiringSyncMap::new();
+        map.store(1, "forever");
+        map.store_with_ttl(2, "short", TTL);
+        assert_eq!(*map.load(&2).unwrap(), "short");
+        assert!(map.ttl(&2).unwrap() <= TTL);
+        assert!(map.ttl(&1).is_none());
+
+        thread::sleep(TTL);
+        assert!(map.load(&2).is_none());
+        assert!(map.remove(&2).is_none());
+        assert_eq!(*map.load(&1).unwrap(), "forever");
+
+        // Storing again revives the key.
+        map.store(2, "again");
+        assert_eq!(*map.load(&2).unwrap(), "again");
+    }
+
+    #[test]
+    fn default_ttl() {
+        let map = ExpiringSyncMap::with_default_ttl(TTL);
+        map.store(1, 1);
+        map.store_with_ttl(2, 2, Duration::from_secs(60));
+        thread::sleep(TTL);
+        assert!(map.load(&1).is_none());
+        assert_eq!(*map.load(&2).unwrap(), 2);
+    }
+
+    #[test]
+    fn sweep() {
+        let map = ExpiringSyncMap::new();
+        for i in 0..10 {
+            if i % 2 == 0 {
+                map.store_with_ttl(i, i, TTL);
+            } else {
+                map.store(i, i);
+            }
+        }
+        assert_eq!(map.sweep(), 0);
+
+        thread::sleep(TTL);
+        let mut live = Vec::new();
+        map.range(|k, _| {
+            live.push(*k);
+            true
+        });
+        live.sort();
+        assert_eq!(live, [1, 3, 5, 7, 9]);
+        assert_eq!(map.sweep(), 5);
+        assert_eq!(map.sweep(), 0);
+    }
+
+    #[test]
+    fn maintenance() {
+        let map = ExpiringSyncMap::new();
+        for i in 0..10 {
+            map.store_with_ttl(i, i, TTL);
+        }
+        map.store(10, 10);
+        let mut maintenance = map.maintenance().batch_size(4);
+        while maintenance.run_pending() {}
+
+        thread::sleep(TTL);
+        assert!(maintenance.run_pending());
+        assert!(maintenance.run_pending());
+        assert!(!maintenance.run_pending());
+        assert_eq!(map.sweep(), 0);
+        let mut live = Vec::new();
+        map.range(|k, _| {
+            live.push(*k);
+            true
+        });
+        assert_eq!(live, [10]);
+    }
+}
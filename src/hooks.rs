@@ -0,0 +1,130 @@
+//! Callbacks run when the entries of a map change.
+use std::{fmt, sync::Arc};
+
+type Hook<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+type UpdateHook<K, V> = Arc<dyn Fn(&K, &V, &V) + Send + Sync>;
+
+/// Returns the weight of a key and its value, e.g. their size in bytes.
+pub type Weigher<K, V> = fn(&K, &V) -> usize;
+
+/// The callbacks a [`SyncMap`] runs once a change to its entries has taken
+/// effect, set through a [`SyncMapBuilder`].
+///
+/// Callbacks run on the thread that made the change, after it has released
+/// the dirty lock, so they may use the map. The exception is a write through
+/// a [`MapEntry`], whose callback runs with the key still locked: writing the
+/// same key from it deadlocks.
+///
+/// A value deleted and later expunged by a promotion is only reported once,
+/// when it is deleted.
+///
+/// The map's weigher, if any, is kept here too, as it is run for the same
+/// changes to keep the map's total weight.
+///
+/// [`SyncMap`]: crate::map::SyncMap
+/// [`SyncMapBuilder`]: crate::builder::SyncMapBuilder
+/// [`MapEntry`]: crate::map::MapEntry
+pub struct Hooks<K, V> {
+    pub(crate) on_insert: Option<Hook<K, V>>,
+    pub(crate) on_update: Option<UpdateHook<K, V>>,
+    pub(crate) on_remove: Option<Hook<K, V>>,
+    pub(crate) on_evict: Option<Hook<K, V>>,
+    pub(crate) weigher: Option<Weigher<K, V>>,
+}
+
+impl<K, V> Hooks<K, V> {
+    /// Whether neither a callback nor a weigher is set, so that changes need
+    /// not be reported.
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.on_insert.is_none()
+            && self.on_update.is_none()
+            && self.on_remove.is_none()
+            && self.on_evict.is_none()
+            && self.weigher.is_none()
+    }
+
+    #[inline]
+    pub(crate) fn inserted(&self, key: &K, value: &V) {
+        if let Some(f) = &self.on_insert {
+            f(key, value);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn updated(&self, key: &K, previous: &V, value: &V) {
+        if let Some(f) = &self.on_update {
+            f(key, previous, value);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn removed(&self, key: &K, value: &V) {
+        if let Some(f) = &self.on_remove {
+            f(key, value);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn evicted(&self, key: &K, value: &V) {
+        if let Some(f) = &self.on_evict {
+            f(key, value);
+        }
+    }
+}
+
+impl<K, V> Default for Hooks<K, V> {
+    fn default() -> Self {
+        Hooks {
+            on_insert: None,
+            on_update: None,
+            on_remove: None,
+            on_evict: None,
+            weigher: None,
+        }
+    }
+}
+
+impl<K, V> Clone for Hooks<K, V> {
+    fn clone(&self) -> Self {
+        Hooks {
+            on_insert: self.on_insert.clone(),
+            on_update: self.on_update.clone(),
+            on_remove: self.on_remove.clone(),
+            on_evict: self.on_evict.clone(),
+            weigher: self.weigher,
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for Hooks<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_insert", &self.on_insert.is_some())
+            .field("on_update", &self.on_update.is_some())
+            .field("on_remove", &self.on_remove.is_some())
+            .field("on_evict", &self.on_evict.is_some())
+            .field("weigher", &self.weigher.is_some())
+            .finish()
+    }
+}
+
+/// The callbacks a [`SyncMapBuilder`] has been given so far: `()` until the
+/// first one, which fixes the key and value types.
+///
+/// [`SyncMapBuilder`]: crate::builder::SyncMapBuilder
+pub trait IntoHooks<K, V> {
+    fn into_hooks(self) -> Hooks<K, V>;
+}
+
+impl<K, V> IntoHooks<K, V> for () {
+    fn into_hooks(self) -> Hooks<K, V> {
+        Hooks::default()
+    }
+}
+
+impl<K, V> IntoHooks<K, V> for Hooks<K, V> {
+    fn into_hooks(self) -> Hooks<K, V> {
+        self
+    }
+}
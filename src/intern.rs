@@ -0,0 +1,230 @@
+//! Interned string keys, e.g. for long paths repeated across maps and
+//! shards.
+//!
+//! An [`InternedStr`] is a shared pointer to a string that an [`Interner`]
+//! stores once, however many maps hold it as a key. It hashes and compares
+//! as the string, so a map keyed by them is looked up with a plain `&str`:
+//!
+//! ```
+//! use sync_map::{intern::InternedStr, map::SyncMap};
+//!
+//! let map = SyncMap::new();
+//! map.store(InternedStr::new("/api/v1/users"), 1);
+//! assert_eq!(*map.load("/api/v1/users").unwrap(), 1);
+//! ```
+use std::{
+    borrow::Borrow,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::{Arc, OnceLock},
+};
+
+use crate::map::SyncMap;
+
+/// A string stored once by an [`Interner`].
+///
+/// Cloning it only increments a reference count. Strings interned by the
+/// same interner compare by address first, and by content otherwise.
+#[derive(Clone)]
+pub struct InternedStr(Arc<str>);
+
+impl InternedStr {
+    /// Interns a string in the global interner. See [`Interner::global`].
+    pub fn new(s: &str) -> Self {
+        Interner::global().intern(s)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns whether both are the same interned string, rather than equal
+    /// strings.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(&this.0, &other.0)
+    }
+}
+
+impl From<&str> for InternedStr {
+    fn from(s: &str) -> Self {
+        InternedStr::new(s)
+    }
+}
+
+impl Deref for InternedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for InternedStr {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for InternedStr {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for InternedStr {
+    fn eq(&self, other: &Self) -> bool {
+        InternedStr::ptr_eq(self, other) || self.0 == other.0
+    }
+}
+
+impl Eq for InternedStr {}
+
+impl PartialEq<str> for InternedStr {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for InternedStr {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+// Must hash as the string does, for lookups by `&str`.
+impl Hash for InternedStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl PartialOrd for InternedStr {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternedStr {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Debug for InternedStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for InternedStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A set of strings, each stored once and shared by every [`InternedStr`]
+/// handed out for it.
+///
+/// Strings stay interned until [`Interner::purge`] finds them unused.
+#[derive(Default)]
+pub struct Interner {
+    strings: SyncMap<InternedStr, ()>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    /// Returns the interner used by [`InternedStr::new`], shared by the whole
+    /// process.
+    pub fn global() -> &'static Interner {
+        static GLOBAL: OnceLock<Interner> = OnceLock::new();
+        GLOBAL.get_or_init(Interner::new)
+    }
+
+    /// Returns the interned copy of a string, interning it if there is none.
+    ///
+    /// Once the string has been interned, this only takes a lookup in the
+    /// read map and a reference count increment.
+    pub fn intern(&self, s: &str) -> InternedStr {
+        loop {
+            if let Some(r) = self.strings.get_key_value(s) {
+                return r.key().clone();
+            }
+            // Of concurrent calls, the first to store wins, and the others
+            // return its copy.
+            self.strings.load_or_store(InternedStr(Arc::from(s)), ());
+        }
+    }
+
+    /// Returns the interned copy of a string, if there is one.
+    pub fn get(&self, s: &str) -> Option<InternedStr> {
+        self.strings.get_key_value(s).map(|r| r.key().clone())
+    }
+
+    /// Drops the strings no [`InternedStr`] refers to anymore, returning how
+    /// many were dropped.
+    ///
+    /// A string interned again while it is being dropped gets a new copy, so
+    /// the same string may briefly be held twice; copies still compare equal.
+    pub fn purge(&self) -> usize {
+        let mut purged = 0;
+        self.strings.retain(|s, _| {
+            let unused = Arc::strong_count(&s.0) == 1;
+            purged += unused as usize;
+            !unused
+        });
+        purged
+    }
+
+    /// Returns the number of strings interned.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.strings.range(|_, _| {
+            len += 1;
+            true
+        });
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for Interner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern() {
+        let interner = Interner::new();
+        let a = interner.intern("/a/long/path");
+        let b = interner.intern(&String::from("/a/long/path"));
+        assert!(InternedStr::ptr_eq(&a, &b));
+        assert_eq!(a, "/a/long/path");
+        assert_eq!(interner.len(), 1);
+        assert!(interner.get("/other").is_none());
+
+        let map = SyncMap::new();
+        map.store(a.clone(), 1);
+        assert_eq!(*map.load("/a/long/path").unwrap(), 1);
+        assert!(map.remove("/a/long/path").is_some());
+
+        // Still held by `a` and `b`.
+        assert_eq!(interner.purge(), 0);
+        std::mem::drop((a, b, map));
+        assert_eq!(interner.purge(), 1);
+        assert!(interner.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Keys shared between the read and dirty maps.
+//!
+//! Every key is allocated once and shared by all the maps that hold it, so
+//! building a dirty map from the read map neither clones keys nor requires
+//! them to be cloneable.
+//!
+//! A `HashMap<Key<K>, _>` can only be queried with types `Key<K>` borrows
+//! as, and `Key<K>` cannot borrow as every `Q` that `K` does without
+//! conflicting with the blanket `Borrow<T> for T`. Instead, it borrows as a
+//! [`KeyQuery<Q>`] trait object, which hashes and compares as the `Q` it
+//! holds; a `&Q` is looked up by wrapping it in a [`Query`].
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+pub(crate) struct Key<K>(Arc<K>);
+
+impl<K> Key<K> {
+    pub(crate) fn new(key: K) -> Self {
+        Key(Arc::new(key))
+    }
+
+    pub(crate) fn get(&self) -> &K {
+        &self.0
+    }
+
+    /// Returns the key if no other map shares it.
+    pub(crate) fn into_inner(self) -> Option<K> {
+        Arc::into_inner(self.0)
+    }
+
+    /// Returns the key, cloning it if another map shares it.
+    pub(crate) fn into_owned(self) -> K
+    where
+        K: Clone,
+    {
+        Arc::unwrap_or_clone(self.0)
+    }
+}
+
+impl<K> Clone for Key<K> {
+    fn clone(&self) -> Self {
+        Key(self.0.clone())
+    }
+}
+
+impl<K: Hash> Hash for Key<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get().hash(state)
+    }
+}
+
+impl<K: PartialEq> PartialEq for Key<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<K: Eq> Eq for Key<K> {}
+
+impl<K: PartialOrd> PartialOrd for Key<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.get().partial_cmp(other.get())
+    }
+}
+
+impl<K: Ord> Ord for Key<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.get().cmp(other.get())
+    }
+}
+
+/// Something that can be looked up as a `Q`.
+pub(crate) trait KeyQuery<Q: ?Sized> {
+    fn query(&self) -> &Q;
+}
+
+impl<K: Borrow<Q>, Q: ?Sized> KeyQuery<Q> for Key<K> {
+    fn query(&self) -> &Q {
+        self.get().borrow()
+    }
+}
+
+impl<'a, K: Borrow<Q> + 'a, Q: ?Sized + 'a> Borrow<dyn KeyQuery<Q> + 'a> for Key<K> {
+    fn borrow(&self) -> &(dyn KeyQuery<Q> + 'a) {
+        self
+    }
+}
+
+// `Borrow` requires these to agree with `Key<K>`'s, which holds as long as
+// `K`'s agree with `Q`'s. The ordering is only needed by ordered maps.
+impl<Q: Hash + ?Sized> Hash for dyn KeyQuery<Q> + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.query().hash(state)
+    }
+}
+
+impl<Q: PartialEq + ?Sized> PartialEq for dyn KeyQuery<Q> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.query() == other.query()
+    }
+}
+
+impl<Q: Eq + ?Sized> Eq for dyn KeyQuery<Q> + '_ {}
+
+impl<Q: PartialOrd + ?Sized> PartialOrd for dyn KeyQuery<Q> + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.query().partial_cmp(other.query())
+    }
+}
+
+impl<Q: Ord + ?Sized> Ord for dyn KeyQuery<Q> + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.query().cmp(other.query())
+    }
+}
+
+/// A borrowed key to look up.
+pub(crate) struct Query<'q, Q: ?Sized>(pub(crate) &'q Q);
+
+impl<Q: ?Sized> Query<'_, Q> {
+    pub(crate) fn as_dyn(&self) -> &dyn KeyQuery<Q> {
+        self
+    }
+}
+
+impl<Q: ?Sized> KeyQuery<Q> for Query<'_, Q> {
+    fn query(&self) -> &Q {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn query() {
+        let mut m = HashMap::new();
+        let key = Key::new(String::from("a"));
+        m.insert(key.clone(), 1);
+        assert_eq!(m.get(Query("a").as_dyn()), Some(&1));
+        assert_eq!(m.get(Query(&String::from("a")).as_dyn()), Some(&1));
+        assert_eq!(m.get(Query("b").as_dyn()), None);
+
+        let (k, _) = m.get_key_value(Query("a").as_dyn()).unwrap();
+        assert!(Arc::ptr_eq(&k.0, &key.0));
+        assert!(m.remove(Query("a").as_dyn()).is_some());
+        assert_eq!(key.into_inner().as_deref(), Some("a"));
+    }
+}
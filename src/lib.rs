@@ -1,2 +1,24 @@
+pub mod atomic;
+pub mod btree;
+pub mod builder;
+pub mod compat;
 mod entry;
+pub mod error;
+pub mod expiring;
+pub mod hooks;
+#[cfg(feature = "intern")]
+pub mod intern;
+mod key;
 pub mod map;
+pub mod multi;
+mod order;
+#[cfg(feature = "persist")]
+pub mod persist;
+pub mod policy;
+mod reclaim;
+pub mod set;
+pub mod sharded;
+pub mod stats;
+#[cfg(feature = "async")]
+pub mod watch;
+pub mod weak;
@@ -1,63 +1,279 @@
 use std::{
-    collections::HashMap,
+    borrow::Borrow,
+    collections::{
+        hash_map::{self, RandomState},
+        HashMap,
+    },
+    convert::Infallible,
+    fmt,
+    hash::BuildHasher,
+    ops::Deref,
     ptr,
-    rc::Rc,
-    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::Mutex;
 
-use crate::entry::Entry;
+use crate::{
+    atomic::AtomicValue,
+    builder::SyncMapBuilder,
+    entry::{Entry, EntryState, TryInsert, TrySwap, Unlinked},
+    error::Error,
+    hooks::Hooks,
+    key::{Key, Query},
+    order::{ACQUIRE, RELAXED, RELEASE},
+    policy::{
+        Candidates, DirtyOverflow, EvictionPolicy, MissThreshold, PromotionPolicy, SampledLru,
+    },
+    reclaim::{self, Collector, Guard},
+    stats::Counters,
+};
 
 // The actual inner map.
-type Map<K, V> = HashMap<K, Rc<Entry<V>>>;
+type Map<K, V, S> = HashMap<Key<K>, Arc<Entry<V>>, S>;
+
+// A read map and the key and entry found in it, if any.
+type ReadEntry<'g, K, V, S> = (
+    &'g ReadOnly<K, V, S>,
+    Option<(&'g Key<K>, &'g Arc<Entry<V>>)>,
+);
 
-struct ReadOnly<K, V>
+struct ReadOnly<K, V, S>
 where
     K: std::cmp::Eq + std::hash::Hash,
 {
-    m: Map<K, V>,
-    amended: bool,
+    m: Map<K, V, S>,
+
+    // True if the dirty map contains some key not in m. Only set with the
+    // dirty lock held.
+    amended: AtomicBool,
 }
 
-impl<K, V> Default for ReadOnly<K, V>
+impl<K, V, S> ReadOnly<K, V, S>
 where
-    K: std::cmp::Eq + std::hash::Hash + Clone,
+    K: std::cmp::Eq + std::hash::Hash,
 {
-    fn default() -> Self {
-        ReadOnly::new()
+    fn new(m: Map<K, V, S>) -> Self {
+        ReadOnly {
+            m,
+            amended: AtomicBool::new(false),
+        }
     }
 }
 
-impl<K, V> ReadOnly<K, V>
+impl<K, V, S> ReadOnly<K, V, S>
 where
-    K: std::cmp::Eq + std::hash::Hash + Clone,
+    K: std::cmp::Eq + std::hash::Hash,
 {
-    fn new() -> Self {
-        ReadOnly {
-            m: HashMap::new(),
-            amended: false,
+    // Retires a read map unlinked from `SyncMap::read`. Its memory is only
+    // freed once no scan holds it either.
+    unsafe fn retire(read: *mut Self, collector: &Collector) {
+        let read = Arc::from_raw(read);
+        collector.retire(Box::into_raw(Box::new(read)));
+    }
+}
+
+/// A reference to a value in a [`SyncMap`].
+///
+/// The value stays valid while the `Ref` is held, even if it is concurrently
+/// replaced or removed from the map.
+pub struct Ref<'a, V> {
+    _guard: Guard,
+    value: &'a V,
+}
+
+impl<'a, V> Ref<'a, V> {
+    // The caller must ensure `value` outlives `guard`.
+    pub(crate) unsafe fn new(guard: Guard, value: *const V) -> Self {
+        Ref {
+            _guard: guard,
+            value: &*value,
+        }
+    }
+
+    /// Makes a `Ref` to a component of the value, which stays valid for as
+    /// long as the value does.
+    pub fn map<U>(r: Ref<'a, V>, f: impl FnOnce(&V) -> &U) -> Ref<'a, U> {
+        Ref {
+            value: f(r.value),
+            _guard: r._guard,
+        }
+    }
+}
+
+impl<V> Deref for Ref<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for Ref<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// A reference to a key and its value in a [`SyncMap`], yielded by
+/// [`SyncMap::iter`].
+///
+/// Both stay valid while the `RefPair` is held.
+pub struct RefPair<'a, K, V> {
+    _guard: Guard,
+    key: &'a K,
+    value: &'a V,
+}
+
+impl<'a, K, V> RefPair<'a, K, V> {
+    // The caller must ensure `key` and `value` outlive `guard`.
+    pub(crate) unsafe fn new(guard: Guard, key: *const K, value: *const V) -> Self {
+        RefPair {
+            _guard: guard,
+            key: &*key,
+            value: &*value,
         }
     }
+
+    pub fn key(&self) -> &K {
+        self.key
+    }
+
+    pub fn value(&self) -> &V {
+        self.value
+    }
+
+    pub fn pair(&self) -> (&K, &V) {
+        (self.key, self.value)
+    }
+
+    /// Keeps only the reference to the key.
+    pub fn into_key(self) -> Ref<'a, K> {
+        Ref {
+            _guard: self._guard,
+            value: self.key,
+        }
+    }
+
+    /// Keeps only the reference to the value.
+    pub fn into_value(self) -> Ref<'a, V> {
+        Ref {
+            _guard: self._guard,
+            value: self.value,
+        }
+    }
+}
+
+/// The error returned by [`SyncMap::try_insert`] when the key is present.
+pub struct OccupiedError<'a, V> {
+    /// The value in the map.
+    pub current: Ref<'a, V>,
+    /// The value that was not inserted.
+    pub value: V,
+}
+
+impl<V: fmt::Debug> fmt::Debug for OccupiedError<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("current", &*self.current)
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Display for OccupiedError<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to insert {:?}, key already holds {:?}",
+            self.value, &*self.current
+        )
+    }
+}
+
+impl<V: fmt::Debug> std::error::Error for OccupiedError<'_, V> {}
+
+/// The error returned by the `try_` methods of [`SyncMap`] when they would
+/// have to wait for a lock. Holds whatever the call was given to store.
+pub struct WouldBlock<T = ()>(pub T);
+
+impl<T> fmt::Debug for WouldBlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WouldBlock(..)")
+    }
+}
+
+impl<T> fmt::Display for WouldBlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the operation would block on a lock")
+    }
+}
+
+impl<T> std::error::Error for WouldBlock<T> {}
+
+/// The error returned by [`SyncMap::store_with_limit`] when the dirty map
+/// holds as many new keys as it may. Holds what the call was given to store.
+pub struct Full<T>(pub T);
+
+impl<T> fmt::Debug for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Full(..)")
+    }
+}
+
+impl<T> fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the dirty map is full")
+    }
+}
+
+impl<T> std::error::Error for Full<T> {}
+
+impl<K, V> Deref for RefPair<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for RefPair<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.pair().fmt(f)
+    }
 }
 
-pub struct SyncMap<K, V>
+// Bounds the number of entries in the dirty map, and with it the read map,
+// or the total weight of the map.
+struct Bound<K> {
+    max_entries: usize,
+    max_weight: usize,
+    policy: Box<dyn EvictionPolicy<K>>,
+
+    // Ticks with every key added, to order accesses.
+    clock: AtomicU64,
+}
+
+pub struct SyncMap<K, V, S = RandomState>
 where
     K: std::cmp::Eq + std::hash::Hash,
 {
-    // Held when store the read map.
-    mu: Mutex<()>,
-
     // read contains the portion of the map's contents that are safe for
-    // concurrent access (with or without mu held).
+    // concurrent access (with or without the dirty lock held).
     //
     // The read field itself is always safe to load, but must only be stored with
-    // mu held.
+    // the dirty lock held.
     //
-    // Entries stored in read may be updated concurrently without mu, but updating
-    // a previously-expunged entry requires that the entry be copied to the dirty
-    // map and unexpunged with mu held.
-    read: AtomicPtr<ReadOnly<K, V>>,
+    // Entries stored in read may be updated concurrently without the lock, but
+    // updating a previously-expunged entry requires that the entry be copied to
+    // the dirty map and unexpunged with the lock held.
+    //
+    // Points into an `Arc`, so that a scan can keep a read map alive without
+    // staying pinned.
+    read: AtomicPtr<ReadOnly<K, V, S>>,
 
     // dirty contains the portion of the map's contents that require mutex to be
     // held. To ensure that the dirty map can be promoted to the read map quickly,
@@ -69,135 +285,5065 @@ where
     //
     // If the dirty map is nil, the next write to the map will initialize it by
     // making a shallow copy of the clean map, omitting stale entries.
-    dirty: Mutex<Option<Map<K, V>>>,
+    dirty: Mutex<Option<Map<K, V, S>>>,
 
     misses: AtomicU64,
+
+    // Decides, from misses and the dirty map size, when to promote.
+    policy: Arc<dyn PromotionPolicy>,
+
+    // Only maintained with the `stats` feature.
+    counters: Counters,
+
+    // Only set for bounded maps.
+    bound: Option<Bound<K>>,
+
+    // Run once a change has taken effect.
+    hooks: Hooks<K, V>,
+
+    // The total weight of the values, as the weigher in `hooks` sees them.
+    // Only counted if there is one.
+    weight: AtomicUsize,
+
+    // Values evicted with the lock held, held back from the collector until
+    // the eviction callback has seen them. Only used if there is one.
+    evicted: Mutex<Vec<(Key<K>, Unlinked<V>)>>,
+
+    // Values for which this returns true are dropped, along with their key,
+    // when the dirty map is next copied from the read map.
+    stale: Option<fn(&V) -> bool>,
+
+    // Set to compact the map once the values deleted since the tables were
+    // last cleaned reach this ratio of the read map's size.
+    compaction: Option<f64>,
+
+    // Deletions since the tables were last cleaned. Only counted if
+    // `compaction` is set.
+    deleted: AtomicUsize,
+
+    // The most keys the dirty map may gain between promotions, and what
+    // happens to a new key past that.
+    dirty_limit: Option<(usize, DirtyOverflow)>,
+
+    // Keys added to the dirty map since the last promotion. Only written with
+    // the dirty lock held.
+    pending: AtomicUsize,
+
+    // The minimum capacity of a freshly created dirty map, so that capacity
+    // requested up front survives promotions. Only written with the dirty
+    // lock held.
+    capacity: AtomicUsize,
+
+    // Replaced values, unlinked entries and retired read maps wait here until
+    // no reader can still observe them.
+    collector: Collector,
+
+    // Cloned into every read and dirty map.
+    hash_builder: S,
 }
 
-impl<K, V> Default for SyncMap<K, V>
+unsafe impl<K, V, S> Send for SyncMap<K, V, S>
 where
-    K: std::cmp::Eq + std::hash::Hash + Clone,
+    K: std::cmp::Eq + std::hash::Hash + Send,
+    V: Send,
+    S: Send,
+{
+}
+
+unsafe impl<K, V, S> Sync for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Send + Sync,
+    V: Send + Sync,
+    S: Sync,
+{
+}
+
+impl<K, V, S> Default for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone + Default,
 {
     fn default() -> Self {
-        SyncMap::new()
+        SyncMap::with_hasher(S::default())
     }
 }
 
-impl<K, V> SyncMap<K, V>
+impl<K, V> SyncMap<K, V, RandomState>
 where
-    K: std::cmp::Eq + std::hash::Hash + Clone,
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new() -> SyncMap<K, V, RandomState> {
+        SyncMap::with_hasher(RandomState::new())
+    }
+
+    /// Creates an empty map whose dirty map can hold at least `capacity`
+    /// entries without reallocating.
+    pub fn with_capacity(capacity: usize) -> SyncMap<K, V, RandomState> {
+        SyncMap::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+
+    /// Creates an empty map that holds at most `max_entries` keys, evicting
+    /// the least recently used of a few sampled keys when a new key would go
+    /// past the bound. See [`SampledLru`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_entries` is zero.
+    pub fn bounded(max_entries: usize) -> SyncMap<K, V, RandomState>
+    where
+        K: Clone + Send + 'static,
+    {
+        let mut map = SyncMap::with_capacity(max_entries);
+        map.set_bound(max_entries, Box::new(SampledLru::default()));
+        map
+    }
+
+    /// Returns a builder to configure a map before creating it.
+    pub fn builder() -> SyncMapBuilder {
+        SyncMapBuilder::new()
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
 {
-    pub fn new() -> SyncMap<K, V> {
+    /// Creates an empty map which will use the given hash builder to hash
+    /// keys, in both the read and the dirty map.
+    pub fn with_hasher(hash_builder: S) -> SyncMap<K, V, S> {
+        SyncMap::with_capacity_and_hasher(0, hash_builder)
+    }
+
+    /// Creates an empty map with the given capacity, using `hash_builder` to
+    /// hash keys.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> SyncMap<K, V, S> {
+        SyncMap::with_policy(capacity, hash_builder, Arc::new(MissThreshold::default()))
+    }
+
+    pub(crate) fn with_policy(
+        capacity: usize,
+        hash_builder: S,
+        policy: Arc<dyn PromotionPolicy>,
+    ) -> SyncMap<K, V, S> {
+        let read = Self::new_readonly(HashMap::with_hasher(hash_builder.clone()));
         SyncMap {
-            mu: Mutex::new(()),
-            read: AtomicPtr::new(ptr::null_mut()),
-            dirty: Mutex::new(Some(HashMap::new())),
+            read: AtomicPtr::new(read),
+            dirty: Mutex::new(None),
             misses: AtomicU64::new(0),
+            policy,
+            counters: Counters::default(),
+            bound: None,
+            hooks: Hooks::default(),
+            weight: AtomicUsize::new(0),
+            evicted: Mutex::new(Vec::new()),
+            stale: None,
+            compaction: None,
+            deleted: AtomicUsize::new(0),
+            dirty_limit: None,
+            pending: AtomicUsize::new(0),
+            capacity: AtomicUsize::new(capacity),
+            collector: Collector::new(),
+            hash_builder,
+        }
+    }
+
+    pub(crate) fn set_bound(&mut self, max_entries: usize, policy: Box<dyn EvictionPolicy<K>>) {
+        assert!(max_entries > 0, "a bounded map must hold at least one key");
+        self.bound = Some(Bound {
+            max_entries,
+            max_weight: usize::MAX,
+            policy,
+            clock: AtomicU64::new(0),
+        });
+    }
+
+    pub(crate) fn set_weight_bound(
+        &mut self,
+        max_weight: usize,
+        policy: Box<dyn EvictionPolicy<K>>,
+    ) {
+        assert!(
+            self.hooks.weigher.is_some(),
+            "a map bounded by weight needs a weigher"
+        );
+        self.bound = Some(Bound {
+            max_entries: usize::MAX,
+            max_weight,
+            policy,
+            clock: AtomicU64::new(0),
+        });
+    }
+
+    pub(crate) fn set_hooks(&mut self, hooks: Hooks<K, V>) {
+        self.hooks = hooks;
+    }
+
+    pub(crate) fn set_stale(&mut self, stale: fn(&V) -> bool) {
+        self.stale = Some(stale);
+    }
+
+    pub(crate) fn set_compaction(&mut self, ratio: f64) {
+        assert!(ratio > 0.0, "the compaction ratio must be positive");
+        self.compaction = Some(ratio);
+    }
+
+    pub(crate) fn set_dirty_limit(&mut self, max_len: usize, overflow: DirtyOverflow) {
+        assert!(max_len > 0, "the dirty map must be able to hold a new key");
+        self.dirty_limit = Some((max_len, overflow));
+    }
+
+    /// Reserves capacity for at least `additional` more keys.
+    ///
+    /// The reservation applies to the current dirty map, if any, and to every
+    /// dirty map created after it is promoted.
+    pub fn reserve(&self, additional: usize) {
+        let guard = reclaim::pin();
+        let mut dirty = self.dirty.lock();
+        let len = match dirty.as_mut() {
+            Some(d) => {
+                d.reserve(additional);
+                d.len()
+            }
+            None => self.load_readonly(&guard).m.len(),
+        };
+
+        let capacity = len.saturating_add(additional);
+        if capacity > self.capacity.load(Ordering::Relaxed) {
+            self.capacity.store(capacity, Ordering::Relaxed);
         }
     }
 
+    /// Returns the total weight of the values in the map, as given by the
+    /// weigher set through [`SyncMapBuilder::weigher`], or 0 without one.
+    ///
+    /// The weight is updated once a change has taken effect, as callbacks are
+    /// run, so it may briefly lag behind concurrent writes.
+    pub fn weight(&self) -> usize {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    /// Returns a reference to the map's [`BuildHasher`].
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
     #[inline]
-    fn load_readonly(&self) -> Option<&ReadOnly<K, V>> {
-        let read_map = self.read.load(Ordering::Acquire);
-        if read_map.is_null() {
+    fn load_readonly<'g>(&self, _guard: &'g Guard) -> &'g ReadOnly<K, V, S> {
+        // Retired read maps outlive every guard that could have loaded them.
+        unsafe { &*self.read.load(ACQUIRE) }
+    }
+
+    // Entries unlinked from either map are retired rather than dropped, so an
+    // entry found under the lock stays valid after it is released.
+    #[inline]
+    fn pinned_entry<'g>(e: &Arc<Entry<V>>, _guard: &'g Guard) -> &'g Entry<V> {
+        unsafe { &*Arc::as_ptr(e) }
+    }
+
+    // Likewise, a key evicted from the dirty map is retired along with its
+    // entry, and every other key lives as long as a read map.
+    #[inline]
+    fn key_ref<'g>(k: &Key<K>, _guard: &'g Guard) -> &'g K {
+        unsafe { &*ptr::from_ref(k.get()) }
+    }
+
+    /// Returns the value stored in the map for a key.
+    ///
+    /// The key may be any borrowed form of the map's key type, as with
+    /// `HashMap`.
+    pub fn load<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let (_, e) = self.find_entry(key, &guard)?;
+        let value: *const V = e.load(&guard)?;
+        self.touch(e);
+        Some(unsafe { Ref::new(guard, value) })
+    }
+
+    // Looks up a key in the read map. An entry found expunged may have been
+    // replaced by a transaction, whose new read map is then searched instead.
+    fn read_entry<'g, Q>(&self, key: &Q, guard: &'g Guard) -> ReadEntry<'g, K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let mut read = self.load_readonly(guard);
+        loop {
+            let found = read.m.get_key_value(Query(key).as_dyn());
+            if let Some((_, e)) = found {
+                if matches!(e.state(guard), EntryState::HardDelete) {
+                    let current = self.load_readonly(guard);
+                    if !ptr::eq(read, current) {
+                        read = current;
+                        continue;
+                    }
+                }
+            }
+            return (read, found);
+        }
+    }
+
+    // Looks up the stored key and the entry for a key, falling back to the
+    // dirty map if the read map is amended.
+    fn find_entry<'g, Q>(&self, key: &Q, guard: &'g Guard) -> Option<(&'g K, &'g Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let (read, found) = self.read_entry(key, guard);
+        if let Some((k, e)) = found {
+            self.counters.read_hit();
+            return Some((Self::key_ref(k, guard), Self::pinned_entry(e, guard)));
+        }
+        if !read.amended.load(RELAXED) {
+            self.counters.miss();
+            return None;
+        }
+
+        let mut dirty = self.dirty.lock();
+        self.find_entry_locked(key, &mut dirty, guard)
+    }
+
+    // The slow path of `find_entry`, taken with the lock held.
+    fn find_entry_locked<'g, Q>(
+        &self,
+        key: &Q,
+        dirty: &mut Option<Map<K, V, S>>,
+        guard: &'g Guard,
+    ) -> Option<(&'g K, &'g Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        // Avoid reporting a spurious miss if the dirty map got promoted
+        // while we were blocked on the lock.
+        let read = self.load_readonly(guard);
+        if let Some((k, e)) = read.m.get_key_value(Query(key).as_dyn()) {
+            self.counters.read_hit();
+            return Some((Self::key_ref(k, guard), Self::pinned_entry(e, guard)));
+        }
+        if !read.amended.load(RELAXED) {
+            self.counters.miss();
             return None;
         }
 
-        unsafe { Some(&*read_map) }
+        let e = dirty
+            .as_ref()
+            .and_then(|d| d.get_key_value(Query(key).as_dyn()))
+            .map(|(k, e)| (Self::key_ref(k, guard), Self::pinned_entry(e, guard)));
+        if e.is_some() {
+            self.counters.dirty_hit();
+        } else {
+            self.counters.miss();
+        }
+        // Regardless of whether the entry was present, record a miss:
+        // this key will take the slow path until the dirty map is
+        // promoted to the read map.
+        self.miss_locked(dirty);
+        e
     }
 
-    // TODO: reduce the logic.
-    // The whole serach logic is like this:
-    // First check the key in the read map, this don't need the lock.
-    // Then try to find it in the dirty map, note this need the lock
-    pub fn load<'a>(&'a self, key: &K) -> Option<&'a V> {
-        let read_only = self.load_readonly();
+    /// Returns the values stored in the map for each of the keys, in order.
+    ///
+    /// Behaves like calling [`SyncMap::load`] for each key, but the keys
+    /// missing from the read map are all looked up in the dirty map under a
+    /// single acquisition of the lock.
+    pub fn load_many<'q, Q>(&self, keys: impl IntoIterator<Item = &'q Q>) -> Vec<Option<Ref<'_, V>>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized + 'q,
+    {
+        let guard = reclaim::pin();
+        let read = self.load_readonly(&guard);
+        let amended = read.amended.load(RELAXED);
+        let mut entries = Vec::new();
+        let mut missed = Vec::new();
+        for key in keys {
+            let e = read
+                .m
+                .get(Query(key).as_dyn())
+                .map(|e| Self::pinned_entry(e, &guard));
+            if e.is_some() {
+                self.counters.read_hit();
+            } else if amended {
+                missed.push((entries.len(), key));
+            } else {
+                self.counters.miss();
+            }
+            entries.push(e);
+        }
 
-        if let Some(read) = read_only {
-            let present = read.m.contains_key(key);
-            // Maybe the KV is in the dirty map, but need to check if the read map
-            // has any change.
-            if !present && read.amended {
-                let guard = self.dirty.lock().as_ref().unwrap();
-                return self.load_dirty_locked(key, &guard);
+        if !missed.is_empty() {
+            let mut dirty = self.dirty.lock();
+            for (i, key) in missed {
+                entries[i] = self
+                    .find_entry_locked(key, &mut dirty, &guard)
+                    .map(|(_, e)| e);
             }
+        }
 
-            // Never insert this key before.
-            if !present {
+        entries
+            .into_iter()
+            .map(|e| {
+                let e = e?;
+                let value: *const V = e.load(&guard)?;
+                self.touch(e);
+                // Every value pins on its own, so it may outlive `guard`.
+                Some(unsafe { Ref::new(reclaim::pin(), value) })
+            })
+            .collect()
+    }
+
+    /// Returns whether the map holds a value for a key.
+    ///
+    /// Unlike [`SyncMap::load`], this does not count as an access to the key
+    /// for the eviction policy of a bounded map.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        self.find_entry(key, &guard)
+            .is_some_and(|(_, e)| e.load(&guard).is_some())
+    }
+
+    /// Returns the key stored in the map along with its value, e.g. to get
+    /// hold of a canonical key equal to the one looked up.
+    ///
+    /// The stored key must stay put for as long as it is referenced, so a key
+    /// only found in the dirty map gets it promoted first, as
+    /// [`SyncMap::range`] does.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<RefPair<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let mut read = self.load_readonly(&guard);
+        if read.m.contains_key(Query(key).as_dyn()) {
+            self.counters.read_hit();
+        } else if !read.amended.load(RELAXED) {
+            self.counters.miss();
+            return None;
+        } else {
+            let mut dirty = self.dirty.lock();
+            read = self.load_readonly(&guard);
+            if read.m.contains_key(Query(key).as_dyn()) {
+                self.counters.read_hit();
+            } else if dirty
+                .as_ref()
+                .is_some_and(|d| d.contains_key(Query(key).as_dyn()))
+            {
+                self.counters.dirty_hit();
+                self.promote_locked(&mut dirty);
+                read = self.load_readonly(&guard);
+            } else {
+                self.counters.miss();
+                self.miss_locked(&mut dirty);
                 return None;
             }
+        }
+
+        let (k, e) = read.m.get_key_value(Query(key).as_dyn())?;
+        let v: *const V = e.load(&guard)?;
+        self.touch(e);
+        let k: *const K = k.get();
+        Some(unsafe { RefPair::new(guard, k, v) })
+    }
+
+    /// Like [`SyncMap::load`], but returns [`WouldBlock`] instead of waiting
+    /// if the key is missing from the read map and the dirty lock is held.
+    ///
+    /// None of the `try_` methods ever wait for the dirty lock or for an
+    /// entry locked by a [`MapEntry`], and they leave freeing retired values
+    /// to other calls.
+    pub fn try_load<Q>(&self, key: &Q) -> Result<Option<Ref<'_, V>>, WouldBlock>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let Some((_, e)) = self.try_find_entry(key, &guard)? else {
+            return Ok(None);
+        };
+        let Some(value) = e.load(&guard) else {
+            return Ok(None);
+        };
+        let value: *const V = value;
+        self.touch(e);
+        Ok(Some(unsafe { Ref::new(guard, value) }))
+    }
+
+    /// Like [`SyncMap::store`], but hands the key and value back instead of
+    /// waiting for a lock. See [`SyncMap::try_load`].
+    pub fn try_store(&self, key: K, value: V) -> Result<(), WouldBlock<(K, V)>> {
+        self.try_swap_limited(key, value, None).map(drop)
+    }
+
+    /// Like [`SyncMap::swap`], but hands the key and value back instead of
+    /// waiting for a lock, or instead of adding a key to a dirty map that
+    /// rejects it, as [`SyncMap::store_with_limit`] does. See
+    /// [`SyncMap::try_load`].
+    pub fn try_swap(&self, key: K, value: V) -> Result<Option<Ref<'_, V>>, Error<(K, V)>> {
+        self.try_swap_limited(key, value, Some(|k, v| Error::Full((k, v))))
+    }
+
+    // Like `swap_limited`, but without waiting for a lock.
+    fn try_swap_limited<E>(
+        &self,
+        key: K,
+        value: V,
+        reject: Option<fn(K, V) -> E>,
+    ) -> Result<Option<Ref<'_, V>>, E>
+    where
+        E: From<WouldBlock<(K, V)>>,
+    {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_swap_nowait(value, &guard, &self.collector) {
+                    TrySwap::Swapped(previous, value) => {
+                        self.touch(e);
+                        self.stored(&key, previous, value);
+                        let previous = previous.map(ptr::from_ref);
+                        return Ok(Self::wrap(guard, previous));
+                    }
+                    TrySwap::Locked(v) => return Err(WouldBlock((key, v)).into()),
+                    TrySwap::Expunged(v) => value = v,
+                }
+            }
 
-            // Find in the read map.
-            read.m.get(key).as_ref().unwrap().load();
+            let Some(mut dirty) = self.dirty.try_lock() else {
+                return Err(WouldBlock((key, value)).into());
+            };
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard, false) else {
+                if let Some(full) = reject.filter(|_| self.rejects_locked()) {
+                    return Err(full(key, value));
+                }
+                let (key, value) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                drop(dirty);
+                self.inserted(key, value);
+                return Ok(None);
+            };
+            drop(dirty);
+
+            match e.try_swap_nowait(value, &guard, &self.collector) {
+                TrySwap::Swapped(previous, value) => {
+                    self.touch(e);
+                    self.stored(&key, previous, value);
+                    let previous = previous.map(ptr::from_ref);
+                    return Ok(Self::wrap(guard, previous));
+                }
+                TrySwap::Locked(v) => return Err(WouldBlock((key, v)).into()),
+                // Expunged again by a promotion since we released the lock.
+                TrySwap::Expunged(v) => value = v,
+            }
         }
+    }
 
-        None
+    /// Returns the value for a key, storing the result of `f` first if the key
+    /// is absent, without waiting for a lock. See [`SyncMap::try_load`].
+    ///
+    /// Unlike [`SyncMap::get_or_try_insert_with`], `f` is called without
+    /// locking the key, at most once, and its value is dropped if another
+    /// call stores one first. The key is handed back if the call would have
+    /// had to wait, or to add it to a dirty map that rejects it, as
+    /// [`SyncMap::store_with_limit`] does; the value computed, if any, is
+    /// then dropped.
+    pub fn try_get_or_insert_with<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Ref<'_, V>, Error<K, E>> {
+        match self.try_load(&key) {
+            Ok(Some(v)) => return Ok(v),
+            Ok(None) => {}
+            Err(WouldBlock(())) => return Err(Error::WouldBlock(key)),
+        }
+        let mut value = f().map_err(Error::ValueInitFailed)?;
+
+        let guard = reclaim::pin();
+        loop {
+            let read = self.load_readonly(&guard);
+            let found = read.m.get(Query(&key).as_dyn());
+            if let Some(e) = found {
+                if let Some(v) = e.load(&guard) {
+                    self.touch(e);
+                    let v: *const V = v;
+                    return Ok(unsafe { Ref::new(guard, v) });
+                }
+            }
+
+            let e = match found {
+                Some(e) => e,
+                None => {
+                    let Some(mut dirty) = self.dirty.try_lock() else {
+                        return Err(Error::WouldBlock(key));
+                    };
+                    let Some(e) = self.entry_locked(&key, &mut dirty, &guard, true) else {
+                        if self.rejects_locked() {
+                            return Err(Error::Full(key));
+                        }
+                        let (key, v) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                        drop(dirty);
+                        self.inserted(key, v);
+                        let v: *const V = v;
+                        return Ok(unsafe { Ref::new(guard, v) });
+                    };
+                    e
+                }
+            };
+
+            match e.try_insert_nowait(value, &guard) {
+                TryInsert::Stored(v) => {
+                    self.touch(e);
+                    self.inserted(&key, v);
+                    let v: *const V = v;
+                    return Ok(unsafe { Ref::new(guard, v) });
+                }
+                TryInsert::Occupied(current, _) => {
+                    self.touch(e);
+                    let current: *const V = current;
+                    return Ok(unsafe { Ref::new(guard, current) });
+                }
+                TryInsert::Locked(_) => return Err(Error::WouldBlock(key)),
+                // Expunged by a promotion since we looked it up.
+                TryInsert::Expunged(v) => value = v,
+            }
+        }
     }
 
-    #[inline(always)]
-    fn load_dirty_locked<'a>(
+    /// Like [`SyncMap::remove`], but returns [`WouldBlock`] instead of
+    /// waiting for a lock. See [`SyncMap::try_load`].
+    pub fn try_remove<Q>(&self, key: &Q) -> Result<Option<Ref<'_, V>>, WouldBlock>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let Some((k, e)) = self.try_find_entry(key, &guard)? else {
+            return Ok(None);
+        };
+        let previous = e
+            .delete_nowait(&guard, &self.collector)
+            .map_err(|()| WouldBlock(()))?;
+        if let Some(previous) = previous {
+            self.removed(k, previous);
+        }
+        let previous = previous.map(ptr::from_ref);
+        Ok(Self::wrap(guard, previous))
+    }
+
+    // Like `find_entry`, but gives up if the lock is held.
+    fn try_find_entry<'g, Q>(
         &self,
-        key: &K,
-        guard: &'a MutexGuard<'a, Option<Map<K, V>>>,
-    ) -> Option<&'a V> {
-        let read_only = self.load_readonly();
-        if let Some(read) = read_only {
-            let present = read.m.contains_key(key);
-            // Check the dirty map.
-            if !present && read.amended {
-                let dirty_map = guard.as_ref().unwrap();
-                let entry = dirty_map.get(key);
-                let res = if let Some(e) = entry { e.load() } else { None };
-                // self.miss_locked(guard);
-                return res;
+        key: &Q,
+        guard: &'g Guard,
+    ) -> Result<Option<(&'g K, &'g Entry<V>)>, WouldBlock>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let (read, found) = self.read_entry(key, guard);
+        if let Some((k, e)) = found {
+            self.counters.read_hit();
+            return Ok(Some((
+                Self::key_ref(k, guard),
+                Self::pinned_entry(e, guard),
+            )));
+        }
+        if !read.amended.load(RELAXED) {
+            self.counters.miss();
+            return Ok(None);
+        }
+
+        let mut dirty = self.dirty.try_lock().ok_or(WouldBlock(()))?;
+        Ok(self.find_entry_locked(key, &mut dirty, guard))
+    }
+
+    /// Returns a snapshot of the map's lookup and promotion counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Stats {
+        let dirty_len = self.dirty.lock().as_ref().map_or(0, |d| d.len());
+        self.counters.snapshot(dirty_len)
+    }
+
+    /// Replaces the value for an existing key with `f(&value)`, retrying until
+    /// no concurrent write got in between, and returns the previous value.
+    ///
+    /// `f` may be called several times under contention and is called without
+    /// any lock held. Returns `None` without calling `f` if the key is absent.
+    pub fn update<Q>(&self, key: &Q, f: impl FnMut(&V) -> V) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let (k, e) = self.find_entry(key, &guard)?;
+        let (previous, value) = e.update(f, &guard, &self.collector)?;
+        self.touch(e);
+        self.updated(k, previous, value);
+        let previous = ptr::from_ref(previous);
+        let res = Self::wrap(guard, Some(previous));
+        self.collector.collect();
+        res
+    }
+
+    /// Sets the value for a key.
+    pub fn store(&self, key: K, value: V) {
+        drop(self.swap(key, value));
+        self.collector.collect();
+    }
+
+    /// Sets the value for a key, unless that would add a key to a dirty map
+    /// already holding as many new keys as
+    /// [`SyncMapBuilder::max_dirty_len`] allows with
+    /// [`DirtyOverflow::Reject`]. The key and value are then handed back, and
+    /// can be stored again once a promotion has emptied the dirty map.
+    ///
+    /// Behaves like [`SyncMap::store`] otherwise.
+    pub fn store_with_limit(&self, key: K, value: V) -> Result<(), Full<(K, V)>> {
+        let res = self
+            .swap_limited(key, value, Some(|k, v| Full((k, v))))
+            .map(drop);
+        self.collector.collect();
+        res
+    }
+
+    /// Sets the value for each key, in order.
+    ///
+    /// Behaves like calling [`SyncMap::store`] for each pair, but the keys
+    /// missing from the read map are all looked up, and the new ones
+    /// inserted, under a single acquisition of the lock.
+    pub fn store_many(&self, pairs: impl IntoIterator<Item = (K, V)>) {
+        let guard = reclaim::pin();
+        let read = self.load_readonly(&guard);
+        let mut missed = Vec::new();
+        for (key, value) in pairs {
+            let Some(e) = read.m.get(Query(&key).as_dyn()) else {
+                missed.push((key, value));
+                continue;
+            };
+            match e.try_swap(value, &guard, &self.collector) {
+                Ok((previous, value)) => {
+                    self.touch(e);
+                    self.stored(&key, previous, value);
+                }
+                Err(value) => missed.push((key, value)),
             }
         }
 
-        None
+        let mut found = Vec::new();
+        let mut inserted = Vec::new();
+        if !missed.is_empty() {
+            let mut dirty = self.dirty.lock();
+            for (key, value) in missed {
+                match self.entry_locked(&key, &mut dirty, &guard, false) {
+                    Some(e) => found.push((key, e, value)),
+                    None => inserted.push(self.insert_value_locked(key, value, &mut dirty, &guard)),
+                }
+            }
+        }
+        for (key, value) in inserted {
+            self.inserted(key, value);
+        }
+
+        // Storing into an entry may wait for its lock holder, so it is done
+        // with the dirty lock released.
+        for (key, e, value) in found {
+            match e.try_swap(value, &guard, &self.collector) {
+                Ok((previous, value)) => {
+                    self.touch(e);
+                    self.stored(&key, previous, value);
+                }
+                // Expunged again by a promotion since we released the lock.
+                Err(value) => self.store(key, value),
+            }
+        }
+        drop(guard);
+
+        self.report_evicted();
+        self.collector.collect();
     }
 
-    // If misses hit the threshold, flip
-    fn miss_locked(&self, mut guard: MutexGuard<'_, Option<Map<K, V>>>) {
-        let num = self.misses.fetch_add(1, Ordering::Release) as usize;
-        if num + 1 < guard.as_ref().unwrap().len() {
+    /// Loads many pairs at once, e.g. to rehydrate a cache at start up.
+    ///
+    /// The pairs are hashed into a new table without holding any lock, which
+    /// is then merged with the map under a single acquisition of the lock and
+    /// published as the read map. Keys that hold a value at that point,
+    /// including ones stored concurrently with the build, keep it: the pairs
+    /// only fill in the keys the map does not hold. Of pairs with the same
+    /// key, the last one is kept.
+    ///
+    /// On a bounded map, or one with callbacks, the pairs are stored one by
+    /// one as by [`SyncMap::load_or_store`] instead, so that they go through
+    /// the eviction policy and the callbacks.
+    pub fn bulk_load(&self, pairs: impl IntoIterator<Item = (K, V)>) {
+        let pairs = pairs.into_iter();
+        if self.bound.is_some() || !self.hooks.is_empty() {
+            let mut m =
+                HashMap::with_capacity_and_hasher(pairs.size_hint().0, self.hash_builder.clone());
+            m.extend(pairs);
+            for (k, v) in m {
+                self.load_or_store(k, v);
+            }
             return;
         }
 
-        let new = Box::into_raw(Box::new(ReadOnly {
-            amended: false,
-            m: guard.take().unwrap(),
-        }));
-        let old = self.read.swap(new, Ordering::Release);
+        let mut m =
+            HashMap::with_capacity_and_hasher(pairs.size_hint().0, self.hash_builder.clone());
+        for (k, v) in pairs {
+            m.insert(Key::new(k), Arc::new(Entry::new(v)));
+        }
 
-        unsafe {
-            let _ = Box::from_raw(old);
+        let guard = reclaim::pin();
+        let mut dirty = self.dirty.lock();
+        let read = self.load_readonly(&guard);
+        // Without a dirty map, the read map holds every key.
+        let current = dirty.as_ref().unwrap_or(&read.m);
+        for (k, e) in current {
+            match e.state(&guard) {
+                EntryState::Present(_) => {
+                    m.insert(k.clone(), e.clone());
+                }
+                // A loaded value may replace a deleted one once no writer
+                // can store into its entry.
+                EntryState::SoftDelete => {
+                    if !m.contains_key(k) || !e.try_expunge_locked() {
+                        m.insert(k.clone(), e.clone());
+                    }
+                }
+                EntryState::HardDelete => {}
+            }
+        }
+
+        // Writers that looked up an entry of the old dirty map may still be
+        // about to use it.
+        if let Some(old) = dirty.replace(m) {
+            unsafe { self.collector.retire(Box::into_raw(Box::new(old))) };
         }
+        self.promote_locked(&mut dirty);
+        self.deleted.store(0, Ordering::Relaxed);
+        drop(dirty);
+        drop(guard);
 
-        *guard = None;
-        self.misses.store(0, Ordering::Release);
+        self.collector.collect();
     }
-}
 
-impl<K, V> Drop for SyncMap<K, V>
-where
-    K: std::cmp::Eq + std::hash::Hash,
-{
-    fn drop(&mut self) {
-        let read_ptr = self.read.load(Ordering::Acquire);
-        if !read_ptr.is_null() {
-            unsafe {
-                let _ = Box::from_raw(read_ptr);
+    /// Swaps the value for a key and returns the previous value if any.
+    pub fn swap(&self, key: K, value: V) -> Option<Ref<'_, V>> {
+        match self.swap_limited::<Infallible>(key, value, None) {
+            Ok(previous) => previous,
+            Err(never) => match never {},
+        }
+    }
+
+    // Swaps in a value, or hands the key and value back through `reject`, if
+    // given, when they would take the dirty map past a limit that rejects
+    // new keys. Without `reject`, the error type can be `Infallible`.
+    fn swap_limited<E>(
+        &self,
+        key: K,
+        value: V,
+        reject: Option<fn(K, V) -> E>,
+    ) -> Result<Option<Ref<'_, V>>, E> {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_swap(value, &guard, &self.collector) {
+                    Ok((previous, value)) => {
+                        self.touch(e);
+                        self.stored(&key, previous, value);
+                        let previous = previous.map(ptr::from_ref);
+                        return Ok(Self::wrap(guard, previous));
+                    }
+                    Err(v) => value = v,
+                }
+            }
+
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard, false) else {
+                if let Some(full) = reject.filter(|_| self.rejects_locked()) {
+                    return Err(full(key, value));
+                }
+                let (key, value) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                drop(dirty);
+                self.inserted(key, value);
+                self.report_evicted();
+                return Ok(None);
+            };
+            drop(dirty);
+            self.report_evicted();
+
+            match e.try_swap(value, &guard, &self.collector) {
+                Ok((previous, value)) => {
+                    self.touch(e);
+                    self.stored(&key, previous, value);
+                    let previous = previous.map(ptr::from_ref);
+                    return Ok(Self::wrap(guard, previous));
+                }
+                // Expunged again by a promotion since we released the lock.
+                Err(v) => value = v,
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns the existing value for the key if present. Otherwise, it stores
+    /// and returns the given value. The boolean result is true if the value was
+    /// loaded, false if stored.
+    pub fn load_or_store(&self, key: K, value: V) -> (Ref<'_, V>, bool) {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_load_or_store(value, &guard) {
+                    Ok((actual, loaded)) => {
+                        self.touch(e);
+                        if !loaded {
+                            self.inserted(&key, actual);
+                        }
+                        let actual: *const V = actual;
+                        return (unsafe { Ref::new(guard, actual) }, loaded);
+                    }
+                    Err(v) => value = v,
+                }
+            }
 
-    #[test]
-    fn load() {}
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard, true) else {
+                let (key, actual) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                drop(dirty);
+                self.inserted(key, actual);
+                self.report_evicted();
+                let actual: *const V = actual;
+                return (unsafe { Ref::new(guard, actual) }, false);
+            };
+            drop(dirty);
+            self.report_evicted();
+
+            match e.try_load_or_store(value, &guard) {
+                Ok((actual, loaded)) => {
+                    self.touch(e);
+                    if !loaded {
+                        self.inserted(&key, actual);
+                    }
+                    let actual: *const V = actual;
+                    return (unsafe { Ref::new(guard, actual) }, loaded);
+                }
+                // Expunged again by a promotion since we released the lock.
+                Err(v) => value = v,
+            }
+        }
+    }
+
+    /// Stores a value if the key is absent. If the key is present, the map is
+    /// left unchanged and the value is handed back along with the current
+    /// one.
+    pub fn try_insert(&self, key: K, value: V) -> Result<(), OccupiedError<'_, V>> {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                match e.try_insert(value, &guard) {
+                    TryInsert::Stored(value) => {
+                        self.touch(e);
+                        self.inserted(&key, value);
+                        return Ok(());
+                    }
+                    TryInsert::Occupied(current, value) => {
+                        self.touch(e);
+                        let current: *const V = current;
+                        let current = unsafe { Ref::new(guard, current) };
+                        return Err(OccupiedError { current, value });
+                    }
+                    TryInsert::Expunged(v) | TryInsert::Locked(v) => value = v,
+                }
+            }
+
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard, true) else {
+                let (key, value) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                drop(dirty);
+                self.inserted(key, value);
+                self.report_evicted();
+                return Ok(());
+            };
+            drop(dirty);
+            self.report_evicted();
+
+            match e.try_insert(value, &guard) {
+                TryInsert::Stored(value) => {
+                    self.touch(e);
+                    self.inserted(&key, value);
+                    return Ok(());
+                }
+                TryInsert::Occupied(current, value) => {
+                    self.touch(e);
+                    let current: *const V = current;
+                    let current = unsafe { Ref::new(guard, current) };
+                    return Err(OccupiedError { current, value });
+                }
+                // Expunged again by a promotion since we released the lock.
+                TryInsert::Expunged(v) | TryInsert::Locked(v) => value = v,
+            }
+        }
+    }
+
+    /// Stores `insert()` if the key is absent, or replaces the value with
+    /// `modify(&value)` if it is present, as a single atomic operation.
+    /// Returns the value now stored.
+    ///
+    /// Neither closure is called with a lock held. `modify` may be called
+    /// several times under contention, and `insert` may be called even if the
+    /// key turns out to be present, in which case its result is dropped.
+    pub fn upsert(
+        &self,
+        key: K,
+        insert: impl FnOnce() -> V,
+        mut modify: impl FnMut(&V) -> V,
+    ) -> Ref<'_, V> {
+        let guard = reclaim::pin();
+        let mut insert = Some(insert);
+        let mut make = || (insert.take().unwrap())();
+        let mut pending = None;
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(&key).as_dyn()) {
+                if let Ok((previous, v)) = e.try_upsert(
+                    &mut pending,
+                    &mut make,
+                    &mut modify,
+                    &guard,
+                    &self.collector,
+                ) {
+                    self.touch(e);
+                    self.stored(&key, previous, v);
+                    let v: *const V = v;
+                    let res = unsafe { Ref::new(guard, v) };
+                    self.collector.collect();
+                    return res;
+                }
+            }
+
+            // The value must be ready before we take the lock, so that no
+            // closure runs with it held.
+            if pending.is_none() {
+                pending = Some(make());
+            }
+
+            let mut dirty = self.dirty.lock();
+            let Some(e) = self.entry_locked(&key, &mut dirty, &guard, true) else {
+                let value = pending.take().unwrap();
+                let (key, v) = self.insert_value_locked(key, value, &mut dirty, &guard);
+                drop(dirty);
+                self.inserted(key, v);
+                self.report_evicted();
+                let v: *const V = v;
+                return unsafe { Ref::new(guard, v) };
+            };
+            drop(dirty);
+            self.report_evicted();
+
+            if let Ok((previous, v)) = e.try_upsert(
+                &mut pending,
+                &mut make,
+                &mut modify,
+                &guard,
+                &self.collector,
+            ) {
+                self.touch(e);
+                self.stored(&key, previous, v);
+                let v: *const V = v;
+                let res = unsafe { Ref::new(guard, v) };
+                self.collector.collect();
+                return res;
+            }
+            // Expunged again by a promotion since we released the lock.
+        }
+    }
+
+    /// Deletes the value for a key, returning the previous value if any.
+    ///
+    /// The entry itself stays in the dirty map, soft deleted, until the next
+    /// promotion gets it expunged: it may be locked by a [`MapEntry`] that is
+    /// about to store into it.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let (k, e) = self.find_entry(key, &guard)?;
+        let previous = e.delete(&guard, &self.collector);
+        if let Some(previous) = previous {
+            self.removed(k, previous);
+        }
+        let previous = previous.map(ptr::from_ref);
+        let res = Self::wrap(guard, previous);
+        self.collector.collect();
+        res
+    }
+
+    /// Deletes the value for a key only if it is still `old`, compared by
+    /// identity. Returns whether it was deleted.
+    pub(crate) fn remove_same<Q>(&self, key: &Q, old: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let deleted = self.find_entry(key, &guard).is_some_and(|(k, e)| {
+            let deleted = e.delete_if_same(old, &guard, &self.collector);
+            if deleted {
+                self.removed(k, old);
+            }
+            deleted
+        });
+        drop(guard);
+
+        self.collector.collect();
+        deleted
+    }
+
+    /// Returns the value for a key, storing the result of `f` first if the key
+    /// is absent. If `f` fails, the error is returned and nothing is stored.
+    ///
+    /// `f` is called with the key's entry locked, so callers racing on the
+    /// same absent key wait for the first one instead of each calling `f`:
+    /// it is called at most once until one call succeeds. Loads are never
+    /// blocked, and see the key absent until then.
+    pub fn get_or_try_insert_with<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Ref<'_, V>, E> {
+        if let Some(v) = self.load(&key) {
+            return Ok(v);
+        }
+        self.entry(key).or_try_insert_with(f)
+    }
+
+    /// Gets the given key's entry for in-place manipulation.
+    ///
+    /// The entry is locked against other writers until the returned
+    /// [`MapEntry`] is dropped, so inspecting and then writing it is atomic.
+    /// Readers are never blocked. Writing the same key through the map while
+    /// holding its entry deadlocks.
+    pub fn entry(&self, key: K) -> MapEntry<'_, K, V, S> {
+        let key = Key::new(key);
+        let guard = reclaim::pin();
+        loop {
+            let read = self.load_readonly(&guard);
+            if let Some(e) = read.m.get(Query(key.get()).as_dyn()) {
+                let e = Self::pinned_entry(e, &guard);
+                if e.lock() {
+                    return MapEntry::new(self, key, ptr::from_ref(e), guard);
+                }
+            }
+
+            let mut dirty = self.dirty.lock();
+            let e = match self.entry_locked(key.get(), &mut dirty, &guard, true) {
+                Some(e) => e,
+                None => {
+                    let e = Arc::new(Entry::new_deleted());
+                    let r = Self::pinned_entry(&e, &guard);
+                    self.insert_locked(key.clone(), e, &mut dirty, &guard);
+                    r
+                }
+            };
+            drop(dirty);
+            self.report_evicted();
+
+            if e.lock() {
+                return MapEntry::new(self, key, ptr::from_ref(e), guard);
+            }
+            // Expunged again by a promotion since we released the lock.
+        }
+    }
+
+    /// Locks the entry for a key, whether or not it holds a value, until the
+    /// returned guard is dropped, e.g. to keep a key stable across several
+    /// steps of external work before settling its value.
+    ///
+    /// Unlike a [`MapEntry`], the guard can store and remove values any
+    /// number of times without giving up the lock. The lock is the entry's
+    /// own, as for [`SyncMap::entry`]: other keys, and readers of this one,
+    /// are not blocked, and writing the same key through the map while
+    /// holding the guard deadlocks.
+    ///
+    /// ```
+    /// use sync_map::map::SyncMap;
+    ///
+    /// let map = SyncMap::new();
+    /// let mut guard = map.lock_entry("socket");
+    /// guard.store("opening");
+    /// // ... register with the OS ...
+    /// guard.store("ready");
+    /// drop(guard);
+    /// assert_eq!(*map.load("socket").unwrap(), "ready");
+    /// ```
+    pub fn lock_entry(&self, key: K) -> EntryGuard<'_, K, V, S> {
+        let (key, lock) = match self.entry(key) {
+            MapEntry::Occupied(e) => (e.key, e.lock),
+            MapEntry::Vacant(e) => (e.key, e.lock),
+        };
+        EntryGuard { key, lock }
+    }
+
+    /// Returns a handle to the entry for a key, which can be kept to load and
+    /// write the key any number of times without hashing it again.
+    ///
+    /// The handle does not lock the key, and writes through it behave as
+    /// writes through the map. Once the key is removed, its entry may be
+    /// dropped from the map; the handle then falls back to looking the key
+    /// up, and its next write moves it to the key's new entry.
+    ///
+    /// The same holds when the entry is replaced or dropped in bulk, by
+    /// [`SyncMap::transaction`], [`SyncMap::remove_all`] or an eviction: the
+    /// map expunges every entry it drops, so the handle never reads a stale
+    /// value from it or writes where the map cannot see. A write racing with
+    /// such a call is ordered before it.
+    ///
+    /// ```
+    /// use sync_map::map::SyncMap;
+    ///
+    /// let map = SyncMap::new();
+    /// let mut hits = map.entry_ref("/index.html");
+    /// for i in 1..=3 {
+    ///     hits.store(i);
+    /// }
+    /// assert_eq!(*hits.load().unwrap(), 3);
+    /// assert_eq!(*map.load("/index.html").unwrap(), 3);
+    /// ```
+    pub fn entry_ref(&self, key: K) -> EntryRef<'_, K, V, S> {
+        let key = Key::new(key);
+        let entry = self.resolve(&key);
+        EntryRef {
+            map: self,
+            key,
+            entry,
+        }
+    }
+
+    // Returns the entry for a key, adding a deleted one if the key has none.
+    // The entry may be expunged again by the time it is used.
+    fn resolve(&self, key: &Key<K>) -> Arc<Entry<V>> {
+        let guard = reclaim::pin();
+        let read = self.load_readonly(&guard);
+        if let Some(e) = read.m.get(Query(key.get()).as_dyn()) {
+            if !matches!(e.state(&guard), EntryState::HardDelete) {
+                return e.clone();
+            }
+        }
+
+        let mut dirty = self.dirty.lock();
+        let e = match self.entry_locked(key.get(), &mut dirty, &guard, true) {
+            // Found in either map, and in the dirty map if there is one.
+            Some(_) => dirty
+                .as_ref()
+                .and_then(|d| d.get(Query(key.get()).as_dyn()))
+                .or_else(|| self.load_readonly(&guard).m.get(Query(key.get()).as_dyn()))
+                .unwrap()
+                .clone(),
+            None => {
+                let e = Arc::new(Entry::new_deleted());
+                self.insert_locked(key.clone(), e.clone(), &mut dirty, &guard);
+                e
+            }
+        };
+        drop(dirty);
+        self.report_evicted();
+        e
+    }
+
+    /// Locks the entry for a key and returns a guard through which its value
+    /// can be changed, or `None` if the key holds no value.
+    ///
+    /// Readers are never blocked and may hold the current value, so it cannot
+    /// be changed in place: the guard clones it on the first mutable access,
+    /// and the copy replaces the value when the guard is dropped. Until then
+    /// loads return the previous value, and other writers to the key wait.
+    /// If the thread panics while holding the guard, the copy is discarded.
+    /// Writing the same key through the map while holding the guard
+    /// deadlocks.
+    ///
+    /// ```
+    /// use sync_map::map::SyncMap;
+    ///
+    /// let map = SyncMap::new();
+    /// map.store("log", vec![1, 2]);
+    /// if let Some(mut log) = map.get_mut("log") {
+    ///     log.push(3);
+    ///     log.retain(|&x| x != 1);
+    /// }
+    /// assert_eq!(*map.load("log").unwrap(), [2, 3]);
+    /// ```
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+        V: Clone,
+    {
+        let guard = reclaim::pin();
+        let (k, e) = self.find_entry(key, &guard)?;
+        if !e.lock() {
+            // Expunged, so the key holds no value. A store would have to
+            // unexpunge the entry first.
+            return None;
+        }
+        let (k, e) = (ptr::from_ref(k), ptr::from_ref(e));
+        let lock = EntryLock {
+            map: self,
+            entry: unsafe { &*e },
+            guard,
+        };
+        lock.get()?;
+        Some(RefMut {
+            key: unsafe { &*k },
+            lock,
+            value: None,
+        })
+    }
+
+    /// Gets the entries of several keys at once, e.g. to move an amount
+    /// between two accounts atomically.
+    ///
+    /// Every entry is locked, as by [`SyncMap::entry`], before any is
+    /// returned. Entries are locked in a fixed order, so concurrent calls
+    /// with overlapping keys cannot deadlock each other; holding another
+    /// entry of the map while calling this still can.
+    ///
+    /// ```
+    /// use sync_map::map::{MapEntry, SyncMap};
+    ///
+    /// let map = SyncMap::new();
+    /// map.store("alice", 100);
+    /// map.store("bob", 0);
+    /// if let [MapEntry::Occupied(mut from), MapEntry::Occupied(mut to)] =
+    ///     map.entry_many(["alice", "bob"])
+    /// {
+    ///     let (a, b) = (*from.get(), *to.get());
+    ///     from.insert(a - 30);
+    ///     to.insert(b + 30);
+    /// }
+    /// assert_eq!(*map.load("bob").unwrap(), 30);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the same key is given twice, or if the map is bounded to
+    /// fewer than `N` keys, as adding the last key would evict another.
+    pub fn entry_many<const N: usize>(&self, keys: [K; N]) -> [MapEntry<'_, K, V, S>; N] {
+        if let Some(bound) = &self.bound {
+            assert!(bound.max_entries >= N, "more keys than the map can hold");
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[..i].contains(key), "duplicate key in entry_many");
+        }
+        let keys = keys.map(Key::new);
+        let guard = reclaim::pin();
+        loop {
+            let entries = self.entries_for(&keys, &guard);
+
+            // Entries never move, so their addresses order them for every
+            // caller.
+            let mut order: [usize; N] = std::array::from_fn(|i| i);
+            order.sort_unstable_by_key(|&i| ptr::from_ref(entries[i]));
+            let locked = order.iter().take_while(|&&i| entries[i].lock()).count();
+            if locked == N {
+                let mut entries = entries.into_iter();
+                return keys
+                    .map(|key| MapEntry::new(self, key, entries.next().unwrap(), reclaim::pin()));
+            }
+
+            // One was expunged since we looked it up, and is unexpunged by
+            // the next lookup.
+            for &i in &order[..locked] {
+                entries[i].unlock();
+            }
+        }
+    }
+
+    // Looks up the entries for keys, adding deleted ones for the missing keys
+    // and unexpunging the expunged ones under a single acquisition of the
+    // lock.
+    fn entries_for<'g, const N: usize>(
+        &self,
+        keys: &[Key<K>; N],
+        guard: &'g Guard,
+    ) -> [&'g Entry<V>; N] {
+        let read = self.load_readonly(guard);
+        let mut entries = keys.each_ref().map(|key| {
+            read.m
+                .get(Query(key.get()).as_dyn())
+                .map(|e| Self::pinned_entry(e, guard))
+                .filter(|e| !matches!(e.state(guard), EntryState::HardDelete))
+        });
+        if entries.iter().any(Option::is_none) {
+            let mut dirty = self.dirty.lock();
+            for (key, e) in keys.iter().zip(&mut entries) {
+                if e.is_some() {
+                    continue;
+                }
+                *e = Some(
+                    match self.entry_locked(key.get(), &mut dirty, guard, true) {
+                        Some(e) => e,
+                        None => {
+                            let new = Arc::new(Entry::new_deleted());
+                            let r = Self::pinned_entry(&new, guard);
+                            self.insert_locked(key.clone(), new, &mut dirty, guard);
+                            r
+                        }
+                    },
+                );
+            }
+            drop(dirty);
+            self.report_evicted();
+        }
+        entries.map(Option::unwrap)
+    }
+
+    /// Applies the writes `f` makes to a [`Transaction`] at once: readers see
+    /// either none or all of them, e.g. for a configuration update spanning
+    /// several keys.
+    ///
+    /// The writes are applied in order to the dirty map, under a single
+    /// acquisition of the lock, which is then promoted to the read map.
+    /// The keys written get new entries, leaving the values in the previous
+    /// read map untouched for readers still using it. A concurrent write to
+    /// one of the keys is ordered before the transaction, and overwritten by
+    /// it. Nothing is applied if `f` panics.
+    ///
+    /// Committing takes time linear in the size of the map if there is no
+    /// dirty map yet, as creating one does.
+    ///
+    /// ```
+    /// use sync_map::map::SyncMap;
+    ///
+    /// let map = SyncMap::new();
+    /// map.store("host", "a");
+    /// map.transaction(|txn| {
+    ///     txn.store("host", "b");
+    ///     txn.store("port", "80");
+    ///     txn.remove("debug");
+    /// });
+    /// assert_eq!(*map.load("port").unwrap(), "80");
+    /// ```
+    pub fn transaction<R>(&self, f: impl FnOnce(&mut Transaction<K, V>) -> R) -> R {
+        let mut txn = Transaction { writes: Vec::new() };
+        let res = f(&mut txn);
+        if txn.writes.is_empty() {
+            return res;
+        }
+
+        let guard = reclaim::pin();
+        let mut changes = Vec::with_capacity(txn.writes.len());
+        let mut removed_keys = Vec::new();
+        let mut dirty = self.dirty.lock();
+        self.dirty_locked(&mut dirty, &guard);
+        let d = dirty.as_mut().unwrap();
+        for (key, value) in txn.writes {
+            let change = match value {
+                Some(value) => {
+                    let e = Arc::new(Entry::new(value));
+                    let value = Self::pinned_entry(&e, &guard).load(&guard);
+                    match d.entry(Key::new(key)) {
+                        hash_map::Entry::Occupied(mut o) => {
+                            (Self::key_ref(o.key(), &guard), Some(o.insert(e)), value)
+                        }
+                        hash_map::Entry::Vacant(v) => {
+                            let k = Self::key_ref(v.key(), &guard);
+                            self.added_locked(k, &e);
+                            v.insert(e);
+                            (k, None, value)
+                        }
+                    }
+                }
+                None => match d.remove_entry(Query(&key).as_dyn()) {
+                    Some((k, old)) => {
+                        let r = Self::key_ref(&k, &guard);
+                        removed_keys.push(k);
+                        (r, Some(old), None)
+                    }
+                    None => continue,
+                },
+            };
+            changes.push(change);
+        }
+        self.evict_locked(d);
+        self.promote_locked(&mut dirty);
+        drop(dirty);
+
+        // Writers that looked up a replaced or removed entry before the lock
+        // was taken may still be about to use it. Expunging it sends them to
+        // the lock, where they find the key's new entry, and makes a write
+        // that got in first the previous value. It waits for the entry's lock
+        // holder, so is done with the lock released.
+        let changes: Vec<_> = changes
+            .into_iter()
+            .map(|(k, old, value)| {
+                let previous = old.and_then(|old| {
+                    let v = old.expunge(&guard, &self.collector);
+                    unsafe { self.collector.retire(Box::into_raw(Box::new(old))) };
+                    v
+                });
+                (k, previous, value)
+            })
+            .collect();
+        if !removed_keys.is_empty() {
+            unsafe { self.collector.retire(Box::into_raw(Box::new(removed_keys))) };
+        }
+
+        for (k, previous, value) in changes {
+            match (previous, value) {
+                (previous, Some(value)) => self.stored(k, previous, value),
+                (Some(previous), None) => self.report_removed(k, previous),
+                (None, None) => {}
+            }
+        }
+        self.report_evicted();
+        drop(guard);
+
+        self.collector.collect();
+        res
+    }
+
+    /// Calls `f` sequentially for each key and value present in the map.
+    /// If `f` returns false, range stops the iteration.
+    ///
+    /// Range does not necessarily correspond to any consistent snapshot of the
+    /// map's contents: no key will be visited more than once, but if the value
+    /// for any key is stored or deleted concurrently (including by `f`), range
+    /// may reflect any mapping for that key from any point during the range
+    /// call.
+    pub fn range(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        let guard = reclaim::pin();
+        let read = self.load_promoted(&guard);
+        for (k, e) in read.m.iter() {
+            if let Some(v) = e.load(&guard) {
+                if !f(k.get(), v) {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Retains only the entries for which `f` returns true, deleting the rest.
+    ///
+    /// Like [`SyncMap::range`], retain visits every key present at the start
+    /// of the call at most once, and `f` is called without any lock held. An
+    /// entry is only deleted if it still holds the value `f` rejected, so a
+    /// concurrent store is never lost.
+    pub fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        let guard = reclaim::pin();
+        let read = self.load_promoted(&guard);
+        for (k, e) in read.m.iter() {
+            if let Some(v) = e.load(&guard) {
+                if !f(k.get(), v) && e.delete_if_same(v, &guard, &self.collector) {
+                    self.removed(k.get(), v);
+                }
+            }
+        }
+        drop(guard);
+
+        self.collector.collect();
+    }
+
+    /// Returns an iterator over the keys and values present in the map.
+    ///
+    /// Like [`SyncMap::range`], the iterator visits every key present at the
+    /// time of the call at most once, but may observe concurrent writes.
+    pub fn iter(&self) -> Iter<'_, K, V, S> {
+        let guard = reclaim::pin();
+        let read: *const ReadOnly<K, V, S> = self.load_promoted(&guard);
+        Iter {
+            // The read map is kept alive by `guard`, which moves along with
+            // the iterator.
+            inner: unsafe { (*read).m.iter() },
+            _guard: guard,
+            _map: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the keys present in the map at the time of
+    /// the call, with their values, that can be held for as long as needed.
+    ///
+    /// Like [`SyncMap::iter`], but the iterator keeps the read map of the
+    /// time of the call alive rather than staying pinned, so a long iteration
+    /// does not hold back the reclamation of replaced values, and promotions
+    /// in the meantime do not change the keys it visits: every key present
+    /// for the whole iteration is yielded exactly once, and keys added after
+    /// the call are not. A value is the one held when it is yielded.
+    pub fn snapshot_iter(&self) -> SnapshotIter<'_, K, V, S> {
+        let guard = reclaim::pin();
+        let read: *const ReadOnly<K, V, S> = self.load_promoted(&guard);
+        let read = unsafe {
+            // The read map cannot have been freed while pinned.
+            Arc::increment_strong_count(read);
+            Arc::from_raw(read)
+        };
+        SnapshotIter {
+            // The read map is kept alive by `read`, which outlives it.
+            inner: unsafe { (*Arc::as_ptr(&read)).m.iter() },
+            read: Some(read),
+            map: self,
+        }
+    }
+
+    /// Removes every entry from the map, returning an iterator over the keys
+    /// and values that were present.
+    ///
+    /// The map is emptied at once, so entries stored after the call are left
+    /// in it. Unlike [`SyncMap::drain`], this does not hand over the values:
+    /// they stay shared, returned the way [`SyncMap::remove`] returns them,
+    /// since a concurrent reader may still hold them. Dropping the iterator
+    /// before it is exhausted still removes the remaining entries.
+    pub fn remove_all(&self) -> RemoveAll<'_, K, V, S> {
+        let guard = reclaim::pin();
+        let read = {
+            let mut dirty = self.dirty.lock();
+            if dirty.is_some() {
+                self.promote_locked(&mut dirty);
+            }
+            let empty = HashMap::with_hasher(self.hash_builder.clone());
+            let new = Self::new_readonly(empty);
+            self.misses.store(0, RELAXED);
+            self.read.swap(new, RELEASE)
+        };
+        RemoveAll {
+            map: self,
+            read,
+            // The unlinked read map is only retired when the iterator is
+            // dropped.
+            inner: unsafe { (*read).m.iter() },
+            guard,
+        }
+    }
+
+    /// Removes every entry from the map, returning an iterator over the keys
+    /// and values that were present, e.g. to flush a buffer of writes.
+    ///
+    /// Like [`SyncMap::remove_all`], but yields owned values. A concurrent
+    /// reader may still hold a removed value, so each one is cloned, as
+    /// [`DashMap::remove`](crate::compat::DashMap::remove) does.
+    pub fn drain(&self) -> Drain<'_, K, V, S>
+    where
+        V: Clone,
+    {
+        Drain {
+            inner: self.remove_all(),
+        }
+    }
+
+    /// Returns a handle to run the map's upkeep in bounded steps, from
+    /// wherever suits the application, e.g. a timer task or every so many
+    /// writes. The map never spawns threads of its own.
+    ///
+    /// Each [`Maintenance::run_pending`] frees the replaced and removed values
+    /// no reader can still see, and for a map with stale values, such as a
+    /// [`SyncWeakMap`], removes those among the next batch of keys.
+    ///
+    /// [`SyncWeakMap`]: crate::weak::SyncWeakMap
+    pub fn maintenance(&self) -> Maintenance<'_> {
+        let stale = self.stale.map(|stale| Box::new(stale) as Stale<V>);
+        Maintenance::new(Box::new(Sweep {
+            map: self,
+            stale,
+            cursor: None,
+        }))
+    }
+
+    // Like `maintenance`, but removes the values for which `stale` returns
+    // true.
+    pub(crate) fn maintenance_with<'a>(
+        &'a self,
+        stale: impl Fn(&V) -> bool + 'a,
+    ) -> Maintenance<'a> {
+        Maintenance::new(Box::new(Sweep {
+            map: self,
+            stale: Some(Box::new(stale)),
+            cursor: None,
+        }))
+    }
+
+    // Loads the read map after promoting the dirty map if needed, so that it
+    // holds every key that was present at the time of the call.
+    fn load_promoted<'g>(&self, guard: &'g Guard) -> &'g ReadOnly<K, V, S> {
+        // If read.amended is false, then read.m satisfies that property without
+        // requiring us to hold the lock for a long time.
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            return read;
+        }
+
+        let mut dirty = self.dirty.lock();
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            return read;
+        }
+        self.promote_locked(&mut dirty);
+        self.load_readonly(guard)
+    }
+
+    fn wrap<'a>(guard: Guard, value: Option<*const V>) -> Option<Ref<'a, V>> {
+        Some(unsafe { Ref::new(guard, value?) })
+    }
+
+    // If the promotion policy says misses hit the threshold, flip
+    fn miss_locked(&self, dirty: &mut Option<Map<K, V, S>>) {
+        let misses = self.misses.fetch_add(1, RELAXED) + 1;
+        let dirty_len = dirty.as_ref().map_or(0, |d| d.len());
+        if !self.policy.should_promote(misses, dirty_len) {
+            return;
+        }
+
+        self.promote_locked(dirty);
+    }
+
+    // Promotes the dirty map to be the new read map.
+    fn promote_locked(&self, dirty: &mut Option<Map<K, V, S>>) {
+        let m = dirty
+            .take()
+            .unwrap_or_else(|| HashMap::with_hasher(self.hash_builder.clone()));
+        let new = Self::new_readonly(m);
+        let old = self.read.swap(new, RELEASE);
+
+        unsafe { ReadOnly::retire(old, &self.collector) };
+
+        self.misses.store(0, RELAXED);
+        self.pending.store(0, RELAXED);
+        self.counters.promotion();
+    }
+
+    // Looks up the entry for a key with the lock held, unexpunging it into
+    // the dirty map if needed. Optionally records a miss if the entry was only
+    // found in the dirty map.
+    fn entry_locked<'g>(
+        &self,
+        key: &K,
+        dirty: &mut Option<Map<K, V, S>>,
+        guard: &'g Guard,
+        miss: bool,
+    ) -> Option<&'g Entry<V>> {
+        let read = self.load_readonly(guard);
+        if let Some((k, e)) = read.m.get_key_value(Query(key).as_dyn()) {
+            if e.unexpunge_locked() {
+                // The entry was previously expunged, which implies that there is a
+                // non-nil dirty map and this entry is not in it.
+                let d = dirty.as_mut().unwrap();
+                self.added_locked(key, e);
+                d.insert(k.clone(), e.clone());
+                self.evict_locked(d);
+            }
+            return Some(Self::pinned_entry(e, guard));
+        }
+
+        let e = dirty
+            .as_ref()?
+            .get(Query(key).as_dyn())
+            .map(|e| Self::pinned_entry(e, guard))?;
+        if miss {
+            self.miss_locked(dirty);
+        }
+        Some(e)
+    }
+
+    // Adds an entry for a key missing from both maps, returning the key.
+    fn insert_locked<'g>(
+        &self,
+        key: Key<K>,
+        e: Arc<Entry<V>>,
+        dirty: &mut Option<Map<K, V, S>>,
+        guard: &'g Guard,
+    ) -> &'g K {
+        let k = Self::key_ref(&key, guard);
+        if self.dirty_full_locked() {
+            self.promote_locked(dirty);
+        }
+        self.pending.fetch_add(1, RELAXED);
+        let read = self.load_readonly(guard);
+        if !read.amended.load(RELAXED) {
+            // We're adding the first new key to the dirty map.
+            // Make sure it is allocated and mark the read-only map as incomplete.
+            self.dirty_locked(dirty, guard);
+            read.amended.store(true, RELAXED);
+        }
+        let d = dirty.as_mut().unwrap();
+        self.added_locked(key.get(), &e);
+        d.insert(key, e);
+        self.evict_locked(d);
+        k
+    }
+
+    // Returns whether a new key would go past a limit that rejects it.
+    fn rejects_locked(&self) -> bool {
+        matches!(self.dirty_limit, Some((_, DirtyOverflow::Reject))) && self.dirty_full_locked()
+    }
+
+    // Returns whether the dirty map holds as many new keys as it may.
+    fn dirty_full_locked(&self) -> bool {
+        self.dirty_limit
+            .is_some_and(|(max_len, _)| self.pending.load(RELAXED) >= max_len)
+    }
+
+    // Adds a value for a key missing from both maps, returning both.
+    fn insert_value_locked<'g>(
+        &self,
+        key: K,
+        value: V,
+        dirty: &mut Option<Map<K, V, S>>,
+        guard: &'g Guard,
+    ) -> (&'g K, &'g V) {
+        let e = Arc::new(Entry::new(value));
+        let value = Self::pinned_entry(&e, guard).load(guard).unwrap();
+        (self.insert_locked(Key::new(key), e, dirty, guard), value)
+    }
+
+    // Records an access to an entry, if the map is bounded.
+    #[inline]
+    fn touch(&self, e: &Entry<V>) {
+        if let Some(bound) = &self.bound {
+            e.touch(bound.clock.load(Ordering::Relaxed));
+        }
+    }
+
+    // Tells the eviction policy, if any, about a key about to be added to the
+    // dirty map.
+    fn added_locked(&self, key: &K, e: &Entry<V>) {
+        if let Some(bound) = &self.bound {
+            e.touch(bound.clock.fetch_add(1, Ordering::Relaxed) + 1);
+            bound.policy.inserted(key);
+        }
+    }
+
+    // Evicts entries until the dirty map is back within the bound, if any.
+    fn evict_locked(&self, dirty: &mut Map<K, V, S>) {
+        let Some(bound) = &self.bound else {
+            return;
+        };
+
+        // Give up after as many victims as there are entries, in case the
+        // policy keeps picking locked ones.
+        let mut attempts = dirty.len();
+        while (dirty.len() > bound.max_entries
+            || self.weight.load(Ordering::Relaxed) > bound.max_weight)
+            && attempts > 0
+        {
+            attempts -= 1;
+            let victim = {
+                let accessed = |k: &K| dirty.get(Query(k).as_dyn()).map(|e| e.accessed());
+                bound
+                    .policy
+                    .victim(&Candidates::new(&accessed, dirty.len()))
+            };
+            let Some(victim) = victim else {
+                break;
+            };
+            let Some(e) = dirty.get(Query(&victim).as_dyn()) else {
+                continue;
+            };
+            let Ok(value) = e.try_evict_locked() else {
+                bound.policy.inserted(&victim);
+                continue;
+            };
+
+            // A writer that looked the entry up with the lock held may still
+            // be about to use it, and a caller may still hold its key.
+            let (k, e) = dirty.remove_entry(Query(&victim).as_dyn()).unwrap();
+            if let Some(value) = value {
+                self.unweigh(k.get(), value.get());
+                if self.hooks.on_evict.is_some() {
+                    // Reported by `report_evicted` once the lock is released.
+                    self.evicted.lock().push((k.clone(), value));
+                } else {
+                    value.retire(&self.collector);
+                }
+            }
+            unsafe { self.collector.retire(Box::into_raw(Box::new((k, e)))) };
+        }
+    }
+
+    // Runs the eviction callback for the values evicted since the last call.
+    // Called with the dirty lock released.
+    fn report_evicted(&self) {
+        if self.hooks.on_evict.is_none() {
+            return;
+        }
+        let evicted = std::mem::take(&mut *self.evicted.lock());
+        self.report_evicted_values(evicted);
+    }
+
+    // Adds a value stored for a key to the total weight, and evicts entries
+    // if that takes the map over its bound. Called with the lock released.
+    fn weigh(&self, key: &K, value: &V) {
+        let Some(weigher) = self.hooks.weigher else {
+            return;
+        };
+        let weight = weigher(key, value);
+        let total = self.weight.fetch_add(weight, Ordering::Relaxed) + weight;
+        let Some(bound) = &self.bound else {
+            return;
+        };
+        if total > bound.max_weight {
+            let guard = reclaim::pin();
+            let mut dirty = self.dirty.lock();
+            self.dirty_locked(&mut dirty, &guard);
+            self.evict_locked(dirty.as_mut().unwrap());
+            drop(dirty);
+            self.report_evicted();
+        }
+    }
+
+    // Reports a value stored for a key that held none.
+    fn inserted(&self, key: &K, value: &V) {
+        self.hooks.inserted(key, value);
+        self.weigh(key, value);
+    }
+
+    // Reports a value replaced by another.
+    fn updated(&self, key: &K, previous: &V, value: &V) {
+        self.hooks.updated(key, previous, value);
+        self.unweigh(key, previous);
+        self.weigh(key, value);
+    }
+
+    // Reports a store, which is an update if it replaced a value.
+    fn stored(&self, key: &K, previous: Option<&V>, value: &V) {
+        match previous {
+            Some(previous) => self.updated(key, previous, value),
+            None => self.inserted(key, value),
+        }
+    }
+
+    // Reports a value deleted from an entry that stays in the map, and
+    // compacts the map if enough of them have piled up.
+    fn removed(&self, key: &K, value: &V) {
+        self.report_removed(key, value);
+        let Some(ratio) = self.compaction else {
+            return;
+        };
+        let deleted = self.deleted.fetch_add(1, Ordering::Relaxed) + 1;
+        let guard = reclaim::pin();
+        let len = self.load_readonly(&guard).m.len();
+        if deleted as f64 >= ratio * len as f64 {
+            self.shrink_to_fit();
+        }
+    }
+
+    /// Drops the entries of deleted keys from the map and shrinks its tables
+    /// as much as possible.
+    ///
+    /// Deleted entries are normally only dropped when a new key next makes
+    /// the map copy its read map, so a map that deletes many keys without
+    /// adding new ones keeps them, and its tables never shrink. This promotes
+    /// the dirty map, which is a copy of the read map if there was none.
+    /// Capacity requested with [`SyncMap::reserve`] is given up.
+    pub fn shrink_to_fit(&self) {
+        let guard = reclaim::pin();
+        let mut dirty = self.dirty.lock();
+        self.dirty_locked(&mut dirty, &guard);
+        let d = dirty.as_mut().unwrap();
+
+        // Writers that looked them up before the lock was taken may still be
+        // about to use them.
+        let mut removed = Vec::new();
+        d.retain(|k, e| {
+            let expunged = e.try_expunge_locked();
+            if expunged {
+                removed.push((k.clone(), e.clone()));
+            }
+            !expunged
+        });
+        if !removed.is_empty() {
+            unsafe { self.collector.retire(Box::into_raw(Box::new(removed))) };
+        }
+        d.shrink_to_fit();
+        self.capacity.store(0, Ordering::Relaxed);
+        self.deleted.store(0, Ordering::Relaxed);
+        self.promote_locked(&mut dirty);
+        drop(dirty);
+        drop(guard);
+
+        self.collector.collect();
+    }
+
+    fn dirty_locked(&self, dirty: &mut Option<Map<K, V, S>>, guard: &Guard) {
+        if dirty.is_some() {
+            return;
+        }
+
+        let read = self.load_readonly(guard);
+        let capacity = read.m.len().max(self.capacity.load(Ordering::Relaxed));
+        let mut m = HashMap::with_capacity_and_hasher(capacity, self.hash_builder.clone());
+        // Copy every entry before expunging any, so that a panicking `Hash`
+        // leaves no entry expunged without a dirty map to unexpunge it into.
+        m.extend(read.m.iter().map(|(k, e)| (k.clone(), e.clone())));
+        m.retain(|_, e| match self.stale {
+            Some(stale) => !e.try_prune_locked(stale, guard, &self.collector),
+            None => !e.try_expunge_locked(),
+        });
+        *dirty = Some(m);
+        // The deleted entries are gone once this map is promoted.
+        self.deleted.store(0, Ordering::Relaxed);
+    }
+
+    fn new_readonly(m: Map<K, V, S>) -> *mut ReadOnly<K, V, S> {
+        Arc::into_raw(Arc::new(ReadOnly::new(m))).cast_mut()
+    }
+
+    // Folds the dirty map into the read map and returns it, dropping deleted
+    // entries. Exclusive access leaves no reader, lock holder, scan or
+    // retired read map behind, so the read map and every entry in it are
+    // uniquely owned.
+    fn get_mut_map(&mut self) -> &mut Map<K, V, S> {
+        self.collector.flush();
+        let read = unsafe { &mut **self.read.get_mut() };
+        if let Some(m) = self.dirty.get_mut().take() {
+            *read = ReadOnly::new(m);
+        }
+        *self.misses.get_mut() = 0;
+
+        read.m.retain(|_, e| Self::entry_mut(e).get_mut().is_some());
+        &mut read.m
+    }
+
+    // Whether the key made it into the read map.
+    #[cfg(test)]
+    pub(crate) fn is_promoted<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        self.load_readonly(&guard)
+            .m
+            .contains_key(Query(key).as_dyn())
+    }
+
+    fn entry_mut(e: &mut Arc<Entry<V>>) -> &mut Entry<V> {
+        Arc::get_mut(e).expect("entry shared despite exclusive access")
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher + Clone + Sync,
+{
+    /// Calls `f` for each key and value present in the map, spreading the
+    /// entries over one scoped thread per available core.
+    ///
+    /// Like [`SyncMap::range`], every key present at the time of the call is
+    /// visited at most once, but `f` cannot stop the iteration.
+    pub fn par_range(&self, f: impl Fn(&K, &V) + Sync) {
+        self.par_for_each(|k, e, guard| {
+            if let Some(v) = e.load(guard) {
+                f(k, v);
+            }
+        });
+    }
+
+    /// Retains only the entries for which `f` returns true, like
+    /// [`SyncMap::retain`], calling `f` from one scoped thread per available
+    /// core.
+    pub fn par_retain(&self, f: impl Fn(&K, &V) -> bool + Sync) {
+        self.par_for_each(|k, e, guard| {
+            if let Some(v) = e.load(guard) {
+                if !f(k, v) && e.delete_if_same(v, guard, &self.collector) {
+                    self.removed(k, v);
+                }
+            }
+        });
+
+        self.collector.collect();
+    }
+
+    fn par_for_each(&self, f: impl Fn(&K, &Entry<V>, &Guard) + Sync) {
+        // Splitting up a map smaller than this costs more than it saves.
+        const MIN_CHUNK: usize = 1024;
+
+        let guard = reclaim::pin();
+        let read = self.load_promoted(&guard);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk = read.m.len().div_ceil(threads).max(MIN_CHUNK);
+        if read.m.len() <= chunk {
+            for (k, e) in read.m.iter() {
+                f(k.get(), e, &guard);
+            }
+            return;
+        }
+
+        let entries: Vec<_> = read.m.iter().collect();
+        let f = &f;
+        std::thread::scope(|s| {
+            for part in entries.chunks(chunk) {
+                s.spawn(move || {
+                    let guard = reclaim::pin();
+                    for (k, e) in part {
+                        f(k.get(), e, &guard);
+                    }
+                });
+            }
+        });
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: PartialEq,
+    S: BuildHasher + Clone,
+{
+    /// Swaps the old and new values for a key if the value stored in the map
+    /// is equal to `old`. Returns whether the swap happened.
+    pub fn compare_and_swap<Q>(&self, key: &Q, old: &V, new: V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let swapped = self.find_entry(key, &guard).and_then(|(k, e)| {
+            let (previous, value) = e.try_compare_and_swap(old, new, &guard, &self.collector)?;
+            self.touch(e);
+            self.updated(k, previous, value);
+            Some(())
+        });
+        drop(guard);
+
+        self.collector.collect();
+        swapped.is_some()
+    }
+
+    /// Deletes the entry for a key if its value is equal to `old`. Returns
+    /// whether the entry was deleted.
+    ///
+    /// If there is no current value for key in the map, returns false (even
+    /// if `old` is some value nobody could have stored).
+    pub fn compare_and_remove<Q>(&self, key: &Q, old: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let guard = reclaim::pin();
+        let deleted = self.find_entry(key, &guard).and_then(|(k, e)| {
+            let previous = e.try_compare_and_delete(old, &guard, &self.collector)?;
+            self.removed(k, previous);
+            Some(())
+        });
+        drop(guard);
+
+        self.collector.collect();
+        deleted.is_some()
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Copies the keys and values present in the map into a `HashMap`.
+    ///
+    /// The dirty map is promoted first, which holds the lock only briefly;
+    /// the copy itself is made from the read map without blocking writers.
+    /// As with [`SyncMap::range`], a value stored concurrently may or may not
+    /// be reflected in the snapshot.
+    pub fn snapshot(&self) -> HashMap<K, V, S> {
+        let guard = reclaim::pin();
+        let read = self.load_promoted(&guard);
+        let mut m = HashMap::with_capacity_and_hasher(read.m.len(), self.hash_builder.clone());
+        m.extend(
+            read.m
+                .iter()
+                .filter_map(|(k, e)| Some((k.get().clone(), e.load(&guard)?.clone()))),
+        );
+        m
+    }
+
+    /// Returns an iterator over the keys and values present in the map, in
+    /// batches of at most `batch_size` copies, e.g. to stream a large map
+    /// without copying it all at once.
+    ///
+    /// Every key present for the whole scan is yielded exactly once, and no
+    /// key more than once; keys added after the call are not. Only copying a
+    /// batch pins the thread, so the scan can be held and resumed at will,
+    /// but it keeps the read map of the time of the call alive until it is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn scan(&self, batch_size: usize) -> Scan<'_, K, V, S> {
+        assert!(batch_size > 0, "batch size must be positive");
+        let guard = reclaim::pin();
+        let read: *const ReadOnly<K, V, S> = self.load_promoted(&guard);
+        let read = unsafe {
+            // The read map cannot have been freed while pinned.
+            Arc::increment_strong_count(read);
+            Arc::from_raw(read)
+        };
+        Scan {
+            // The read map is kept alive by `read`, which outlives it.
+            inner: unsafe { (*Arc::as_ptr(&read)).m.iter() },
+            _read: read,
+            batch_size,
+            _map: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns the changes that turn `other` into this map: the keys only
+    /// present here, the keys only present in `other`, and the keys whose
+    /// values differ, e.g. to reconcile a desired state against an observed
+    /// one.
+    ///
+    /// Both maps are promoted first and compared through their read maps, as
+    /// [`SyncMap::snapshot`] copies them, so the diff is made without
+    /// blocking writers. A value stored concurrently in either map may or
+    /// may not be reflected in it.
+    pub fn diff<S2>(&self, other: &SyncMap<K, V, S2>) -> Diff<K, V>
+    where
+        V: PartialEq,
+        S2: BuildHasher + Clone,
+    {
+        let guard = reclaim::pin();
+        let other = other.load_promoted(&guard);
+        self.diff_with(
+            &guard,
+            |key| other.m.get(Query(key).as_dyn())?.load(&guard),
+            other
+                .m
+                .iter()
+                .filter_map(|(k, e)| Some((k.get(), e.load(&guard)?))),
+        )
+    }
+
+    /// Returns the changes that turn `snapshot`, e.g. one taken earlier with
+    /// [`SyncMap::snapshot`], into this map. See [`SyncMap::diff`].
+    pub fn diff_snapshot<S2>(&self, snapshot: &HashMap<K, V, S2>) -> Diff<K, V>
+    where
+        V: PartialEq,
+        S2: BuildHasher,
+    {
+        let guard = reclaim::pin();
+        self.diff_with(&guard, |key| snapshot.get(key), snapshot.iter())
+    }
+
+    // Diffs the read map against another map, given as a lookup and an
+    // iterator over its keys and values.
+    fn diff_with<'g, 'o>(
+        &self,
+        guard: &'g Guard,
+        get: impl Fn(&K) -> Option<&'o V>,
+        iter: impl Iterator<Item = (&'o K, &'o V)>,
+    ) -> Diff<K, V>
+    where
+        V: PartialEq + 'o,
+        K: 'o,
+    {
+        let read = self.load_promoted(guard);
+        let mut diff = Diff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for (k, e) in &read.m {
+            let Some(v) = e.load(guard) else { continue };
+            match get(k.get()) {
+                None => diff.added.push((k.get().clone(), v.clone())),
+                Some(old) if old != v => {
+                    diff.changed.push((k.get().clone(), old.clone(), v.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, old) in iter {
+            let present = read
+                .m
+                .get(Query(k).as_dyn())
+                .is_some_and(|e| e.load(guard).is_some());
+            if !present {
+                diff.removed.push((k.clone(), old.clone()));
+            }
+        }
+        diff
+    }
+}
+
+impl<K, V, S> SyncMap<K, Arc<V>, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a clone of the `Arc` stored for a key, which unlike a [`Ref`]
+    /// neither borrows the map nor keeps the thread pinned, so it can be held
+    /// across an `.await`.
+    pub fn load_shared<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.load(key).map(|v| Arc::clone(&v))
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: AtomicValue,
+    S: BuildHasher + Clone,
+{
+    /// Adds `delta` to the counter for a key, starting from `V::default()` if
+    /// it is missing, and returns the new count.
+    ///
+    /// The counter is updated in place, so only creating it allocates. An
+    /// update is not reported to the update callback, and one racing with
+    /// the removal of the key may be lost.
+    ///
+    /// ```
+    /// use std::sync::atomic::AtomicU64;
+    ///
+    /// use sync_map::map::SyncMap;
+    ///
+    /// let hits: SyncMap<&str, AtomicU64> = SyncMap::new();
+    /// hits.increment("/", 1);
+    /// assert_eq!(hits.increment("/", 2), 3);
+    /// ```
+    pub fn increment(&self, key: K, delta: V::Value) -> V::Value {
+        self.counter(key).add_fetch(delta)
+    }
+
+    /// Like [`SyncMap::increment`], but returns the previous count.
+    pub fn fetch_add(&self, key: K, delta: V::Value) -> V::Value {
+        self.counter(key).fetch_add(delta)
+    }
+
+    /// Replaces the counter for a key with `f(count)`, starting from
+    /// `V::default()` if it is missing, unless `f` returns `None`. Returns the
+    /// previous count, as `Err` if `f` returned `None`.
+    ///
+    /// `f` may be called several times under contention. See
+    /// [`SyncMap::increment`].
+    pub fn fetch_update(
+        &self,
+        key: K,
+        f: impl FnMut(V::Value) -> Option<V::Value>,
+    ) -> Result<V::Value, V::Value> {
+        self.counter(key).fetch_update(f)
+    }
+
+    fn counter(&self, key: K) -> Ref<'_, V> {
+        match self.load(&key) {
+            Some(counter) => counter,
+            None => self.load_or_store(key, V::default()).0,
+        }
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Stores every key-value pair from the iterator.
+    ///
+    /// Exclusive access lets the pairs go straight into the read map, without
+    /// taking the lock or going through the dirty map.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        if self.bound.is_some() || !self.hooks.is_empty() {
+            // Keys have to go through the eviction policy and the callbacks.
+            for (k, v) in iter {
+                self.store(k, v);
+            }
+            return;
+        }
+
+        let m = self.get_mut_map();
+        m.reserve(iter.size_hint().0);
+        for (k, v) in iter {
+            match m.entry(Key::new(k)) {
+                hash_map::Entry::Occupied(mut e) => {
+                    Self::entry_mut(e.get_mut()).replace_mut(v);
+                }
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(Arc::new(Entry::new(v)));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V, S> Extend<(&'a K, &'a V)> for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Copy,
+    V: Copy,
+    S: BuildHasher + Clone,
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
+    }
+}
+
+#[cfg(feature = "persist")]
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + crate::persist::Persist,
+    V: crate::persist::Persist,
+    S: BuildHasher + Clone,
+{
+    /// Writes the keys and values present in the map to `w`, in the format
+    /// described in [`persist`](crate::persist).
+    ///
+    /// As with [`SyncMap::snapshot`], the dirty map is promoted first and the
+    /// entries are written from the read map without blocking writers, and a
+    /// value stored concurrently may or may not be reflected. The thread
+    /// stays pinned until the snapshot is written, so the memory of values
+    /// replaced in the meantime is only reclaimed afterwards. `w` is not
+    /// buffered or flushed.
+    pub fn write_snapshot<W: std::io::Write>(&self, mut w: W) -> std::io::Result<()> {
+        let guard = reclaim::pin();
+        let read = self.load_promoted(&guard);
+        let entries: Vec<_> = read
+            .m
+            .iter()
+            .filter_map(|(k, e)| Some((k.get(), e.load(&guard)?)))
+            .collect();
+
+        crate::persist::write_header(&mut w, entries.len())?;
+        for (k, v) in entries {
+            k.persist(&mut w)?;
+            v.persist(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a map written by [`SyncMap::write_snapshot`] from `r`, into the
+    /// read map of a new map. `r` is not buffered.
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidData`] if `r` does not hold a
+    /// snapshot of the map's key and value types.
+    pub fn read_snapshot<R: std::io::Read>(mut r: R) -> std::io::Result<Self>
+    where
+        S: Default,
+    {
+        use crate::persist::{prealloc, read_header};
+
+        let len = read_header(&mut r)?;
+        let mut m = HashMap::with_capacity_and_hasher(prealloc(len), S::default());
+        for _ in 0..len {
+            let k = K::restore(&mut r)?;
+            m.insert(k, V::restore(&mut r)?);
+        }
+        Ok(SyncMap::from(m))
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone + Default,
+{
+    /// Builds the read map directly from the iterator, so the new map starts
+    /// out fully promoted.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = SyncMap::with_hasher(S::default());
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K, V, S> From<HashMap<K, V, S>> for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Moves the contents of a `HashMap` into the read map, keeping its
+    /// hasher.
+    fn from(m: HashMap<K, V, S>) -> Self {
+        let mut map = SyncMap::with_hasher(m.hasher().clone());
+        map.extend(m);
+        map
+    }
+}
+
+impl<K, V, S> Clone for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Deep copies the map into a new one whose read map holds every entry.
+    /// The copy is not bounded, even if the map is, and keeps the map's
+    /// callbacks without running them for the copied entries.
+    ///
+    /// The dirty map is promoted first so every key present at the time of
+    /// the call is copied. As with [`SyncMap::range`], a value stored
+    /// concurrently may or may not be reflected in the copy.
+    fn clone(&self) -> Self {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut map =
+            SyncMap::with_policy(capacity, self.hash_builder.clone(), self.policy.clone());
+        map.stale = self.stale;
+        map.compaction = self.compaction;
+        map.dirty_limit = self.dirty_limit;
+
+        map.extend(self.snapshot());
+        map.hooks = self.hooks.clone();
+        if let Some(weigher) = map.hooks.weigher {
+            let mut weight = 0;
+            map.range(|k, v| {
+                weight += weigher(k, v);
+                true
+            });
+            *map.weight.get_mut() = weight;
+        }
+        map
+    }
+}
+
+impl<K, V, S> fmt::Debug for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    /// Prints the entries present in the map, as visited by
+    /// [`SyncMap::range`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        self.range(|k, v| {
+            m.entry(k, v);
+            true
+        });
+        m.finish()
+    }
+}
+
+/// An iterator over the entries of a [`SyncMap`], created by
+/// [`SyncMap::iter`].
+pub struct Iter<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    inner: hash_map::Iter<'a, Key<K>, Arc<Entry<V>>>,
+    _guard: Guard,
+    _map: std::marker::PhantomData<&'a SyncMap<K, V, S>>,
+}
+
+impl<'a, K, V, S> Iterator for Iter<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    type Item = RefPair<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every item pins on its own, so it may outlive the iterator.
+        let guard = reclaim::pin();
+        for (k, e) in self.inner.by_ref() {
+            if let Some(v) = e.load(&guard) {
+                let v: *const V = v;
+                return Some(unsafe { RefPair::new(guard, k.get(), v) });
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+/// An iterator over the entries removed from a [`SyncMap`], created by
+/// [`SyncMap::remove_all`].
+pub struct RemoveAll<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: &'a SyncMap<K, V, S>,
+    read: *mut ReadOnly<K, V, S>,
+    inner: hash_map::Iter<'a, Key<K>, Arc<Entry<V>>>,
+    guard: Guard,
+}
+
+impl<'a, K, V, S> Iterator for RemoveAll<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    type Item = (K, Ref<'a, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every value pins on its own, so it may outlive the iterator.
+        let guard = reclaim::pin();
+        for (k, e) in self.inner.by_ref() {
+            // Expunging the entry sends writers still holding it to the lock,
+            // where they find the key missing.
+            if let Some(v) = e.expunge(&guard, &self.map.collector) {
+                self.map.report_removed(k.get(), v);
+                let v: *const V = v;
+                return Some((k.get().clone(), unsafe { Ref::new(guard, v) }));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<K, V, S> Drop for RemoveAll<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn drop(&mut self) {
+        for (k, e) in self.inner.by_ref() {
+            if let Some(v) = e.expunge(&self.guard, &self.map.collector) {
+                self.map.report_removed(k.get(), v);
+            }
+        }
+        unsafe { ReadOnly::retire(self.read, &self.map.collector) };
+        self.map.collector.collect();
+    }
+}
+
+/// A draining iterator over the entries of a [`SyncMap`], created by
+/// [`SyncMap::drain`].
+pub struct Drain<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    inner: RemoveAll<'a, K, V, S>,
+}
+
+impl<K, V, S> Iterator for Drain<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, v)| (k, V::clone(&v)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// An iterator over the keys of a [`SyncMap`] at one point in time, created
+/// by [`SyncMap::snapshot_iter`].
+pub struct SnapshotIter<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    // Borrows from `read`, which is only retired when the iterator is
+    // dropped.
+    inner: hash_map::Iter<'a, Key<K>, Arc<Entry<V>>>,
+    read: Option<Arc<ReadOnly<K, V, S>>>,
+    map: &'a SyncMap<K, V, S>,
+}
+
+impl<'a, K, V, S> Iterator for SnapshotIter<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    type Item = RefPair<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every item pins on its own, so it may outlive the iterator.
+        let guard = reclaim::pin();
+        for (k, e) in self.inner.by_ref() {
+            if let Some(v) = e.load(&guard) {
+                let v: *const V = v;
+                return Some(unsafe { RefPair::new(guard, k.get(), v) });
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<K, V, S> Drop for SnapshotIter<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn drop(&mut self) {
+        // Items still hold keys of the read map, and were pinned before it is
+        // retired.
+        let read = self.read.take().unwrap();
+        unsafe { self.map.collector.retire(Box::into_raw(Box::new(read))) };
+    }
+}
+
+/// An iterator over batches of copied entries of a [`SyncMap`], created by
+/// [`SyncMap::scan`].
+pub struct Scan<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    // Borrows from `_read`, so it must be dropped first.
+    inner: hash_map::Iter<'a, Key<K>, Arc<Entry<V>>>,
+    _read: Arc<ReadOnly<K, V, S>>,
+    batch_size: usize,
+    _map: std::marker::PhantomData<&'a SyncMap<K, V, S>>,
+}
+
+impl<K, V, S> Iterator for Scan<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    type Item = Vec<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let guard = reclaim::pin();
+        let mut batch = Vec::new();
+        for (k, e) in self.inner.by_ref() {
+            if let Some(v) = e.load(&guard) {
+                batch.push((k.get().clone(), v.clone()));
+                if batch.len() == self.batch_size {
+                    break;
+                }
+            }
+        }
+        (!batch.is_empty()).then_some(batch)
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    type Item = RefPair<'a, K, V>;
+    type IntoIter = Iter<'a, K, V, S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An owning iterator over the entries of a [`SyncMap`], created by its
+/// [`IntoIterator`] implementation.
+pub struct IntoIter<K, V> {
+    inner: hash_map::IntoIter<Key<K>, Arc<Entry<V>>>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        for (k, e) in self.inner.by_ref() {
+            let mut e = Arc::into_inner(e).expect("entry shared despite exclusive access");
+            if let Some(v) = e.take_mut() {
+                let k = k.into_inner().expect("key shared despite exclusive access");
+                return Some((k, v));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V, S> IntoIterator for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    /// Consumes the map, yielding every key and value it holds.
+    ///
+    /// Owning the map means no reader is left, so the values are moved out
+    /// rather than copied, and dropping the iterator drops the rest of them
+    /// right away.
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        let empty = HashMap::with_hasher(self.hash_builder.clone());
+        let m = std::mem::replace(self.get_mut_map(), empty);
+        IntoIter {
+            inner: m.into_iter(),
+        }
+    }
+}
+
+/// A handle to run the upkeep of a map in bounded steps, obtained from
+/// [`SyncMap::maintenance`].
+pub struct Maintenance<'a> {
+    task: Box<dyn Upkeep + 'a>,
+    batch_size: usize,
+}
+
+// A part of a map's upkeep.
+trait Upkeep {
+    // Does at most `batch_size` units of work, returning whether some is left.
+    fn run(&mut self, batch_size: usize) -> bool;
+}
+
+impl<'a> Maintenance<'a> {
+    const DEFAULT_BATCH_SIZE: usize = 1024;
+
+    fn new(task: Box<dyn Upkeep + 'a>) -> Self {
+        Maintenance {
+            task,
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    // Runs every handle in turn.
+    pub(crate) fn all(handles: Vec<Maintenance<'a>>) -> Self {
+        Maintenance::new(Box::new(handles))
+    }
+
+    /// Sets how many keys a run visits at most, 1024 by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch size must be positive");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Runs the next step of the upkeep, and returns whether a sweep is left
+    /// unfinished, that is whether running again right away has work to do.
+    ///
+    /// A sweep visits the keys present when it started, a batch per run, and
+    /// starts over on the run after it finishes. Removals count towards the
+    /// compaction ratio, so a run may also compact the map.
+    pub fn run_pending(&mut self) -> bool {
+        self.task.run(self.batch_size)
+    }
+}
+
+impl Upkeep for Vec<Maintenance<'_>> {
+    fn run(&mut self, batch_size: usize) -> bool {
+        let mut more = false;
+        for m in self {
+            more |= m.task.run(batch_size);
+        }
+        more
+    }
+}
+
+// Returns true for the values a sweep removes.
+type Stale<'a, V> = Box<dyn Fn(&V) -> bool + 'a>;
+
+// Removes stale values, a batch at a time, and frees what can be.
+struct Sweep<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: &'a SyncMap<K, V, S>,
+    stale: Option<Stale<'a, V>>,
+    cursor: Option<Cursor<'a, K, V, S>>,
+}
+
+// The keys left to visit, and the read map they belong to, which must be
+// dropped last.
+type Cursor<'a, K, V, S> = (
+    hash_map::Iter<'a, Key<K>, Arc<Entry<V>>>,
+    Arc<ReadOnly<K, V, S>>,
+);
+
+impl<K, V, S> Upkeep for Sweep<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    fn run(&mut self, batch_size: usize) -> bool {
+        let guard = reclaim::pin();
+        let mut more = false;
+        if let Some(stale) = &self.stale {
+            let (iter, _) = self.cursor.get_or_insert_with(|| {
+                let read: *const ReadOnly<K, V, S> = self.map.load_promoted(&guard);
+                let read = unsafe {
+                    // The read map cannot have been freed while pinned.
+                    Arc::increment_strong_count(read);
+                    Arc::from_raw(read)
+                };
+                // The read map is kept alive by the Arc, which outlives it.
+                (unsafe { (*Arc::as_ptr(&read)).m.iter() }, read)
+            });
+            for (k, e) in iter.take(batch_size) {
+                if let Some(v) = e.load(&guard) {
+                    if stale(v) && e.delete_if_same(v, &guard, &self.map.collector) {
+                        self.map.removed(k.get(), v);
+                    }
+                }
+            }
+            more = iter.len() > 0;
+            if !more {
+                self.cursor = None;
+            }
+        }
+        drop(guard);
+
+        self.map.collector.collect_now();
+        more
+    }
+}
+
+/// The changes between two maps, returned by [`SyncMap::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diff<K, V> {
+    /// The keys and values only present in the newer map.
+    pub added: Vec<(K, V)>,
+    /// The keys and values only present in the older map.
+    pub removed: Vec<(K, V)>,
+    /// The keys present in both maps with different values, with the older
+    /// value and then the newer one.
+    pub changed: Vec<(K, V, V)>,
+}
+
+impl<K, V> Diff<K, V> {
+    /// Returns true if the maps hold the same keys and values.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The writes of a [`SyncMap::transaction`], applied once it returns.
+pub struct Transaction<K, V> {
+    // `None` removes the key.
+    writes: Vec<(K, Option<V>)>,
+}
+
+impl<K, V> Transaction<K, V> {
+    /// Sets the value for a key.
+    pub fn store(&mut self, key: K, value: V) {
+        self.writes.push((key, Some(value)));
+    }
+
+    /// Deletes the value for a key.
+    pub fn remove(&mut self, key: K) {
+        self.writes.push((key, None));
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Transaction<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("writes", &self.writes)
+            .finish()
+    }
+}
+
+/// A view into a single key of a [`SyncMap`], obtained from
+/// [`SyncMap::entry`].
+///
+/// The key stays locked against other writers for as long as the entry, or
+/// the occupied or vacant entry it is matched into, is alive.
+pub enum MapEntry<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+/// A locked key of a [`SyncMap`] that holds a value.
+pub struct OccupiedEntry<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    key: Key<K>,
+    lock: EntryLock<'a, K, V, S>,
+}
+
+/// A locked key of a [`SyncMap`] that holds no value.
+pub struct VacantEntry<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    key: Key<K>,
+    lock: EntryLock<'a, K, V, S>,
+}
+
+/// A locked key of a [`SyncMap`], obtained from [`SyncMap::lock_entry`].
+///
+/// The key stays locked against other writers until the guard is dropped.
+pub struct EntryGuard<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    key: Key<K>,
+    lock: EntryLock<'a, K, V, S>,
+}
+
+/// A locked key of a [`SyncMap`] and a copy of its value to change,
+/// obtained from [`SyncMap::get_mut`].
+///
+/// The copy replaces the value when the guard is dropped.
+pub struct RefMut<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    // Kept valid by the lock's guard.
+    key: &'a K,
+    lock: EntryLock<'a, K, V, S>,
+    // Cloned from the value on the first mutable access.
+    value: Option<V>,
+}
+
+/// A handle to the entry for a key of a [`SyncMap`], obtained from
+/// [`SyncMap::entry_ref`].
+///
+/// It keeps the entry alive, but does not lock the key.
+pub struct EntryRef<'a, K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: &'a SyncMap<K, V, S>,
+    key: Key<K>,
+    // The key's entry when last resolved. Once expunged, it may no longer be
+    // in the map.
+    entry: Arc<Entry<V>>,
+}
+
+// Holds the lock of an entry and releases it when dropped.
+struct EntryLock<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: &'a SyncMap<K, V, S>,
+    // Kept valid by `guard`.
+    entry: &'a Entry<V>,
+    guard: Guard,
+}
+
+impl<K, V, S> EntryLock<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    // The value can only change through this lock, so it stays valid for as
+    // long as the lock is borrowed.
+    fn get(&self) -> Option<&V> {
+        self.entry.load(&self.guard)
+    }
+}
+
+impl<K, V, S> Drop for EntryLock<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn drop(&mut self) {
+        self.entry.unlock();
+    }
+}
+
+impl<'a, K, V, S> MapEntry<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    // `entry` must be locked and protected by `guard`.
+    fn new(map: &'a SyncMap<K, V, S>, key: Key<K>, entry: *const Entry<V>, guard: Guard) -> Self {
+        let entry = unsafe { &*entry };
+        let lock = EntryLock { map, entry, guard };
+        if lock.get().is_some() {
+            MapEntry::Occupied(OccupiedEntry { key, lock })
+        } else {
+            MapEntry::Vacant(VacantEntry { key, lock })
+        }
+    }
+
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match self {
+            MapEntry::Occupied(e) => e.key(),
+            MapEntry::Vacant(e) => e.key(),
+        }
+    }
+
+    /// Stores `default` if the entry is vacant, and returns the value.
+    pub fn or_insert(self, default: V) -> Ref<'a, V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Stores the result of `default` if the entry is vacant, and returns the
+    /// value.
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> Ref<'a, V> {
+        self.or_insert_with_key(|_| default())
+    }
+
+    /// Stores the result of `default` if the entry is vacant and `default`
+    /// succeeds, and returns the value.
+    pub fn or_try_insert_with<E>(
+        self,
+        default: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Ref<'a, V>, E> {
+        match self {
+            MapEntry::Occupied(e) => Ok(e.into_ref()),
+            MapEntry::Vacant(e) => Ok(e.insert(default()?)),
+        }
+    }
+
+    /// Stores the result of `default`, which is passed the key, if the entry
+    /// is vacant, and returns the value.
+    pub fn or_insert_with_key(self, default: impl FnOnce(&K) -> V) -> Ref<'a, V> {
+        match self {
+            MapEntry::Occupied(e) => e.into_ref(),
+            MapEntry::Vacant(e) => {
+                let value = default(e.key.get());
+                e.insert(value)
+            }
+        }
+    }
+
+    /// Stores the default value if the entry is vacant, and returns the
+    /// value.
+    pub fn or_default(self) -> Ref<'a, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Replaces the value of an occupied entry with `f(&value)`.
+    ///
+    /// Concurrent readers may hold the current value, so it is replaced
+    /// rather than modified in place.
+    pub fn and_modify(self, f: impl FnOnce(&V) -> V) -> Self {
+        match self {
+            MapEntry::Occupied(mut e) => {
+                let value = f(e.get());
+                e.insert(value);
+                MapEntry::Occupied(e)
+            }
+            MapEntry::Vacant(e) => MapEntry::Vacant(e),
+        }
+    }
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// Returns a reference to the value.
+    pub fn get(&self) -> &V {
+        self.lock.get().unwrap()
+    }
+
+    /// Sets the value, returning the previous one.
+    pub fn insert(&mut self, value: V) -> Ref<'a, V> {
+        let lock = &self.lock;
+        let previous: *const V = lock
+            .entry
+            .swap_held(value, &lock.guard, &lock.map.collector)
+            .unwrap();
+        let value = lock.get().unwrap();
+        lock.map.touch(lock.entry);
+        lock.map
+            .updated(self.key.get(), unsafe { &*previous }, value);
+        unsafe { Ref::new(reclaim::pin(), previous) }
+    }
+
+    /// Removes the value, returning it.
+    pub fn remove(self) -> Ref<'a, V> {
+        let lock = &self.lock;
+        let previous: *const V = lock
+            .entry
+            .delete_held(&lock.guard, &lock.map.collector)
+            .unwrap();
+        lock.map.removed(self.key.get(), unsafe { &*previous });
+        unsafe { Ref::new(reclaim::pin(), previous) }
+    }
+
+    /// Unlocks the entry, returning a reference to the value.
+    pub fn into_ref(self) -> Ref<'a, V> {
+        let value: *const V = self.get();
+        unsafe { Ref::new(reclaim::pin(), value) }
+    }
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// Sets the value, unlocks the entry and returns a reference to the value.
+    pub fn insert(self, value: V) -> Ref<'a, V> {
+        let lock = &self.lock;
+        lock.entry
+            .swap_held(value, &lock.guard, &lock.map.collector);
+        let value: *const V = lock.get().unwrap();
+        lock.map.touch(lock.entry);
+        lock.map.inserted(self.key.get(), unsafe { &*value });
+        unsafe { Ref::new(reclaim::pin(), value) }
+    }
+}
+
+impl<K, V, S> VacantEntry<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Takes ownership of the key, cloning it if the map already holds it.
+    pub fn into_key(self) -> K {
+        self.key.into_owned()
+    }
+}
+
+impl<'a, K, V, S> EntryGuard<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a reference to the locked key.
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// Returns a reference to the value, if the key holds one.
+    pub fn get(&self) -> Option<&V> {
+        self.lock.get()
+    }
+
+    /// Stores a value for the key, returning the previous one.
+    pub fn store(&mut self, value: V) -> Option<Ref<'a, V>> {
+        let lock = &self.lock;
+        let previous = lock
+            .entry
+            .swap_held(value, &lock.guard, &lock.map.collector)
+            .map(ptr::from_ref);
+        let value = lock.get().unwrap();
+        lock.map.touch(lock.entry);
+        lock.map
+            .stored(self.key.get(), previous.map(|p| unsafe { &*p }), value);
+        SyncMap::<K, V, S>::wrap(reclaim::pin(), previous)
+    }
+
+    /// Removes the value for the key, returning it. The key stays locked.
+    pub fn remove(&mut self) -> Option<Ref<'a, V>> {
+        let lock = &self.lock;
+        let previous = lock.entry.delete_held(&lock.guard, &lock.map.collector)?;
+        lock.map.removed(self.key.get(), previous);
+        let previous: *const V = previous;
+        Some(unsafe { Ref::new(reclaim::pin(), previous) })
+    }
+}
+
+impl<'a, K, V, S> EntryRef<'a, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a reference to the key.
+    pub fn key(&self) -> &K {
+        self.key.get()
+    }
+
+    /// Returns the value stored for the key.
+    pub fn load(&self) -> Option<Ref<'a, V>> {
+        let guard = reclaim::pin();
+        match self.entry.state(&guard) {
+            EntryState::Present(value) => {
+                let value: *const V = value;
+                Some(unsafe { Ref::new(guard, value) })
+            }
+            EntryState::SoftDelete => None,
+            EntryState::HardDelete => self.map.load(self.key.get()),
+        }
+    }
+
+    /// Returns whether the entry has been expunged, that is deleted and
+    /// dropped from the dirty map, so that the handle may no longer point
+    /// into the map. Loads then look the key up, until a write moves the
+    /// handle to the key's current entry.
+    pub fn is_expunged(&self) -> bool {
+        let guard = reclaim::pin();
+        matches!(self.entry.state(&guard), EntryState::HardDelete)
+    }
+
+    /// Sets the value for the key.
+    pub fn store(&mut self, value: V) {
+        drop(self.swap(value));
+        self.map.collector.collect();
+    }
+
+    /// Swaps the value for the key and returns the previous value if any.
+    pub fn swap(&mut self, value: V) -> Option<Ref<'a, V>> {
+        let guard = reclaim::pin();
+        let mut value = value;
+        loop {
+            match self.entry.try_swap(value, &guard, &self.map.collector) {
+                Ok((previous, value)) => {
+                    self.map.touch(&self.entry);
+                    self.map.stored(self.key.get(), previous, value);
+                    let previous = previous.map(ptr::from_ref);
+                    return SyncMap::<K, V, S>::wrap(guard, previous);
+                }
+                Err(v) => {
+                    value = v;
+                    self.entry = self.map.resolve(&self.key);
+                }
+            }
+        }
+    }
+
+    /// Deletes the value for the key, returning it if there was one.
+    pub fn remove(&mut self) -> Option<Ref<'a, V>> {
+        let guard = reclaim::pin();
+        if matches!(self.entry.state(&guard), EntryState::HardDelete) {
+            return self.map.remove(self.key.get());
+        }
+        let previous = self.entry.delete(&guard, &self.map.collector)?;
+        self.map.removed(self.key.get(), previous);
+        let previous: *const V = previous;
+        Some(unsafe { Ref::new(guard, previous) })
+    }
+
+    /// Swaps in `new` if the value for the key is equal to `old`. Returns
+    /// whether it was swapped. See [`SyncMap::compare_and_swap`].
+    pub fn compare_and_swap(&self, old: &V, new: V) -> bool
+    where
+        V: PartialEq,
+    {
+        let guard = reclaim::pin();
+        if matches!(self.entry.state(&guard), EntryState::HardDelete) {
+            return self.map.compare_and_swap(self.key.get(), old, new);
+        }
+        let swapped = self
+            .entry
+            .try_compare_and_swap(old, new, &guard, &self.map.collector)
+            .map(|(previous, value)| {
+                self.map.touch(&self.entry);
+                self.map.updated(self.key.get(), previous, value);
+            })
+            .is_some();
+        drop(guard);
+
+        self.map.collector.collect();
+        swapped
+    }
+
+    /// Deletes the value for the key if it is equal to `old`. Returns whether
+    /// it was deleted. See [`SyncMap::compare_and_remove`].
+    pub fn compare_and_remove(&self, old: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let guard = reclaim::pin();
+        if matches!(self.entry.state(&guard), EntryState::HardDelete) {
+            return self.map.compare_and_remove(self.key.get(), old);
+        }
+        let deleted = self
+            .entry
+            .try_compare_and_delete(old, &guard, &self.map.collector)
+            .map(|previous| self.map.removed(self.key.get(), previous))
+            .is_some();
+        drop(guard);
+
+        self.map.collector.collect();
+        deleted
+    }
+}
+
+impl<K, V, S> RefMut<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Returns a reference to the locked key.
+    pub fn key(&self) -> &K {
+        self.key
+    }
+
+    /// Returns a reference to the value, as changed so far.
+    pub fn value(&self) -> &V {
+        self
+    }
+
+    /// Returns a mutable reference to the copy of the value.
+    pub fn value_mut(&mut self) -> &mut V {
+        self
+    }
+}
+
+impl<K, V, S> Deref for RefMut<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match &self.value {
+            Some(value) => value,
+            None => self.lock.get().unwrap(),
+        }
+    }
+}
+
+impl<K, V, S> std::ops::DerefMut for RefMut<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        let lock = &self.lock;
+        self.value
+            .get_or_insert_with(|| lock.get().unwrap().clone())
+    }
+}
+
+impl<K, V, S> Drop for RefMut<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        // A copy left halfway through a change by a panic is dropped.
+        let Some(value) = self.value.take() else {
+            return;
+        };
+        if std::thread::panicking() {
+            return;
+        }
+        let lock = &self.lock;
+        let previous: *const V = lock
+            .entry
+            .swap_held(value, &lock.guard, &lock.map.collector)
+            .unwrap();
+        let value = lock.get().unwrap();
+        lock.map.touch(lock.entry);
+        lock.map.updated(self.key, unsafe { &*previous }, value);
+    }
+}
+
+impl<K, V, S> fmt::Debug for RefMut<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefMut")
+            .field("key", self.key)
+            .field("value", &**self)
+            .finish()
+    }
+}
+
+impl<K, V, S> fmt::Debug for EntryRef<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryRef")
+            .field("key", self.key.get())
+            .field("value", &self.load())
+            .finish()
+    }
+}
+
+impl<K, V, S> fmt::Debug for EntryGuard<'_, K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryGuard")
+            .field("key", self.key.get())
+            .field("value", &self.lock.get())
+            .finish()
+    }
+}
+
+impl<K, V, S> SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    // Takes a value no longer stored for a key off the total weight.
+    fn unweigh(&self, key: &K, value: &V) {
+        if let Some(weigher) = self.hooks.weigher {
+            self.weight
+                .fetch_sub(weigher(key, value), Ordering::Relaxed);
+        }
+    }
+
+    // Reports a value deleted without compacting the map.
+    fn report_removed(&self, key: &K, value: &V) {
+        self.hooks.removed(key, value);
+        self.unweigh(key, value);
+    }
+
+    // Runs the eviction callback for values held back from the collector.
+    fn report_evicted_values(&self, evicted: Vec<(Key<K>, Unlinked<V>)>) {
+        // Every value is retired up front, so that none is leaked if the
+        // callback panics; the guard keeps them valid until it returns.
+        let guard = reclaim::pin();
+        let evicted: Vec<_> = evicted
+            .into_iter()
+            .map(|(k, value)| {
+                let v: *const V = value.get();
+                value.retire(&self.collector);
+                (k, v)
+            })
+            .collect();
+        for (k, v) in &evicted {
+            self.hooks.evicted(k.get(), unsafe { &**v });
+        }
+        drop(guard);
+    }
+}
+
+impl<K, V, S> Drop for SyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn drop(&mut self) {
+        let evicted = std::mem::take(self.evicted.get_mut());
+        if !evicted.is_empty() {
+            self.report_evicted_values(evicted);
+        }
+        let read_ptr = *self.read.get_mut();
+        unsafe {
+            let _ = Arc::from_raw(read_ptr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn load() {
+        let map = SyncMap::new();
+        assert!(map.load(&1).is_none());
+
+        map.store(1, String::from("a"));
+        assert_eq!(*map.load(&1).unwrap(), "a");
+
+        map.store(1, String::from("b"));
+        assert_eq!(*map.load(&1).unwrap(), "b");
+    }
+
+    #[test]
+    fn borrow() {
+        let map = SyncMap::new();
+        map.store(String::from("a"), 1);
+        map.store(String::from("b"), 2);
+        assert_eq!(*map.load("a").unwrap(), 1);
+        assert_eq!(*map.update("a", |v| v + 1).unwrap(), 1);
+        assert!(map.compare_and_swap("a", &2, 3));
+        assert!(map.compare_and_remove("a", &3));
+        assert_eq!(*map.remove("b").unwrap(), 2);
+        assert!(map.load("b").is_none());
+    }
+
+    #[test]
+    fn contains_key() {
+        let map = SyncMap::new();
+        assert!(!map.contains_key(&1));
+        map.store(1, 1);
+        assert!(map.contains_key(&1));
+        map.remove(&1);
+        assert!(!map.contains_key(&1));
+    }
+
+    #[test]
+    fn shared_keys() {
+        #[derive(PartialEq, Eq, Hash, Debug)]
+        struct NoClone(u32);
+
+        let map = SyncMap::new();
+        map.store(NoClone(1), 1);
+        map.store(NoClone(2), 2);
+        // Promote, then copy the read map into a new dirty map.
+        map.range(|_, _| true);
+        map.store(NoClone(3), 3);
+        assert!(map.is_promoted(&NoClone(1)));
+        map.remove(&NoClone(2));
+        assert_eq!(*map.entry(NoClone(1)).or_insert(0), 1);
+
+        let mut pairs: Vec<_> = map.into_iter().collect();
+        pairs.sort_by_key(|(_, v)| *v);
+        assert_eq!(pairs, [(NoClone(1), 1), (NoClone(3), 3)]);
+    }
+
+    #[test]
+    fn lock_entry() {
+        let map = SyncMap::new();
+        map.store(1, 10);
+        let mut guard = map.lock_entry(1);
+        assert_eq!(guard.get(), Some(&10));
+        assert_eq!(*guard.remove().unwrap(), 10);
+        assert!(guard.remove().is_none());
+
+        // Writers to the key wait for the guard, readers do not.
+        thread::scope(|s| {
+            let writer = s.spawn(|| map.swap(1, 30).map(|v| *v));
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(map.load(&1).is_none());
+            assert!(guard.store(20).is_none());
+            assert_eq!(*map.load(&1).unwrap(), 20);
+            std::mem::drop(guard);
+            assert_eq!(writer.join().unwrap(), Some(20));
+        });
+        assert_eq!(*map.load(&1).unwrap(), 30);
+
+        let mut guard = map.lock_entry(2);
+        assert!(guard.get().is_none());
+        map.store(3, 3);
+        guard.store(2);
+        std::mem::drop(guard);
+        assert_eq!(*map.load(&2).unwrap(), 2);
+    }
+
+    #[test]
+    fn entry_ref() {
+        let map = SyncMap::new();
+        map.store(1, 10);
+        let mut e = map.entry_ref(1);
+        assert_eq!(*e.load().unwrap(), 10);
+        assert_eq!(*e.swap(11).unwrap(), 10);
+        assert!(e.compare_and_swap(&11, 12));
+        assert!(!e.compare_and_swap(&11, 13));
+        assert_eq!(*map.load(&1).unwrap(), 12);
+        map.store(1, 14);
+        assert_eq!(*e.load().unwrap(), 14);
+
+        // Removed, expunged by the next copy of the read map and dropped by
+        // the promotion after that.
+        assert!(e.compare_and_remove(&14));
+        map.store(2, 2);
+        map.range(|_, _| true);
+        assert!(e.is_expunged());
+        map.store(3, 3);
+        map.range(|_, _| true);
+        assert!(!map.is_promoted(&1));
+
+        // Stored again through the map, into a new entry.
+        map.store(1, 15);
+        assert_eq!(*e.load().unwrap(), 15);
+        e.store(16);
+        assert!(!e.is_expunged());
+        assert_eq!(*map.load(&1).unwrap(), 16);
+        assert_eq!(*e.remove().unwrap(), 16);
+        assert!(e.load().is_none());
+
+        let mut vacant = map.entry_ref(4);
+        assert!(vacant.load().is_none());
+        vacant.store(4);
+        assert_eq!(*map.load(&4).unwrap(), 4);
+
+        // Replaced by a transaction.
+        map.range(|_, _| true);
+        map.transaction(|txn| txn.store(4, 5));
+        assert!(vacant.is_expunged());
+        assert_eq!(*vacant.load().unwrap(), 5);
+        assert!(vacant.compare_and_swap(&5, 6));
+        assert_eq!(*map.load(&4).unwrap(), 6);
+
+        // Dropped by `remove_all`.
+        assert_eq!(map.remove_all().count(), 3);
+        assert!(vacant.load().is_none());
+        vacant.store(7);
+        assert_eq!(*map.load(&4).unwrap(), 7);
+    }
+
+    #[test]
+    fn bulk_load() {
+        let map = SyncMap::new();
+        map.store(0, 0);
+        map.store(1, 1);
+        map.range(|_, _| true);
+        map.store(2, 2);
+        map.remove(&1);
+
+        map.bulk_load((0..100).map(|i| (i, i * 10)));
+        assert!(map.dirty.lock().is_none());
+        assert_eq!(*map.load(&0).unwrap(), 0);
+        assert_eq!(*map.load(&1).unwrap(), 10);
+        assert_eq!(*map.load(&2).unwrap(), 2);
+        assert_eq!(*map.load(&99).unwrap(), 990);
+
+        // Concurrent writes are kept, whether they land before or after.
+        let map = SyncMap::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    map.store(i * 2, 1);
+                }
+            });
+            map.bulk_load((0..2000).map(|i| (i, 0)));
+        });
+        for i in 0..1000 {
+            assert_eq!(*map.load(&(i * 2)).unwrap(), 1);
+            assert_eq!(*map.load(&(i * 2 + 1)).unwrap(), 0);
+        }
+
+        // A bounded map keeps the values held too.
+        let map = SyncMapBuilder::new().build_bounded(10, crate::policy::Fifo::new());
+        map.store(0, 0);
+        map.bulk_load([(0, 1), (1, 1), (1, 2)]);
+        assert_eq!(*map.load(&0).unwrap(), 0);
+        assert_eq!(*map.load(&1).unwrap(), 2);
+    }
+
+    #[test]
+    fn get_mut() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let map = SyncMap::new();
+        assert!(map.get_mut(&1).is_none());
+        map.store(1, vec![1]);
+        map.store(2, vec![2]);
+        map.remove(&2);
+        assert!(map.get_mut(&2).is_none());
+
+        let mut v = map.get_mut(&1).unwrap();
+        v.push(2);
+        // Readers see the previous value until the guard is dropped.
+        assert_eq!(*map.load(&1).unwrap(), [1]);
+        assert_eq!(*v, [1, 2]);
+        std::mem::drop(v);
+        assert_eq!(*map.load(&1).unwrap(), [1, 2]);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let mut v = map.get_mut(&1).unwrap();
+            v.clear();
+            panic!("halfway");
+        }));
+        assert!(res.is_err());
+        assert_eq!(*map.load(&1).unwrap(), [1, 2]);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        map.get_mut(&1).unwrap().push(0);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.load(&1).unwrap().len(), 402);
+    }
+
+    #[test]
+    fn entry_many_expunged() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.store(2, 2);
+        map.range(|_, _| true);
+        map.remove(&1);
+        // Copies the read map into a new dirty map, expunging 1.
+        map.store(3, 3);
+
+        let [a, b] = map.entry_many([1, 2]);
+        assert!(matches!(a, MapEntry::Vacant(_)));
+        a.or_insert(10);
+        std::mem::drop(b);
+        assert_eq!(*map.load(&1).unwrap(), 10);
+    }
+
+    #[test]
+    fn get_mut_expunged() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.store(2, 2);
+        map.range(|_, _| true);
+        map.remove(&1);
+        // Copies the read map into a new dirty map, expunging 1.
+        map.store(3, 3);
+        assert!(map.get_mut(&1).is_none());
+
+        map.store(1, 10);
+        *map.get_mut(&1).unwrap() += 1;
+        assert_eq!(*map.load(&1).unwrap(), 11);
+    }
+
+    #[test]
+    fn snapshot_iter() {
+        let map: SyncMap<String, u64> = (0..100).map(|i| (i.to_string(), i)).collect();
+        let mut iter = map.snapshot_iter();
+        let first = iter.next().unwrap();
+
+        // Promotions and compactions underneath do not change the keys
+        // visited, nor free the ones already yielded.
+        for i in 100..200 {
+            map.store(i.to_string(), i);
+        }
+        map.range(|_, _| true);
+        for i in 0..50 {
+            map.remove(&i.to_string());
+        }
+        map.shrink_to_fit();
+        let rest: Vec<_> = iter.collect();
+        map.shrink_to_fit();
+        map.remove(first.key());
+        map.shrink_to_fit();
+
+        let mut keys: Vec<u64> = rest.iter().map(|r| *r.value()).collect();
+        keys.push(first.key().parse().unwrap());
+        keys.sort();
+        keys.dedup();
+        assert!(keys.iter().all(|&k| k < 100));
+        assert!((50..100).all(|k| keys.contains(&k)));
+        assert_eq!(keys.len(), rest.len() + 1);
+    }
+
+    #[test]
+    fn load_shared() {
+        let map = SyncMap::new();
+        map.store(1, Arc::new(String::from("a")));
+        let v = map.load_shared(&1).unwrap();
+        map.remove(&1);
+        assert_eq!(*v, "a");
+        assert!(map.load_shared(&1).is_none());
+    }
+
+    #[test]
+    fn panics() {
+        use std::{
+            cell::Cell,
+            hash::{Hash, Hasher},
+            panic::{catch_unwind, AssertUnwindSafe},
+        };
+
+        thread_local! {
+            static ARMED: Cell<bool> = const { Cell::new(false) };
+        }
+
+        // Hashing 13 panics while armed.
+        #[derive(PartialEq, Eq, Debug)]
+        struct Key(u64);
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                assert!(self.0 != 13 || !ARMED.get(), "hashing 13");
+                self.0.hash(state);
+            }
+        }
+
+        fn panics(f: impl FnOnce()) {
+            assert!(catch_unwind(AssertUnwindSafe(f)).is_err());
+        }
+
+        let map = SyncMap::new();
+        for i in 0..20 {
+            map.store(Key(i), i);
+        }
+        map.range(|_, _| true);
+        map.remove(&Key(5));
+
+        // Rebuilding the dirty map hashes every key.
+        ARMED.set(true);
+        panics(|| map.store(Key(100), 100));
+        ARMED.set(false);
+        map.store(Key(5), 5);
+        map.store(Key(100), 100);
+        assert_eq!(*map.load(&Key(5)).unwrap(), 5);
+
+        panics(|| map.range(|_, _| panic!("range")));
+        panics(|| {
+            map.update(&Key(1), |_| panic!("update"));
+        });
+        panics(|| map.retain(|k, _| k.0 < 10 || panic!("retain")));
+        panics(|| {
+            map.upsert(Key(2), || 0, |_| panic!("upsert"));
+        });
+        panics(|| {
+            map.entry(Key(3)).and_modify(|_| panic!("entry"));
+        });
+
+        // Nothing was left locked or half changed.
+        assert_eq!(*map.swap(Key(3), 30).unwrap(), 3);
+        assert_eq!(*map.update(&Key(1), |v| v + 1).unwrap(), 1);
+        assert_eq!(*map.load(&Key(2)).unwrap(), 2);
+        let mut len = 0;
+        map.range(|_, _| {
+            len += 1;
+            true
+        });
+        assert_eq!(len, 21);
+    }
+
+    #[test]
+    fn panicking_hook() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let map = SyncMapBuilder::new()
+            .on_evict(|_: &u64, v: &String| assert!(v != "b"))
+            .build_bounded(1, crate::policy::Fifo::new());
+        map.store(1, String::from("a"));
+        map.store(2, String::from("b"));
+        assert!(catch_unwind(AssertUnwindSafe(|| map.store(3, String::from("c")))).is_err());
+        map.store(4, String::from("d"));
+        assert_eq!(*map.load(&4).unwrap(), "d");
+    }
+
+    #[test]
+    fn increment() {
+        use std::sync::atomic::{AtomicI32, AtomicU64};
+
+        let map: SyncMap<u64, AtomicU64> = SyncMap::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1000 {
+                        map.increment(i % 10, 1);
+                    }
+                });
+            }
+        });
+        for i in 0..10 {
+            assert_eq!(map.load(&i).unwrap().load(Ordering::Relaxed), 400);
+        }
+        assert_eq!(map.fetch_add(0, 5), 400);
+        assert_eq!(map.increment(0, 5), 410);
+
+        let map: SyncMap<&str, AtomicI32> = SyncMap::new();
+        assert_eq!(map.increment("a", -1), -1);
+        assert_eq!(map.fetch_update("a", |v| (v < 0).then_some(0)), Ok(-1));
+        assert_eq!(map.fetch_update("a", |v| (v < 0).then_some(0)), Err(0));
+        assert_eq!(map.fetch_update("b", |v| Some(v + 2)), Ok(0));
+        assert_eq!(map.fetch_add("b", 0), 2);
+    }
+
+    #[test]
+    fn get_key_value() {
+        let map = SyncMap::new();
+        let key: Arc<str> = Arc::from("a");
+        map.store(key.clone(), 1);
+        assert!(!map.is_promoted("a"));
+
+        let pair = map.get_key_value("a").unwrap();
+        assert!(Arc::ptr_eq(pair.key(), &key));
+        assert_eq!(*pair.value(), 1);
+        assert!(map.is_promoted("a"));
+        assert!(map.get_key_value("b").is_none());
+
+        map.remove("a");
+        assert!(map.get_key_value("a").is_none());
+    }
+
+    #[test]
+    fn load_many() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.load(&0);
+        map.store(2, 2);
+        map.store(3, 3);
+        assert!(map.is_promoted(&1));
+        assert!(!map.is_promoted(&2));
+
+        let values = map.load_many(&[1, 2, 4, 3]);
+        let values: Vec<_> = values.iter().map(|v| v.as_deref().copied()).collect();
+        assert_eq!(values, [Some(1), Some(2), None, Some(3)]);
+    }
+
+    #[test]
+    fn store_many() {
+        let map = SyncMap::new();
+        map.store(1, 0);
+        map.load(&0);
+        map.store(2, 0);
+        map.store_many([(1, 1), (2, 2), (3, 3), (3, 4)]);
+        for (k, v) in [(1, 1), (2, 2), (3, 4)] {
+            assert_eq!(*map.load(&k).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn try_ops() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.load(&0);
+        assert!(map.is_promoted(&1));
+
+        {
+            let _dirty = map.dirty.lock();
+            // Read map hits need no lock.
+            assert_eq!(*map.try_load(&1).unwrap().unwrap(), 1);
+            assert!(map.try_store(1, 2).is_ok());
+            assert_eq!(*map.try_remove(&1).unwrap().unwrap(), 2);
+            assert_eq!(map.try_store(2, 2).unwrap_err().0, (2, 2));
+        }
+        assert!(map.try_store(2, 2).is_ok());
+        {
+            let _dirty = map.dirty.lock();
+            assert!(map.try_load(&2).is_err());
+            assert!(map.try_remove(&2).is_err());
+        }
+        assert_eq!(*map.try_load(&2).unwrap().unwrap(), 2);
+
+        let MapEntry::Occupied(_locked) = map.entry(2) else {
+            panic!("2 is present");
+        };
+        assert!(map.try_store(2, 3).is_err());
+        assert!(map.try_remove(&2).is_err());
+        assert_eq!(*map.try_load(&2).unwrap().unwrap(), 2);
+    }
+
+    #[test]
+    fn try_errors() {
+        let map = SyncMapBuilder::new()
+            .max_dirty_len(2, DirtyOverflow::Reject)
+            .build();
+        assert!(map.try_swap(1, 1).unwrap().is_none());
+        assert_eq!(*map.try_swap(1, 10).unwrap().unwrap(), 1);
+        assert!(map.try_swap(2, 2).is_ok());
+        assert!(matches!(map.try_swap(3, 3), Err(Error::Full((3, 3)))));
+        assert!(matches!(
+            map.try_get_or_insert_with(3, || Ok::<_, ()>(3)),
+            Err(Error::Full(3))
+        ));
+        assert_eq!(
+            *map.try_get_or_insert_with(1, || Err("unused")).unwrap(),
+            10
+        );
+
+        // Promotes, making room for a new key.
+        map.store(4, 4);
+        assert!(matches!(
+            map.try_get_or_insert_with(3, || Err("down")),
+            Err(Error::ValueInitFailed("down"))
+        ));
+        assert_eq!(
+            *map.try_get_or_insert_with(3, || Ok::<_, ()>(3)).unwrap(),
+            3
+        );
+        {
+            let _dirty = map.dirty.lock();
+            let err = map.try_swap(5, 5).unwrap_err();
+            assert!(matches!(err, Error::WouldBlock(_)));
+            assert_eq!(err.into_inner(), Some((5, 5)));
+            let err = map.try_get_or_insert_with(5, || Ok::<_, ()>(5));
+            assert!(matches!(err, Err(Error::WouldBlock(5))));
+        }
+
+        let err: Error<(u64, u64)> = Full((5, 5)).into();
+        assert_eq!(err.to_string(), "the dirty map is full");
+    }
+
+    #[test]
+    fn get_or_try_insert_with() {
+        let map = SyncMap::new();
+        let calls = AtomicUsize::new(0);
+        assert_eq!(
+            map.get_or_try_insert_with(1, || Err("unavailable"))
+                .unwrap_err(),
+            "unavailable"
+        );
+        assert!(map.load(&1).is_none());
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let v = map.get_or_try_insert_with(1, || {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(std::time::Duration::from_millis(10));
+                        Ok::<_, ()>(String::from("a"))
+                    });
+                    assert_eq!(*v.unwrap(), "a");
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn with_hasher() {
+        type Hasher = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+
+        let map: SyncMap<u64, u64, Hasher> = SyncMap::with_hasher(Hasher::default());
+        for i in 0..100 {
+            map.store(i, i);
+        }
+        map.range(|_, _| true);
+        for i in 0..100 {
+            assert_eq!(*map.load(&i).unwrap(), i);
+        }
+
+        let map: SyncMap<u64, u64, Hasher> = SyncMap::default();
+        map.store(1, 1);
+        assert_eq!(*map.load(&1).unwrap(), 1);
+    }
+
+    #[test]
+    fn with_capacity() {
+        let map = SyncMap::with_capacity(1024);
+        map.store(0, 0);
+        assert!(map.dirty.lock().as_ref().unwrap().capacity() >= 1024);
+
+        // Promote, then make sure the next dirty map is pre-sized as well.
+        map.range(|_, _| true);
+        assert!(map.dirty.lock().is_none());
+        map.store(1, 1);
+        assert!(map.dirty.lock().as_ref().unwrap().capacity() >= 1024);
+    }
+
+    #[test]
+    fn reserve() {
+        let map = SyncMap::new();
+        map.store(0, 0);
+        map.reserve(4096);
+        assert!(map.dirty.lock().as_ref().unwrap().capacity() >= 4097);
+
+        map.range(|_, _| true);
+        map.store(1, 1);
+        assert!(map.dirty.lock().as_ref().unwrap().capacity() >= 4097);
+        assert_eq!(*map.load(&0).unwrap(), 0);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let map = SyncMap::new();
+        for i in 0..1000 {
+            map.store(i, i);
+        }
+        map.range(|_, _| true);
+        for i in 0..990 {
+            map.remove(&i);
+        }
+        assert_eq!(map.load_readonly(&reclaim::pin()).m.len(), 1000);
+
+        map.shrink_to_fit();
+        assert_eq!(map.load_readonly(&reclaim::pin()).m.len(), 10);
+        assert!(map.dirty.lock().is_none());
+        assert!(map.load(&0).is_none());
+        assert_eq!(*map.load(&995).unwrap(), 995);
+
+        map.store(0, 1);
+        map.remove(&995);
+        map.shrink_to_fit();
+        assert_eq!(map.load_readonly(&reclaim::pin()).m.len(), 10);
+        assert_eq!(*map.load(&0).unwrap(), 1);
+
+        // Removals before a shrink no longer count towards compaction.
+        let map = SyncMapBuilder::new().compaction_ratio(0.5).build();
+        for i in 0..10 {
+            map.store(i, i);
+        }
+        map.range(|_, _| true);
+        for i in 0..4 {
+            map.remove(&i);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.deleted.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "persist")]
+    #[test]
+    fn snapshot_persist() {
+        let map: SyncMap<u64, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        map.remove(&7);
+        map.store(100, String::from("new"));
+
+        let mut bytes = Vec::new();
+        map.write_snapshot(&mut bytes).unwrap();
+        let restored: SyncMap<u64, String> = SyncMap::read_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(restored.snapshot(), map.snapshot());
+        assert!(restored.dirty.lock().is_none());
+
+        let err = SyncMap::<u64, String>::read_snapshot(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn from_iter() {
+        let map: SyncMap<_, _> = (0..100).map(|i| (i, i * 10)).collect();
+        assert!(map.dirty.lock().is_none());
+        assert!(!map
+            .load_readonly(&reclaim::pin())
+            .amended
+            .load(Ordering::Relaxed));
+        for i in 0..100 {
+            assert_eq!(*map.load(&i).unwrap(), i * 10);
+        }
+
+        let m: HashMap<_, _> = (0..10).map(|i| (i, i)).collect();
+        let map = SyncMap::from(m);
+        assert_eq!(*map.load(&9).unwrap(), 9);
+    }
+
+    #[test]
+    fn extend() {
+        let mut map = SyncMap::new();
+        map.store(1, 1);
+        map.store(2, 2);
+        map.remove(&2);
+        map.store(3, 3);
+        for i in 0..100 {
+            map.store(1, i);
+        }
+
+        map.extend([(1, 10), (4, 40)]);
+        map.extend([(&5, &50)]);
+        assert!(map.dirty.lock().is_none());
+        {
+            let guard = reclaim::pin();
+            let read = map.load_readonly(&guard);
+            assert_eq!(read.m.len(), 4);
+            assert!(!read.m.contains_key(Query(&2).as_dyn()));
+        }
+        assert_eq!(*map.load(&1).unwrap(), 10);
+        assert_eq!(*map.load(&3).unwrap(), 3);
+        assert_eq!(*map.load(&5).unwrap(), 50);
+
+        // The map keeps working concurrently afterwards.
+        map.store(2, 20);
+        assert_eq!(*map.load(&2).unwrap(), 20);
+    }
+
+    #[test]
+    fn iter() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i * 10);
+        }
+        map.remove(&3);
+
+        let mut seen: Vec<_> = map.iter().map(|r| (*r.key(), *r)).collect();
+        seen.sort();
+        let expected: Vec<_> = (0..10).filter(|&i| i != 3).map(|i| (i, i * 10)).collect();
+        assert_eq!(seen, expected);
+
+        // Items stay valid after the iterator and concurrent writes.
+        let items: Vec<_> = (&map).into_iter().collect();
+        for i in 0..10 {
+            map.store(i, 0);
+        }
+        for r in &items {
+            assert_eq!(*r.value(), r.key() * 10);
+        }
+    }
+
+    #[test]
+    fn into_iter() {
+        let dropped = AtomicUsize::new(0);
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, Counted(&dropped));
+        }
+        map.remove(&0);
+        map.store(1, Counted(&dropped));
+
+        // The removed and replaced values are dropped up front.
+        let mut iter = map.into_iter();
+        let (_, v) = iter.next().unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+        std::mem::drop(iter);
+        assert_eq!(dropped.load(Ordering::Relaxed), 10);
+        std::mem::drop(v);
+        assert_eq!(dropped.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn debug() {
+        let map = SyncMap::new();
+        assert_eq!(format!("{map:?}"), "{}");
+        map.store(1, "a");
+        map.store(2, "b");
+        map.remove(&2);
+        assert_eq!(format!("{map:?}"), r#"{1: "a"}"#);
+    }
+
+    #[test]
+    fn clone() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, vec![i]);
+        }
+        map.remove(&0);
+
+        let copy = map.clone();
+        map.store(1, vec![]);
+        assert!(copy.load(&0).is_none());
+        assert_eq!(*copy.load(&1).unwrap(), [1]);
+        assert_eq!(*copy.load(&9).unwrap(), [9]);
+        assert!(copy.dirty.lock().is_none());
+    }
+
+    #[test]
+    fn remove_all() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i);
+        }
+        map.load(&10);
+        map.store(10, 10);
+        map.remove(&0);
+
+        let mut removed: Vec<_> = map.remove_all().map(|(k, v)| (k, *v)).collect();
+        removed.sort();
+        assert_eq!(removed, (1..=10).map(|i| (i, i)).collect::<Vec<_>>());
+        assert!(map.iter().next().is_none());
+
+        map.store(1, 1);
+        map.store(2, 2);
+        let mut removed = map.remove_all();
+        removed.next();
+        std::mem::drop(removed);
+        assert!(map.load(&1).is_none());
+        assert!(map.load(&2).is_none());
+        map.store(1, 1);
+        assert_eq!(*map.load(&1).unwrap(), 1);
+    }
+
+    #[test]
+    fn remove_all_concurrent() {
+        let map = SyncMap::new();
+        let stored = AtomicUsize::new(0);
+        let removed = thread::scope(|s| {
+            for t in 0..4 {
+                let (map, stored) = (&map, &stored);
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.store(t * 1000 + i, ());
+                        stored.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+            let mut removed = 0;
+            while stored.load(Ordering::Relaxed) < 4000 {
+                removed += map.remove_all().count();
+            }
+            removed
+        });
+        // No store is lost to a concurrent removal.
+        assert_eq!(removed + map.remove_all().count(), 4000);
+    }
+
+    #[test]
+    fn drain() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i.to_string());
+        }
+        let held = map.load(&1).unwrap();
+        let mut drained: Vec<(i32, String)> = map.drain().collect();
+        drained.sort();
+        assert_eq!(
+            drained,
+            (0..10).map(|i| (i, i.to_string())).collect::<Vec<_>>()
+        );
+        assert_eq!(*held, "1");
+        assert!(map.iter().next().is_none());
+    }
+
+    #[test]
+    fn try_insert() {
+        let map = SyncMap::new();
+        assert!(map.try_insert(1, String::from("a")).is_ok());
+        let err = map.try_insert(1, String::from("b")).unwrap_err();
+        assert_eq!(*err.current, "a");
+        assert_eq!(err.value, "b");
+        assert_eq!(
+            err.to_string(),
+            r#"failed to insert "b", key already holds "a""#
+        );
+
+        // Present in the read map, and deleted.
+        map.load(&2);
+        map.remove(&1);
+        assert!(map.is_promoted(&1));
+        assert!(map.try_insert(1, String::from("c")).is_ok());
+        assert_eq!(*map.load(&1).unwrap(), "c");
+    }
+
+    #[test]
+    fn scan() {
+        let map = SyncMap::new();
+        for i in 0..100 {
+            map.store(i, i);
+        }
+
+        let mut scan = map.scan(30);
+        let mut seen: Vec<_> = scan.next().unwrap();
+        assert_eq!(seen.len(), 30);
+        // Writes between batches, including ones that promote and rebuild
+        // the dirty map.
+        for i in 0..100 {
+            if i % 2 == 0 {
+                map.remove(&i);
+            } else {
+                map.store(i, i + 1000);
+            }
+        }
+        map.store(100, 100);
+        map.range(|_, _| true);
+        seen.extend(scan.flatten());
+
+        let mut keys: Vec<_> = seen.iter().map(|(k, _)| *k).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), seen.len());
+        assert!(!keys.contains(&100));
+        for i in (1..100).step_by(2) {
+            assert!(keys.contains(&i));
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let observed = SyncMap::new();
+        let desired = SyncMap::new();
+        for i in 0..4 {
+            observed.store(i, i);
+        }
+        assert_eq!(desired.diff(&observed).removed.len(), 4);
+        desired.store(1, 1);
+        desired.store(2, 20);
+        desired.store(3, 3);
+        desired.store(4, 4);
+        // Deleted keys are absent from both sides.
+        desired.store(5, 5);
+        desired.remove(&5);
+        observed.store(6, 6);
+        observed.remove(&6);
+
+        let mut diff = desired.diff(&observed);
+        diff.removed.sort();
+        assert_eq!(diff.added, [(4, 4)]);
+        assert_eq!(diff.removed, [(0, 0)]);
+        assert_eq!(diff.changed, [(2, 2, 20)]);
+        assert_eq!(desired.diff_snapshot(&observed.snapshot()), diff);
+        assert!(desired.diff(&desired.clone()).is_empty());
+    }
+
+    #[test]
+    fn snapshot() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i);
+        }
+        map.remove(&0);
+
+        let snapshot = map.snapshot();
+        map.store(1, 0);
+        map.store(10, 10);
+        assert_eq!(snapshot.len(), 9);
+        assert_eq!(snapshot[&1], 1);
+        assert!(!snapshot.contains_key(&10));
+        assert!(map.is_promoted(&9));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.load(&2);
+        assert_eq!(
+            map.stats(),
+            crate::stats::Stats {
+                misses: 1,
+                promotions: 1,
+                ..Default::default()
+            }
+        );
+
+        map.store(2, 2);
+        map.store(3, 3);
+        map.load(&2);
+        map.load(&1);
+        map.load(&1);
+        assert_eq!(
+            map.stats(),
+            crate::stats::Stats {
+                read_hits: 2,
+                dirty_hits: 1,
+                misses: 1,
+                promotions: 1,
+                dirty_len: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn ref_map() {
+        let map = SyncMap::new();
+        map.store(1, (1, String::from("a")));
+        let r = Ref::map(map.load(&1).unwrap(), |v| &v.1);
+        map.store(1, (2, String::from("b")));
+        assert_eq!(*r, "a");
+    }
+
+    #[test]
+    fn bounded() {
+        let map = SyncMap::bounded(10);
+        for i in 0..100 {
+            map.store(i, i);
+            map.load(&(i / 2));
+        }
+        let mut len = 0;
+        map.range(|_, _| {
+            len += 1;
+            true
+        });
+        assert_eq!(len, 10);
+
+        // An evicted key can be stored again.
+        let evicted = (0..100).find(|i| map.load(i).is_none()).unwrap();
+        map.store(evicted, 0);
+        assert_eq!(*map.load(&evicted).unwrap(), 0);
+    }
+
+    #[test]
+    fn bounded_writes_touch() {
+        // Samples every key, so the least recently used one is evicted.
+        let map = SyncMapBuilder::new().build_bounded(3, SampledLru::new(1000));
+        for i in 0..3 {
+            map.store(i, 0);
+        }
+        map.upsert(0, || 0, |v| v + 1);
+        map.store(3, 0);
+        assert_eq!(*map.load(&0).unwrap(), 1);
+        assert!(map.load(&1).is_none());
+
+        let map = SyncMapBuilder::new().build_bounded(3, SampledLru::new(1000));
+        for i in 0..3 {
+            map.store(i, 0);
+        }
+        map.update(&0, |v| v + 1);
+        map.store(3, 0);
+        assert_eq!(*map.load(&0).unwrap(), 1);
+        assert!(map.load(&1).is_none());
+    }
+
+    #[test]
+    fn bounded_fifo() {
+        let map = SyncMapBuilder::new().build_bounded(3, crate::policy::Fifo::new());
+        for i in 0..5 {
+            map.store(i, i);
+        }
+        assert!(map.load(&0).is_none());
+        assert!(map.load(&1).is_none());
+        for i in 2..5 {
+            assert_eq!(*map.load(&i).unwrap(), i);
+        }
+
+        // A locked key is skipped.
+        let e = map.entry(2);
+        map.store(5, 5);
+        std::mem::drop(e);
+        assert!(map.load(&2).is_some());
+        assert!(map.load(&3).is_none());
+    }
+
+    #[test]
+    fn bounded_concurrent() {
+        let map = SyncMap::bounded(16);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.store(t * 1000 + i, i);
+                        map.load(&(t * 1000 + i / 2));
+                    }
+                });
+            }
+        });
+        assert!(map.iter().count() <= 16);
+    }
+
+    #[test]
+    fn par_range() {
+        let map = SyncMap::new();
+        for i in 0..10_000u64 {
+            map.store(i, i);
+        }
+        map.remove(&0);
+
+        let total = AtomicU64::new(0);
+        let count = AtomicUsize::new(0);
+        map.par_range(|_, v| {
+            total.fetch_add(*v, Ordering::Relaxed);
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(total.into_inner(), (0..10_000).sum());
+        assert_eq!(count.into_inner(), 9_999);
+
+        map.par_retain(|k, _| k % 2 == 0);
+        for i in 0..10_000 {
+            assert_eq!(map.load(&i).is_some(), i != 0 && i % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn load_or_store() {
+        let map = SyncMap::new();
+        let (v, loaded) = map.load_or_store(1, 10);
+        assert_eq!((*v, loaded), (10, false));
+        let (v, loaded) = map.load_or_store(1, 20);
+        assert_eq!((*v, loaded), (10, true));
+    }
+
+    #[test]
+    fn remove() {
+        let map = SyncMap::new();
+        map.store(1, 10);
+        assert_eq!(map.remove(&1).as_deref(), Some(&10));
+        assert!(map.remove(&1).is_none());
+        assert!(map.load(&1).is_none());
+
+        // Promote 1 to the read map, soft delete it, get it expunged by the
+        // next dirty map copy and finally revive it.
+        map.store(1, 10);
+        map.load(&1);
+        assert_eq!(map.remove(&1).as_deref(), Some(&10));
+        map.store(2, 20);
+        map.store(1, 11);
+        assert_eq!(*map.load(&1).unwrap(), 11);
+        assert_eq!(*map.load(&2).unwrap(), 20);
+    }
+
+    #[test]
+    fn range() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i * 10);
+        }
+        map.remove(&3);
+
+        let mut seen = Vec::new();
+        map.range(|k, v| {
+            seen.push((*k, *v));
+            true
+        });
+        seen.sort();
+        let expected: Vec<_> = (0..10).filter(|&i| i != 3).map(|i| (i, i * 10)).collect();
+        assert_eq!(seen, expected);
+
+        let mut count = 0;
+        map.range(|_, _| {
+            count += 1;
+            false
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn retain() {
+        let map = SyncMap::new();
+        for i in 0..10 {
+            map.store(i, i);
+        }
+
+        // The closure runs without the lock held, so it may use the map.
+        map.retain(|k, _| {
+            map.store(k + 100, 0);
+            k % 2 == 0
+        });
+        for i in 0..10 {
+            assert_eq!(map.load(&i).as_deref(), (i % 2 == 0).then_some(&i));
+            assert!(map.load(&(i + 100)).is_some());
+        }
+    }
+
+    #[test]
+    fn retain_keeps_concurrent_store() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.retain(|k, _| {
+            map.store(*k, 2);
+            false
+        });
+        assert_eq!(*map.load(&1).unwrap(), 2);
+    }
+
+    #[test]
+    fn update() {
+        let map = SyncMap::new();
+        assert!(map.update(&1, |v| v + 1).is_none());
+
+        map.store(1, 0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        map.update(&1, |v| v + 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(*map.load(&1).unwrap(), 4000);
+        assert_eq!(*map.update(&1, |_| 0).unwrap(), 4000);
+    }
+
+    #[test]
+    fn upsert() {
+        let map = SyncMap::new();
+        assert_eq!(*map.upsert(1, || 1, |v| v + 1), 1);
+        assert_eq!(*map.upsert(1, || 1, |v| v + 1), 2);
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.upsert(i % 16 + t, || 1, |v| v + 1);
+                    }
+                });
+            }
+        });
+        let mut total = 0;
+        map.range(|_, v| {
+            total += v;
+            true
+        });
+        assert_eq!(total, 4002);
+    }
+
+    #[test]
+    fn entry() {
+        let map = SyncMap::new();
+        assert_eq!(*map.entry(1).or_insert(10), 10);
+        assert_eq!(*map.entry(1).or_insert(20), 10);
+        assert_eq!(*map.entry(1).and_modify(|v| v + 1).or_insert(0), 11);
+        assert_eq!(*map.entry(2).and_modify(|v| v + 1).or_default(), 0);
+
+        match map.entry(1) {
+            MapEntry::Occupied(mut e) => {
+                assert_eq!((*e.key(), *e.get()), (1, 11));
+                assert_eq!(*e.insert(12), 11);
+                assert_eq!(*e.remove(), 12);
+            }
+            MapEntry::Vacant(_) => unreachable!(),
+        }
+        match map.entry(1) {
+            MapEntry::Occupied(_) => unreachable!(),
+            MapEntry::Vacant(e) => assert_eq!(e.into_key(), 1),
+        }
+        assert!(map.load(&1).is_none());
+        assert!(map.load(&3).is_none());
+        map.range(|k, _| {
+            assert_eq!(*k, 2);
+            true
+        });
+    }
+
+    #[test]
+    fn entry_is_atomic() {
+        let map = SyncMap::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1000 {
+                        map.entry(i % 8).and_modify(|v| v + 1).or_insert(1);
+                        if i % 3 == 0 {
+                            map.store(100, i);
+                        }
+                    }
+                });
+            }
+        });
+        let mut total = 0;
+        map.range(|k, v| {
+            if *k != 100 {
+                total += v;
+            }
+            true
+        });
+        assert_eq!(total, 4000);
+    }
+
+    #[test]
+    fn entry_blocks_writers() {
+        let map = SyncMap::new();
+        map.store(1, 0);
+        thread::scope(|s| {
+            let e = map.entry(1);
+            let writer = s.spawn(|| map.swap(1, 2).map(|v| *v));
+            thread::sleep(std::time::Duration::from_millis(20));
+            // Readers are not blocked.
+            assert_eq!(*map.load(&1).unwrap(), 0);
+            e.and_modify(|v| v + 1);
+            assert_eq!(writer.join().unwrap(), Some(1));
+        });
+        assert_eq!(*map.load(&1).unwrap(), 2);
+    }
+
+    #[test]
+    fn entry_many() {
+        let map = SyncMap::bounded(8);
+        for i in 0..4 {
+            map.store(i, 100);
+        }
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        // Move one unit from an account to the next, in both
+                        // lock orders.
+                        let (from, to) = (i % 4, (i + t + 1) % 4);
+                        if from == to {
+                            continue;
+                        }
+                        let [MapEntry::Occupied(mut a), MapEntry::Occupied(mut b)] =
+                            map.entry_many([from, to])
+                        else {
+                            unreachable!();
+                        };
+                        let (x, y) = (*a.get(), *b.get());
+                        a.insert(x - 1);
+                        b.insert(y + 1);
+                    }
+                });
+            }
+        });
+        let mut total = 0;
+        map.range(|_, v| {
+            total += v;
+            true
+        });
+        assert_eq!(total, 400);
+
+        let [a, b] = map.entry_many([4, 0]);
+        assert!(matches!(a, MapEntry::Vacant(_)));
+        assert!(matches!(b, MapEntry::Occupied(_)));
+        if let MapEntry::Vacant(a) = a {
+            a.insert(1);
+        }
+        std::mem::drop(b);
+        assert_eq!(*map.load(&4).unwrap(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn entry_many_duplicate() {
+        let map = SyncMap::<u64, u64>::new();
+        map.entry_many([1, 2, 1]);
+    }
+
+    #[test]
+    fn transaction() {
+        let map = SyncMap::new();
+        map.store(1, "a");
+        map.store(2, "b");
+        let n = map.transaction(|txn| {
+            txn.store(1, "c");
+            txn.store(3, "d");
+            txn.remove(3);
+            txn.store(4, "e");
+            txn.remove(2);
+            txn.remove(5);
+            42
+        });
+        assert_eq!(n, 42);
+        assert_eq!(*map.load(&1).unwrap(), "c");
+        assert!(map.load(&2).is_none());
+        assert!(map.load(&3).is_none());
+        assert_eq!(*map.load(&4).unwrap(), "e");
+        map.store(2, "f");
+        assert_eq!(*map.load(&2).unwrap(), "f");
+    }
+
+    #[test]
+    fn transaction_replaced_entry() {
+        let map = SyncMap::new();
+        map.store(1, 1);
+        map.range(|_, _| true);
+        // Looks the entry up before the transaction replaces it.
+        let mut e = map.entry_ref(1);
+        map.transaction(|txn| txn.store(1, 2));
+        e.store(3);
+        assert_eq!(*map.load(&1).unwrap(), 3);
+    }
+
+    #[test]
+    fn transaction_is_atomic() {
+        let map = SyncMap::new();
+        for i in 0..8 {
+            map.store(i, 0);
+        }
+        thread::scope(|s| {
+            s.spawn(|| {
+                for generation in 1..=500 {
+                    map.transaction(|txn| {
+                        for i in 0..8 {
+                            txn.store(i, generation);
+                        }
+                    });
+                }
+            });
+            for _ in 0..2 {
+                s.spawn(|| {
+                    // A reader that has seen a generation never sees an
+                    // older one afterwards.
+                    for _ in 0..500 {
+                        let mut seen = 0;
+                        for i in 0..8 {
+                            let v = *map.load(&i).unwrap();
+                            assert!(v >= seen);
+                            seen = v;
+                        }
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..500 {
+                    map.store(100, 0);
+                }
+            });
+        });
+        assert_eq!(*map.load(&7).unwrap(), 500);
+    }
+
+    #[test]
+    fn transaction_hooks() {
+        use std::sync::Mutex;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (stored, removed) = (log.clone(), log.clone());
+        let map = SyncMapBuilder::new()
+            .on_insert(move |k: &String, v: &u64| stored.lock().unwrap().push((k.clone(), *v)))
+            .on_remove(move |k: &String, _: &u64| removed.lock().unwrap().push((k.clone(), 0)))
+            .build();
+        map.store(String::from("a"), 1);
+        map.transaction(|txn| {
+            txn.store(String::from("b"), 2);
+            txn.remove(String::from("a"));
+        });
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                (String::from("a"), 1),
+                (String::from("b"), 2),
+                (String::from("a"), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let map = SyncMap::new();
+        assert!(!map.compare_and_swap(&1, &1, 2));
+
+        map.store(1, 1);
+        assert!(!map.compare_and_swap(&1, &2, 3));
+        assert!(map.compare_and_swap(&1, &1, 2));
+        assert_eq!(*map.load(&1).unwrap(), 2);
+
+        assert!(!map.compare_and_remove(&1, &1));
+        assert!(map.compare_and_remove(&1, &2));
+        assert!(map.load(&1).is_none());
+    }
+
+    #[test]
+    fn replaced_value_outlives_ref() {
+        let map = SyncMap::new();
+        map.store(1, String::from("old"));
+        let old = map.load(&1).unwrap();
+        for i in 0..1000 {
+            map.store(1, i.to_string());
+        }
+        assert_eq!(*old, "old");
+        assert_eq!(*map.swap(1, String::from("new")).unwrap(), "999");
+    }
+
+    #[test]
+    fn concurrent() {
+        let map = SyncMap::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.store(i % 64, t * 1000 + i);
+                        if let Some(v) = map.load(&(i % 64)) {
+                            assert!(*v < 4000);
+                        }
+                        if i % 7 == 0 {
+                            map.remove(&(i % 64));
+                        }
+                    }
+                });
+            }
+        });
+
+        map.range(|k, v| {
+            assert!(*k < 64 && *v < 4000);
+            true
+        });
+    }
+
+    // Interleaves loads, stores and removes with the promotions and dirty
+    // map rebuilds that a steady stream of new keys causes.
+    #[test]
+    fn interleavings() {
+        let map = SyncMap::new();
+        thread::scope(|s| {
+            for t in 0..4u64 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..5000 {
+                        let k = (i * 4 + t) % 512;
+                        match i % 4 {
+                            0 | 1 => map.store(k, k.to_string()),
+                            2 => {
+                                map.remove(&k);
+                            }
+                            _ => {}
+                        }
+                        for k in [k, (k + 1) % 512, i % 512] {
+                            if let Some(v) = map.load(&k) {
+                                assert_eq!(*v, k.to_string());
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        map.range(|k, v| {
+            assert_eq!(*v, k.to_string());
+            true
+        });
+    }
 
     #[test]
     fn drop() {
@@ -206,6 +5352,6 @@ mod tests {
         let s = String::from("this will put on the heap");
         let e = super::Entry::new(s);
 
-        map.insert(1, Rc::new(e));
+        map.insert(1, Arc::new(e));
     }
 }
@@ -0,0 +1,208 @@
+//! A concurrent multimap built on [`SyncMap`].
+use std::{borrow::Borrow, collections::hash_map::RandomState, fmt, hash::BuildHasher};
+
+use parking_lot::Mutex;
+
+use crate::map::{MapEntry, SyncMap};
+
+/// A concurrent map from each key to the list of values appended to it, e.g.
+/// to index events by correlation ID.
+///
+/// Appending locks the key's entry, as [`SyncMap::entry`] does, so it is
+/// ordered with the other writes to the key: a value appended concurrently
+/// with [`remove`] is either removed with the others or starts a new list.
+/// Readers only lock the list they copy, never the map.
+///
+/// [`remove`]: SyncMultiMap::remove
+pub struct SyncMultiMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<K, Mutex<Vec<V>>, S>,
+}
+
+impl<K, V> SyncMultiMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new() -> Self {
+        SyncMultiMap::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V> Default for SyncMultiMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        SyncMultiMap::new()
+    }
+}
+
+impl<K, V, S> SyncMultiMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        SyncMultiMap {
+            map: SyncMap::with_hasher(hash_builder),
+        }
+    }
+
+    /// Appends a value to the list for a key, creating it if needed.
+    pub fn append(&self, key: K, value: V) {
+        match self.map.entry(key) {
+            MapEntry::Occupied(e) => e.get().lock().push(value),
+            MapEntry::Vacant(e) => {
+                e.insert(Mutex::new(vec![value]));
+            }
+        }
+    }
+
+    /// Returns a copy of the values appended to a key, in the order they
+    /// were appended, or an empty list if there is none.
+    pub fn load_all<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+        V: Clone,
+    {
+        self.map
+            .load(key)
+            .map_or_else(Vec::new, |values| values.lock().clone())
+    }
+
+    /// Returns how many values are appended to a key.
+    pub fn count<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.load(key).map_or(0, |values| values.lock().len())
+    }
+
+    /// Returns whether any value is appended to a key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.contains_key(key)
+    }
+
+    /// Removes a key, returning the values appended to it.
+    pub fn remove<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        // Deleting waits for any append holding the entry, and later appends
+        // start a new list, so the removed one can be emptied.
+        self.map
+            .remove(key)
+            .map_or_else(Vec::new, |values| std::mem::take(&mut *values.lock()))
+    }
+
+    /// Calls `f` sequentially for each key and its values. If `f` returns
+    /// false, range stops the iteration. See [`SyncMap::range`].
+    ///
+    /// Each list is locked while `f` runs, so `f` must not append to its key.
+    pub fn range(&self, mut f: impl FnMut(&K, &[V]) -> bool) {
+        self.map.range(|k, values| f(k, &values.lock()));
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for SyncMultiMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = SyncMultiMap::with_hasher(S::default());
+        for (k, v) in iter {
+            map.append(k, v);
+        }
+        map
+    }
+}
+
+impl<K, V, S> fmt::Debug for SyncMultiMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        self.range(|k, values| {
+            m.entry(k, &values);
+            true
+        });
+        m.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn append() {
+        let map = SyncMultiMap::new();
+        assert!(map.load_all("a").is_empty());
+        map.append("a", 1);
+        map.append("a", 2);
+        map.append("b", 3);
+        assert_eq!(map.load_all("a"), [1, 2]);
+        assert_eq!(map.count("b"), 1);
+        assert!(map.contains_key("a"));
+
+        assert_eq!(map.remove("a"), [1, 2]);
+        assert!(map.remove("a").is_empty());
+        assert!(!map.contains_key("a"));
+        map.append("a", 4);
+        assert_eq!(map.load_all("a"), [4]);
+        map.remove("b");
+        assert_eq!(format!("{map:?}"), r#"{"a": [4]}"#);
+    }
+
+    #[test]
+    fn from_iter() {
+        let map: SyncMultiMap<_, _> = [(1, "a"), (2, "b"), (1, "c")].into_iter().collect();
+        assert_eq!(map.load_all(&1), ["a", "c"]);
+        let mut total = 0;
+        map.range(|_, values| {
+            total += values.len();
+            true
+        });
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn concurrent() {
+        let map = SyncMultiMap::new();
+        let removed: usize = thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.append(i % 10, t * 1000 + i);
+                    }
+                });
+            }
+            let remover = s.spawn(|| (0..100).map(|i| map.remove(&(i % 10)).len()).sum());
+            remover.join().unwrap()
+        });
+
+        // Every value is either removed or still there, exactly once.
+        let mut left = 0;
+        map.range(|_, values| {
+            left += values.len();
+            true
+        });
+        assert_eq!(removed + left, 4000);
+    }
+}
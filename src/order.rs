@@ -0,0 +1,42 @@
+//! The memory orderings of the map's atomics.
+//!
+//! A [`SyncMap`] publishes data through two kinds of pointers:
+//!
+//! - The read map pointer is only swapped with the dirty lock held, and the
+//!   swap releases the freshly built read map. Readers load it with acquire
+//!   ordering, which makes its contents visible without taking the lock.
+//!   The old read map is retired rather than freed, so a reader that loaded
+//!   it a moment earlier can keep using it.
+//! - An entry pointer is the only source of truth for its value. Every
+//!   store of a value is a release (a compare and swap with acquire-release
+//!   ordering, so that the value it replaces can be read and retired), and
+//!   every load that dereferences it is an acquire. Taking the entry lock
+//!   is an acquire and releasing it a release, as for any lock.
+//!
+//! Everything else needs no ordering of its own:
+//!
+//! - `amended` only decides whether a lookup falls back to the lock. A
+//!   reader that sees it set takes the lock, which orders it after the
+//!   insert that set it; one that sees it clear linearizes before that
+//!   insert.
+//! - `misses` is only read and written with the dirty lock held.
+//! - The bounded maps' clock and access stamps are heuristics.
+//!
+//! With the `seqcst` feature, every ordering here is strengthened to
+//! `SeqCst`, which helps tell a bug in this protocol apart from one elsewhere.
+//!
+//! [`SyncMap`]: crate::map::SyncMap
+use std::sync::atomic::Ordering;
+
+pub(crate) const RELAXED: Ordering = strengthen(Ordering::Relaxed);
+pub(crate) const ACQUIRE: Ordering = strengthen(Ordering::Acquire);
+pub(crate) const RELEASE: Ordering = strengthen(Ordering::Release);
+pub(crate) const ACQ_REL: Ordering = strengthen(Ordering::AcqRel);
+
+const fn strengthen(ordering: Ordering) -> Ordering {
+    if cfg!(feature = "seqcst") {
+        Ordering::SeqCst
+    } else {
+        ordering
+    }
+}
@@ -0,0 +1,216 @@
+//! A compact binary format for snapshots of a map, e.g. to restore a warm
+//! cache at process start.
+//!
+//! A snapshot is the magic bytes `SYNCMAP`, a format version byte, the number
+//! of entries and then each key followed by its value. Integers are fixed
+//! width little-endian, and lengths and `usize`s are written as `u64`.
+//! Strings and lists are prefixed with their length, and an `Option` with a
+//! byte that is 1 if a value follows.
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 7] = b"SYNCMAP";
+const VERSION: u8 = 1;
+
+// Lists are only preallocated up to this many items, so a corrupt length
+// fails at the end of the input rather than on a huge allocation.
+const MAX_PREALLOC: usize = 4096;
+
+/// A type that can be written to and read back from a snapshot, through
+/// [`SyncMap::write_snapshot`] and [`SyncMap::read_snapshot`].
+///
+/// [`SyncMap::write_snapshot`]: crate::map::SyncMap::write_snapshot
+/// [`SyncMap::read_snapshot`]: crate::map::SyncMap::read_snapshot
+pub trait Persist: Sized {
+    /// Writes the value.
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()>;
+
+    /// Reads back a value written by [`Persist::persist`]. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the bytes cannot be one.
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self>;
+}
+
+pub(crate) fn write_header<W: Write + ?Sized>(w: &mut W, len: usize) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    len.persist(w)
+}
+
+// Returns the number of entries that follow the header.
+pub(crate) fn read_header<R: Read + ?Sized>(r: &mut R) -> io::Result<usize> {
+    let mut magic = [0; 7];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a snapshot"));
+    }
+    match u8::restore(r)? {
+        VERSION => usize::restore(r),
+        _ => Err(invalid("unsupported snapshot version")),
+    }
+}
+
+pub(crate) fn prealloc(len: usize) -> usize {
+    len.min(MAX_PREALLOC)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+macro_rules! persist_int {
+    ($($int:ty),* $(,)?) => {
+        $(
+            impl Persist for $int {
+                fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+                    w.write_all(&self.to_le_bytes())
+                }
+
+                fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$int>()];
+                    r.read_exact(&mut bytes)?;
+                    Ok(<$int>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+persist_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Persist for usize {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u64).persist(w)
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        usize::try_from(u64::restore(r)?).map_err(|_| invalid("length out of range"))
+    }
+}
+
+impl Persist for isize {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        (*self as i64).persist(w)
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        isize::try_from(i64::restore(r)?).map_err(|_| invalid("isize out of range"))
+    }
+}
+
+impl Persist for bool {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u8).persist(w)
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        match u8::restore(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("invalid bool")),
+        }
+    }
+}
+
+impl Persist for char {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u32).persist(w)
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        char::from_u32(u32::restore(r)?).ok_or_else(|| invalid("invalid char"))
+    }
+}
+
+impl Persist for String {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.len().persist(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let bytes = Vec::<u8>::restore(r)?;
+        String::from_utf8(bytes).map_err(|_| invalid("invalid UTF-8"))
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.len().persist(w)?;
+        self.iter().try_for_each(|item| item.persist(w))
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let len = usize::restore(r)?;
+        let mut items = Vec::with_capacity(prealloc(len));
+        for _ in 0..len {
+            items.push(T::restore(r)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Some(value) => {
+                true.persist(w)?;
+                value.persist(w)
+            }
+            None => false.persist(w),
+        }
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        match bool::restore(r)? {
+            true => T::restore(r).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn persist<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.0.persist(w)?;
+        self.1.persist(w)
+    }
+
+    fn restore<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Ok((A::restore(r)?, B::restore(r)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Persist + PartialEq + std::fmt::Debug>(value: T) {
+        let mut bytes = Vec::new();
+        value.persist(&mut bytes).unwrap();
+        assert_eq!(T::restore(&mut bytes.as_slice()).unwrap(), value);
+    }
+
+    #[test]
+    fn values() {
+        round_trip(0xdead_beef_u32);
+        round_trip(-7_i64);
+        round_trip(usize::MAX);
+        round_trip('é');
+        round_trip(String::from("hello"));
+        round_trip(vec![Some((1_u8, true)), None]);
+
+        let mut bytes = Vec::new();
+        String::from("ab").persist(&mut bytes).unwrap();
+        assert_eq!(bytes, [2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+    }
+
+    #[test]
+    fn invalid_data() {
+        let err = bool::restore(&mut [2_u8].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = String::restore(&mut [1, 0, 0, 0, 0, 0, 0, 0, 0xff].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Vec::<u8>::restore(&mut [u8::MAX; 8].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_header(&mut b"NOTAMAP\x01".as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
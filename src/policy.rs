@@ -0,0 +1,316 @@
+//! When to promote the dirty map to the read map, and what a bounded map
+//! evicts.
+//!
+//! Every load that has to fall back to the dirty map counts as a miss. Once
+//! misses have cost about as much as copying the dirty map, it is promoted so
+//! that subsequent loads hit the read map again.
+//!
+//! A limit on the keys the dirty map gains between promotions keeps new keys
+//! from piling up behind the lock, where every load of them is a miss; see
+//! [`DirtyOverflow`].
+//!
+//! A bounded map evicts entries as soon as a new key takes its dirty map past
+//! the bound. The [`EvictionPolicy`] picks them.
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::BuildHasher,
+};
+
+use parking_lot::Mutex;
+
+/// Decides when the dirty map gets promoted.
+pub trait PromotionPolicy: Send + Sync {
+    /// Returns whether the dirty map, holding `dirty_len` entries, should be
+    /// promoted after `misses` loads since the last promotion had to consult
+    /// it.
+    ///
+    /// Called with the dirty lock held.
+    fn should_promote(&self, misses: u64, dirty_len: usize) -> bool;
+}
+
+/// Promotes once the misses reach `factor` times the size of the dirty map.
+///
+/// The default factor of 1 matches Go's `sync.Map`. A smaller factor promotes
+/// earlier, which suits read-mostly workloads; a larger one delays promotion,
+/// and with it the copy of the read map that the next new key triggers, which
+/// suits write-heavy workloads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MissThreshold {
+    factor: f64,
+}
+
+impl MissThreshold {
+    /// # Panics
+    ///
+    /// Panics if `factor` is negative or NaN.
+    pub fn new(factor: f64) -> Self {
+        assert!(factor >= 0.0, "miss threshold factor must be non-negative");
+        MissThreshold { factor }
+    }
+
+    pub fn factor(&self) -> f64 {
+        self.factor
+    }
+}
+
+impl Default for MissThreshold {
+    fn default() -> Self {
+        MissThreshold::new(1.0)
+    }
+}
+
+impl PromotionPolicy for MissThreshold {
+    fn should_promote(&self, misses: u64, dirty_len: usize) -> bool {
+        misses as f64 >= self.factor * dirty_len as f64
+    }
+}
+
+/// What a write does with a new key once the dirty map holds as many new
+/// keys as [`SyncMapBuilder::max_dirty_len`] allows.
+///
+/// [`SyncMapBuilder::max_dirty_len`]: crate::builder::SyncMapBuilder::max_dirty_len
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirtyOverflow {
+    /// Promotes the dirty map first, so that the new key goes into a fresh
+    /// one.
+    Promote,
+    /// Fails [`SyncMap::store_with_limit`] with [`Full`], and
+    /// [`SyncMap::try_swap`] and [`SyncMap::try_get_or_insert_with`] with
+    /// [`Error::Full`]. Writes that cannot fail promote instead.
+    ///
+    /// [`SyncMap::store_with_limit`]: crate::map::SyncMap::store_with_limit
+    /// [`SyncMap::try_swap`]: crate::map::SyncMap::try_swap
+    /// [`SyncMap::try_get_or_insert_with`]: crate::map::SyncMap::try_get_or_insert_with
+    /// [`Full`]: crate::map::Full
+    /// [`Error::Full`]: crate::error::Error::Full
+    Reject,
+}
+
+/// The entries of a bounded map's dirty map, as seen by an
+/// [`EvictionPolicy`].
+pub struct Candidates<'a, K> {
+    // The last access of a key on the map's clock, if the key is present.
+    accessed: &'a dyn Fn(&K) -> Option<u64>,
+    len: usize,
+}
+
+impl<'a, K> Candidates<'a, K> {
+    pub(crate) fn new(accessed: &'a dyn Fn(&K) -> Option<u64>, len: usize) -> Self {
+        Candidates { accessed, len }
+    }
+
+    /// Returns whether the key is in the map.
+    pub fn contains(&self, key: &K) -> bool {
+        (self.accessed)(key).is_some()
+    }
+
+    /// Returns when the key was last loaded or stored, on a clock that ticks
+    /// with every new key, or `None` if the key is not in the map.
+    pub fn last_access(&self, key: &K) -> Option<u64> {
+        (self.accessed)(key)
+    }
+
+    /// Returns the number of entries, deleted ones included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Decides which entries a bounded map evicts.
+///
+/// Both methods are called with the dirty lock held, so they never run
+/// concurrently with each other for the same map.
+pub trait EvictionPolicy<K>: Send + Sync {
+    /// Records that `key` was added to the map.
+    fn inserted(&self, key: &K);
+
+    /// Picks a key to evict, or `None` to keep the map over its bound.
+    ///
+    /// A key that turns out to be locked by a [`MapEntry`] is not evicted
+    /// and gets passed to [`inserted`] again.
+    ///
+    /// [`MapEntry`]: crate::map::MapEntry
+    /// [`inserted`]: EvictionPolicy::inserted
+    fn victim(&self, candidates: &Candidates<'_, K>) -> Option<K>;
+}
+
+/// Evicts keys in the order they were added.
+pub struct Fifo<K> {
+    queue: Mutex<VecDeque<K>>,
+}
+
+impl<K> Fifo<K> {
+    pub fn new() -> Self {
+        Fifo {
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl<K> Default for Fifo<K> {
+    fn default() -> Self {
+        Fifo::new()
+    }
+}
+
+impl<K: Clone + Send> EvictionPolicy<K> for Fifo<K> {
+    fn inserted(&self, key: &K) {
+        self.queue.lock().push_back(key.clone());
+    }
+
+    fn victim(&self, candidates: &Candidates<'_, K>) -> Option<K> {
+        let mut queue = self.queue.lock();
+        // Skip keys removed since they were queued.
+        while let Some(key) = queue.pop_front() {
+            if candidates.contains(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+/// Evicts the least recently used of a few keys picked at random, which
+/// approximates LRU without ordering every access.
+pub struct SampledLru<K> {
+    samples: usize,
+    state: Mutex<Sampler<K>>,
+}
+
+struct Sampler<K> {
+    // Every key added, plus some removed since, which are pruned as they get
+    // sampled.
+    keys: Vec<K>,
+    rng: u64,
+}
+
+impl<K> SampledLru<K> {
+    /// # Panics
+    ///
+    /// Panics if `samples` is zero.
+    pub fn new(samples: usize) -> Self {
+        assert!(samples > 0, "at least one key must be sampled");
+        SampledLru {
+            samples,
+            state: Mutex::new(Sampler {
+                keys: Vec::new(),
+                // xorshift must not start at zero.
+                rng: RandomState::new().hash_one(samples) | 1,
+            }),
+        }
+    }
+}
+
+/// Samples 5 keys per eviction.
+impl<K> Default for SampledLru<K> {
+    fn default() -> Self {
+        SampledLru::new(5)
+    }
+}
+
+impl<K> Sampler<K> {
+    fn next_index(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng % self.keys.len() as u64) as usize
+    }
+}
+
+impl<K: Clone + Send> EvictionPolicy<K> for SampledLru<K> {
+    fn inserted(&self, key: &K) {
+        self.state.lock().keys.push(key.clone());
+    }
+
+    fn victim(&self, candidates: &Candidates<'_, K>) -> Option<K> {
+        let mut state = self.state.lock();
+        let mut oldest: Option<(usize, u64)> = None;
+        let mut sampled = 0;
+        while sampled < self.samples && !state.keys.is_empty() {
+            let i = state.next_index();
+            match candidates.last_access(&state.keys[i]) {
+                Some(accessed) => {
+                    if oldest.is_none_or(|(_, oldest)| accessed < oldest) {
+                        oldest = Some((i, accessed));
+                    }
+                    sampled += 1;
+                }
+                None => {
+                    state.keys.swap_remove(i);
+                    if oldest.is_some_and(|(o, _)| o == state.keys.len()) {
+                        // The oldest sample was just moved into the hole.
+                        oldest = oldest.map(|(_, accessed)| (i, accessed));
+                    }
+                }
+            }
+        }
+
+        let (i, _) = oldest?;
+        Some(state.keys.swap_remove(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn miss_threshold() {
+        let policy = MissThreshold::default();
+        assert!(!policy.should_promote(9, 10));
+        assert!(policy.should_promote(10, 10));
+        assert!(policy.should_promote(1, 0));
+
+        let policy = MissThreshold::new(0.5);
+        assert!(!policy.should_promote(4, 10));
+        assert!(policy.should_promote(5, 10));
+
+        let policy = MissThreshold::new(4.0);
+        assert!(!policy.should_promote(39, 10));
+        assert!(policy.should_promote(40, 10));
+    }
+
+    #[test]
+    fn fifo() {
+        let present: HashMap<u64, u64> = [(1, 0), (3, 0)].into();
+        let accessed = |k: &u64| present.get(k).copied();
+        let candidates = Candidates::new(&accessed, present.len());
+
+        let fifo = Fifo::new();
+        for k in [1, 2, 3] {
+            fifo.inserted(&k);
+        }
+        assert_eq!(fifo.victim(&candidates), Some(1));
+        assert_eq!(fifo.victim(&candidates), Some(3));
+        assert_eq!(fifo.victim(&candidates), None);
+    }
+
+    #[test]
+    fn sampled_lru() {
+        let present: HashMap<u64, u64> = (0..100).map(|k| (k, k)).collect();
+        let accessed = |k: &u64| present.get(k).copied();
+        let candidates = Candidates::new(&accessed, present.len());
+
+        // Sampling many times more keys than there are finds the oldest.
+        let lru = SampledLru::new(10_000);
+        for k in 0..110 {
+            lru.inserted(&k);
+        }
+        assert_eq!(lru.victim(&candidates), Some(0));
+        assert_eq!(lru.victim(&candidates), Some(1));
+        // Keys missing from the map were pruned along the way.
+        assert_eq!(lru.state.lock().keys.len(), 98);
+    }
+
+    #[test]
+    #[should_panic]
+    fn miss_threshold_nan() {
+        MissThreshold::new(f64::NAN);
+    }
+}
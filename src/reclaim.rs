@@ -0,0 +1,332 @@
+//! Epoch-based deferred reclamation.
+//!
+//! A value replaced by a store, or a read map replaced by a promotion, may
+//! still be referenced by a reader that loaded it a moment earlier, so it can
+//! never be freed in place. Instead it is retired into the owning map's
+//! [`Collector`] and destroyed once every thread that was pinned at the time
+//! of retirement has unpinned.
+//!
+//! The map only talks to this module through [`pin`], [`Guard`] and
+//! [`Collector`], so the strategy can be swapped (e.g. for hazard pointers)
+//! without touching the map itself.
+use std::sync::{
+    atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use parking_lot::Mutex;
+
+// The global epoch. It advances in steps of 2 so the low bit of a
+// participant's epoch can be used as the "pinned" flag.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+// Every thread that has ever pinned and not yet been pruned.
+static PARTICIPANTS: Mutex<Vec<Arc<Local>>> = Mutex::new(Vec::new());
+
+const PINNED: usize = 1;
+
+// Retired objects become unreachable once the epoch has advanced twice.
+const GRACE: usize = 4;
+
+// Number of retired objects a collector buffers before it tries to free them.
+const COLLECT_THRESHOLD: usize = 64;
+
+/// Per-thread participant state.
+struct Local {
+    // `EPOCH | PINNED` while pinned, 0 otherwise.
+    epoch: AtomicUsize,
+
+    // Number of live guards. Only touched by the owning thread.
+    pins: AtomicUsize,
+
+    // Set once no new guard can be created through this participant, so it
+    // can be pruned as soon as it is unpinned.
+    detached: AtomicBool,
+}
+
+impl Local {
+    fn new(detached: bool) -> Self {
+        Local {
+            epoch: AtomicUsize::new(0),
+            pins: AtomicUsize::new(0),
+            detached: AtomicBool::new(detached),
+        }
+    }
+}
+
+struct Handle(Arc<Local>);
+
+impl Handle {
+    fn register() -> Self {
+        let local = Arc::new(Local::new(false));
+        PARTICIPANTS.lock().push(local.clone());
+        Handle(local)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.detached.store(true, Ordering::Release);
+    }
+}
+
+thread_local! {
+    static HANDLE: Handle = Handle::register();
+}
+
+/// Keeps the current thread pinned; nothing retired while it is alive will be
+/// destroyed before it is dropped.
+pub(crate) struct Guard {
+    // Kept alive by `PARTICIPANTS` until it is both detached and unpinned.
+    local: *const Local,
+}
+
+impl Guard {
+    fn enter(local: &Arc<Local>) -> Guard {
+        let pins = local.pins.load(Ordering::Relaxed);
+        local.pins.store(pins + 1, Ordering::Relaxed);
+        if pins == 0 {
+            let epoch = EPOCH.load(Ordering::Relaxed);
+            local.epoch.store(epoch | PINNED, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+        }
+
+        Guard {
+            local: Arc::as_ptr(local),
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let local = unsafe { &*self.local };
+        let pins = local.pins.load(Ordering::Relaxed);
+        local.pins.store(pins - 1, Ordering::Relaxed);
+        if pins == 1 {
+            local.epoch.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Pins the current thread.
+pub(crate) fn pin() -> Guard {
+    HANDLE
+        .try_with(|handle| Guard::enter(&handle.0))
+        .unwrap_or_else(|_| {
+            // The thread-local handle is already destroyed, so pin through a
+            // one-off participant instead. Pinning before publishing it under
+            // the lock keeps a concurrent advance from pruning it.
+            let mut participants = PARTICIPANTS.lock();
+            let local = Arc::new(Local::new(true));
+            let guard = Guard::enter(&local);
+            participants.push(local);
+            guard
+        })
+}
+
+// Advances the global epoch if every pinned thread has observed the current
+// one, and returns the (possibly new) epoch.
+fn try_advance() -> usize {
+    let mut participants = PARTICIPANTS.lock();
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+
+    participants.retain(|local| {
+        !(local.detached.load(Ordering::Acquire) && local.epoch.load(Ordering::Relaxed) == 0)
+    });
+    for local in participants.iter() {
+        let local_epoch = local.epoch.load(Ordering::Relaxed);
+        if local_epoch & PINNED != 0 && local_epoch & !PINNED != epoch {
+            return epoch;
+        }
+    }
+
+    fence(Ordering::Acquire);
+    let new = epoch.wrapping_add(2);
+    EPOCH.store(new, Ordering::Release);
+    new
+}
+
+// Whether an object retired at `retired` is unreachable at `epoch`.
+//
+// The epoch may have been advanced by another thread since `epoch` was read,
+// so objects retired after it must count as younger rather than wrap around
+// to look ancient.
+fn is_expired(epoch: usize, retired: usize) -> bool {
+    epoch.wrapping_sub(retired) as isize >= GRACE as isize
+}
+
+struct Deferred {
+    epoch: usize,
+    ptr: *mut (),
+    destroy: unsafe fn(*mut ()),
+}
+
+// The collector's owner only retires objects that are safe to drop on any
+// thread.
+unsafe impl Send for Deferred {}
+
+/// Objects retired by one map, waiting for a grace period to elapse.
+pub(crate) struct Collector {
+    garbage: Mutex<Vec<Deferred>>,
+    len: AtomicUsize,
+}
+
+impl Collector {
+    pub(crate) const fn new() -> Self {
+        Collector {
+            garbage: Mutex::new(Vec::new()),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Defers dropping the box behind `ptr` until no pinned thread can still
+    /// observe it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable for
+    /// threads that pin after this call, and must not be retired twice.
+    pub(crate) unsafe fn retire<T>(&self, ptr: *mut T) {
+        unsafe fn destroy<T>(ptr: *mut ()) {
+            drop(Box::from_raw(ptr as *mut T));
+        }
+
+        fence(Ordering::SeqCst);
+        let epoch = EPOCH.load(Ordering::Relaxed);
+        let mut garbage = self.garbage.lock();
+        garbage.push(Deferred {
+            epoch,
+            ptr: ptr as *mut (),
+            destroy: destroy::<T>,
+        });
+        self.len.store(garbage.len(), Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Relaxed) == 0
+    }
+
+    /// Destroys the retired objects that no pinned thread can still observe.
+    ///
+    /// Must not be called with a lock held that a destructor might take.
+    pub(crate) fn collect(&self) {
+        if self.len.load(Ordering::Relaxed) < COLLECT_THRESHOLD {
+            return;
+        }
+        self.collect_now();
+    }
+
+    /// Like [`Collector::collect`], however few objects are waiting.
+    pub(crate) fn collect_now(&self) {
+        let epoch = try_advance();
+        let ready: Vec<Deferred> = {
+            let mut garbage = self.garbage.lock();
+            let (ready, pending) = garbage.drain(..).partition(|d| is_expired(epoch, d.epoch));
+            *garbage = pending;
+            self.len.store(garbage.len(), Ordering::Relaxed);
+            ready
+        };
+
+        for d in ready {
+            unsafe { (d.destroy)(d.ptr) };
+        }
+    }
+
+    /// Destroys every retired object.
+    ///
+    /// Exclusive access to the collector implies exclusive access to the map
+    /// that owns it, so nobody can still be reading what was retired into it.
+    pub(crate) fn flush(&mut self) {
+        for d in self.garbage.get_mut().drain(..) {
+            unsafe { (d.destroy)(d.ptr) };
+        }
+        *self.len.get_mut() = 0;
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn retired_outlives_guard() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+
+        let guard = pin();
+        for _ in 0..COLLECT_THRESHOLD {
+            unsafe { collector.retire(Box::into_raw(Box::new(Counted(&dropped)))) };
+        }
+        for _ in 0..GRACE {
+            collector.collect();
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+
+        // Other tests may be pinned concurrently, so give them a chance to
+        // unpin.
+        drop(guard);
+        for _ in 0..1000 {
+            collector.collect();
+            if dropped.load(Ordering::Relaxed) == COLLECT_THRESHOLD {
+                break;
+            }
+            std::thread::yield_now();
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), COLLECT_THRESHOLD);
+    }
+
+    #[test]
+    fn expired() {
+        assert!(!is_expired(8, 8));
+        assert!(!is_expired(8, 6));
+        assert!(is_expired(8, 4));
+        // Retired after the epoch was read.
+        assert!(!is_expired(8, 10));
+        assert!(is_expired(2, usize::MAX - 1));
+    }
+
+    #[test]
+    fn drop_collector() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+        unsafe { collector.retire(Box::into_raw(Box::new(Counted(&dropped)))) };
+        drop(collector);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn flush() {
+        let dropped = AtomicUsize::new(0);
+        let mut collector = Collector::new();
+        let _guard = pin();
+        unsafe { collector.retire(Box::into_raw(Box::new(Counted(&dropped)))) };
+        collector.flush();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn reentrant_pin() {
+        let outer = pin();
+        let inner = pin();
+        drop(outer);
+        drop(inner);
+    }
+}
@@ -0,0 +1,179 @@
+//! A concurrent set built on [`SyncMap`].
+use std::{borrow::Borrow, collections::hash_map::RandomState, fmt, hash::BuildHasher};
+
+use crate::map::{Ref, SyncMap};
+
+/// A concurrent set: a [`SyncMap`] whose values are `()`.
+///
+/// Membership tests go through the map's read map, so they are lock free for
+/// members that have been promoted. `()` is zero-sized, so members cost no
+/// value allocation.
+pub struct SyncSet<T, S = RandomState>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<T, (), S>,
+}
+
+impl<T> SyncSet<T, RandomState>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new() -> Self {
+        SyncSet::with_hasher(RandomState::new())
+    }
+}
+
+impl<T> Default for SyncSet<T, RandomState>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        SyncSet::new()
+    }
+}
+
+impl<T, S> SyncSet<T, S>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        SyncSet {
+            map: SyncMap::with_hasher(hash_builder),
+        }
+    }
+
+    /// Adds a value to the set. Returns whether it was newly added.
+    pub fn insert(&self, value: T) -> bool {
+        let (_, loaded) = self.map.load_or_store(value, ());
+        !loaded
+    }
+
+    /// Returns whether the set contains a value.
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.contains_key(value)
+    }
+
+    /// Removes a value from the set. Returns whether it was present.
+    pub fn remove<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.remove(value).is_some()
+    }
+
+    /// Returns an iterator over the values in the set. See
+    /// [`SyncMap::iter`].
+    pub fn iter(&self) -> impl Iterator<Item = Ref<'_, T>> {
+        self.map.iter().map(|r| r.into_key())
+    }
+
+    /// Retains only the values for which `f` returns true. See
+    /// [`SyncMap::retain`].
+    pub fn retain(&self, mut f: impl FnMut(&T) -> bool) {
+        self.map.retain(|k, _| f(k));
+    }
+
+    /// Counts the values in the set.
+    ///
+    /// Takes time linear in the size of the set, and may or may not count
+    /// values inserted or removed concurrently.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.map.range(|_, _| {
+            len += 1;
+            true
+        });
+        len
+    }
+
+    /// Returns whether the set holds no value. See [`SyncSet::len`].
+    pub fn is_empty(&self) -> bool {
+        let mut empty = true;
+        self.map.range(|_, _| {
+            empty = false;
+            false
+        });
+        empty
+    }
+}
+
+impl<T, S> FromIterator<T> for SyncSet<T, S>
+where
+    T: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        SyncSet {
+            map: iter.into_iter().map(|value| (value, ())).collect(),
+        }
+    }
+}
+
+impl<T, S> fmt::Debug for SyncSet<T, S>
+where
+    T: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_set();
+        self.map.range(|k, _| {
+            s.entry(k);
+            true
+        });
+        s.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn insert() {
+        let set = SyncSet::new();
+        assert!(set.is_empty());
+        assert!(set.insert(1));
+        assert!(!set.insert(1));
+        assert!(set.contains(&1));
+        assert!(!set.contains(&2));
+        assert_eq!(set.len(), 1);
+        assert_eq!(format!("{set:?}"), "{1}");
+
+        assert!(set.remove(&1));
+        assert!(!set.remove(&1));
+        assert!(!set.contains(&1));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn iter() {
+        let set: SyncSet<u64> = (0..10).collect();
+        set.retain(|v| v % 2 == 0);
+        let mut values: Vec<_> = set.iter().map(|v| *v).collect();
+        values.sort();
+        assert_eq!(values, [0, 2, 4, 6, 8]);
+        assert_eq!(set.len(), 5);
+    }
+
+    #[test]
+    fn concurrent() {
+        let set = SyncSet::new();
+        let inserted: usize = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..1000).filter(|&i| set.insert(i)).count()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(inserted, 1000);
+        assert_eq!(set.len(), 1000);
+    }
+}
@@ -0,0 +1,520 @@
+//! A map partitioned across independent [`SyncMap`] shards.
+//!
+//! Every [`SyncMap`] serializes the writes that miss its read map on a single
+//! dirty lock. Under sustained writes of new keys that lock, and the copy of
+//! the read map each promotion is followed by, become the bottleneck.
+//! Partitioning keys by hash spreads both across shards.
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::BuildHasher,
+    sync::Arc,
+    thread,
+};
+
+use crate::{
+    atomic::AtomicValue,
+    hooks::Hooks,
+    map::{
+        EntryGuard, EntryRef, Full, Maintenance, MapEntry, OccupiedError, Ref, RefMut, RefPair,
+        SyncMap,
+    },
+    policy::{DirtyOverflow, MissThreshold, PromotionPolicy},
+};
+
+/// A concurrent map made of independent [`SyncMap`] shards, each owning the
+/// keys whose hash selects it.
+///
+/// Operations on a single key behave exactly as on a [`SyncMap`]; iteration
+/// visits the shards one after the other.
+pub struct ShardedSyncMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    shards: Box<[SyncMap<K, V, S>]>,
+
+    // Selects a shard from the bits of the hash just below the top 7, which
+    // the shards' own tables use as control bytes.
+    shift: u32,
+
+    hash_builder: S,
+}
+
+impl<K, V> ShardedSyncMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    /// Creates an empty map with a shard count derived from the available
+    /// parallelism.
+    pub fn new() -> Self {
+        ShardedSyncMap::with_shards(default_shards())
+    }
+
+    /// Creates an empty map with at least `shards` shards, rounded up to a
+    /// power of two.
+    pub fn with_shards(shards: usize) -> Self {
+        ShardedSyncMap::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K, V> Default for ShardedSyncMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        ShardedSyncMap::new()
+    }
+}
+
+impl<K, V, S> ShardedSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Creates an empty map with at least `shards` shards, using
+    /// `hash_builder` both to pick a shard and within every shard.
+    pub fn with_shards_and_hasher(shards: usize, hash_builder: S) -> Self {
+        ShardedSyncMap::with_policy(shards, 0, hash_builder, Arc::new(MissThreshold::default()))
+    }
+
+    pub(crate) fn with_policy(
+        shards: usize,
+        capacity: usize,
+        hash_builder: S,
+        policy: Arc<dyn PromotionPolicy>,
+    ) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        let capacity = capacity.div_ceil(shards);
+        ShardedSyncMap {
+            shards: (0..shards)
+                .map(|_| SyncMap::with_policy(capacity, hash_builder.clone(), policy.clone()))
+                .collect(),
+            shift: u64::BITS - shards.trailing_zeros(),
+            hash_builder,
+        }
+    }
+
+    pub(crate) fn set_hooks(&mut self, hooks: Hooks<K, V>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_hooks(hooks.clone());
+        }
+    }
+
+    pub(crate) fn set_compaction(&mut self, ratio: f64) {
+        for shard in self.shards.iter_mut() {
+            shard.set_compaction(ratio);
+        }
+    }
+
+    pub(crate) fn set_dirty_limit(&mut self, max_len: usize, overflow: DirtyOverflow) {
+        for shard in self.shards.iter_mut() {
+            shard.set_dirty_limit(max_len, overflow);
+        }
+    }
+
+    /// Returns the shards, e.g. to work on them in parallel.
+    pub fn shards(&self) -> &[SyncMap<K, V, S>] {
+        &self.shards
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &SyncMap<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+        let hash = self.hash_builder.hash_one(key);
+        ((hash << 7) >> self.shift) as usize
+    }
+
+    /// Returns the value stored in the map for a key.
+    pub fn load<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).load(key)
+    }
+
+    /// Returns whether the map holds a value for a key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).contains_key(key)
+    }
+
+    /// See [`SyncMap::get_key_value`].
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<RefPair<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).get_key_value(key)
+    }
+
+    /// Sets the value for a key.
+    pub fn store(&self, key: K, value: V) {
+        self.shard(&key).store(key, value)
+    }
+
+    /// Sets the value for a key, unless the dirty map of its shard is full.
+    /// See [`SyncMap::store_with_limit`].
+    pub fn store_with_limit(&self, key: K, value: V) -> Result<(), Full<(K, V)>> {
+        self.shard(&key).store_with_limit(key, value)
+    }
+
+    /// Swaps the value for a key and returns the previous value if any.
+    pub fn swap(&self, key: K, value: V) -> Option<Ref<'_, V>> {
+        self.shard(&key).swap(key, value)
+    }
+
+    /// See [`SyncMap::load_or_store`].
+    pub fn load_or_store(&self, key: K, value: V) -> (Ref<'_, V>, bool) {
+        self.shard(&key).load_or_store(key, value)
+    }
+
+    /// See [`SyncMap::try_insert`].
+    pub fn try_insert(&self, key: K, value: V) -> Result<(), OccupiedError<'_, V>> {
+        self.shard(&key).try_insert(key, value)
+    }
+
+    /// See [`SyncMap::get_or_try_insert_with`].
+    pub fn get_or_try_insert_with<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Ref<'_, V>, E> {
+        self.shard(&key).get_or_try_insert_with(key, f)
+    }
+
+    /// See [`SyncMap::update`].
+    pub fn update<Q>(&self, key: &Q, f: impl FnMut(&V) -> V) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).update(key, f)
+    }
+
+    /// See [`SyncMap::upsert`].
+    pub fn upsert(
+        &self,
+        key: K,
+        insert: impl FnOnce() -> V,
+        modify: impl FnMut(&V) -> V,
+    ) -> Ref<'_, V> {
+        self.shard(&key).upsert(key, insert, modify)
+    }
+
+    /// Deletes the value for a key, returning the previous value if any.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).remove(key)
+    }
+
+    /// See [`SyncMap::entry`].
+    pub fn entry(&self, key: K) -> MapEntry<'_, K, V, S> {
+        self.shard(&key).entry(key)
+    }
+
+    /// See [`SyncMap::get_mut`].
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+        V: Clone,
+    {
+        self.shard(key).get_mut(key)
+    }
+
+    /// See [`SyncMap::lock_entry`].
+    pub fn lock_entry(&self, key: K) -> EntryGuard<'_, K, V, S> {
+        self.shard(&key).lock_entry(key)
+    }
+
+    /// Returns a handle to run the upkeep of every shard in bounded steps.
+    /// See [`SyncMap::maintenance`].
+    pub fn maintenance(&self) -> Maintenance<'_> {
+        Maintenance::all(self.shards.iter().map(SyncMap::maintenance).collect())
+    }
+
+    /// Returns a handle to the entry for a key, which can be kept to load and
+    /// write the key without hashing it again. See [`SyncMap::entry_ref`].
+    pub fn entry_ref(&self, key: K) -> EntryRef<'_, K, V, S> {
+        self.shard(&key).entry_ref(key)
+    }
+
+    /// Calls `f` for each key and value present in the map, one shard after
+    /// the other, until it returns false. See [`SyncMap::range`].
+    pub fn range(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        let mut more = true;
+        for shard in self.shards.iter() {
+            shard.range(|k, v| {
+                more = f(k, v);
+                more
+            });
+            if !more {
+                break;
+            }
+        }
+    }
+
+    /// Retains only the entries for which `f` returns true. See
+    /// [`SyncMap::retain`].
+    pub fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.retain(&mut f);
+        }
+    }
+
+    /// Compacts every shard. See [`SyncMap::shrink_to_fit`].
+    pub fn shrink_to_fit(&self) {
+        for shard in self.shards.iter() {
+            shard.shrink_to_fit();
+        }
+    }
+
+    /// Returns the total weight of all shards. See [`SyncMap::weight`].
+    pub fn weight(&self) -> usize {
+        self.shards.iter().map(SyncMap::weight).sum()
+    }
+
+    /// Returns the counters of all shards added up.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Stats {
+        self.shards
+            .iter()
+            .map(SyncMap::stats)
+            .fold(Default::default(), crate::stats::Stats::merge)
+    }
+
+    /// Returns an iterator over the keys and values present in the map, one
+    /// shard after the other. See [`SyncMap::iter`].
+    pub fn iter(&self) -> impl Iterator<Item = RefPair<'_, K, V>> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    /// Returns an iterator over the keys present in each shard when the
+    /// iteration reaches it. See [`SyncMap::snapshot_iter`].
+    pub fn snapshot_iter(&self) -> impl Iterator<Item = RefPair<'_, K, V>> {
+        self.shards.iter().flat_map(|shard| shard.snapshot_iter())
+    }
+}
+
+impl<K, V, S> ShardedSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: PartialEq,
+    S: BuildHasher + Clone,
+{
+    /// See [`SyncMap::compare_and_swap`].
+    pub fn compare_and_swap<Q>(&self, key: &Q, old: &V, new: V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).compare_and_swap(key, old, new)
+    }
+
+    /// See [`SyncMap::compare_and_remove`].
+    pub fn compare_and_remove<Q>(&self, key: &Q, old: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).compare_and_remove(key, old)
+    }
+}
+
+impl<K, V, S> ShardedSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Copies the keys and values present in every shard into a `HashMap`.
+    /// Each shard is copied at a different point in time. See
+    /// [`SyncMap::snapshot`].
+    pub fn snapshot(&self) -> HashMap<K, V, S> {
+        let mut m = HashMap::with_hasher(self.hash_builder.clone());
+        for shard in self.shards.iter() {
+            m.extend(shard.snapshot());
+        }
+        m
+    }
+}
+
+impl<K, V, S> ShardedSyncMap<K, Arc<V>, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    /// Returns a clone of the `Arc` stored for a key. See
+    /// [`SyncMap::load_shared`].
+    pub fn load_shared<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.shard(key).load_shared(key)
+    }
+}
+
+impl<K, V, S> ShardedSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    V: AtomicValue,
+    S: BuildHasher + Clone,
+{
+    /// Adds `delta` to the counter for a key and returns the new count. See
+    /// [`SyncMap::increment`].
+    pub fn increment(&self, key: K, delta: V::Value) -> V::Value {
+        self.shard(&key).increment(key, delta)
+    }
+
+    /// Adds `delta` to the counter for a key and returns the previous count.
+    /// See [`SyncMap::fetch_add`].
+    pub fn fetch_add(&self, key: K, delta: V::Value) -> V::Value {
+        self.shard(&key).fetch_add(key, delta)
+    }
+
+    /// Replaces the counter for a key with `f(count)`. See
+    /// [`SyncMap::fetch_update`].
+    pub fn fetch_update(
+        &self,
+        key: K,
+        f: impl FnMut(V::Value) -> Option<V::Value>,
+    ) -> Result<V::Value, V::Value> {
+        self.shard(&key).fetch_update(key, f)
+    }
+}
+
+impl<K, V, S> fmt::Debug for ShardedSyncMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        self.range(|k, v| {
+            m.entry(k, v);
+            true
+        });
+        m.finish()
+    }
+}
+
+// A few shards per thread keeps two writers from sharing one most of the
+// time.
+fn default_shards() -> usize {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    (threads * 4).next_power_of_two()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn shards() {
+        assert_eq!(ShardedSyncMap::<u64, u64>::with_shards(0).shards().len(), 1);
+        assert_eq!(ShardedSyncMap::<u64, u64>::with_shards(5).shards().len(), 8);
+
+        let map = ShardedSyncMap::with_shards(16);
+        for i in 0..1000 {
+            map.store(i, i);
+        }
+        for shard in map.shards() {
+            assert!(shard.iter().count() > 0);
+        }
+        for i in 0..1000 {
+            assert_eq!(*map.load(&i).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn operations() {
+        let map = ShardedSyncMap::new();
+        assert!(map.load(&1).is_none());
+        map.store(1, 10);
+        assert_eq!(*map.swap(1, 11).unwrap(), 10);
+        assert_eq!(*map.load_or_store(1, 12).0, 11);
+        assert!(map.contains_key(&1));
+        assert_eq!(map.get_key_value(&1).unwrap().pair(), (&1, &11));
+        assert_eq!(map.try_insert(1, 12).unwrap_err().value, 12);
+        assert_eq!(*map.update(&1, |v| v + 1).unwrap(), 11);
+        assert_eq!(*map.upsert(2, || 20, |v| v + 1), 20);
+        assert_eq!(*map.entry(2).and_modify(|v| v + 1).or_insert(0), 21);
+        assert!(map.compare_and_swap(&2, &21, 22));
+        assert!(map.compare_and_remove(&2, &22));
+        assert_eq!(*map.remove(&1).unwrap(), 12);
+        assert!(map.load(&1).is_none());
+        assert_eq!(format!("{map:?}"), "{}");
+
+        let map = ShardedSyncMap::new();
+        map.store(1, Arc::new(10));
+        assert_eq!(map.load_shared(&1), Some(Arc::new(10)));
+
+        let map: ShardedSyncMap<_, std::sync::atomic::AtomicU64> = ShardedSyncMap::new();
+        assert_eq!(map.increment(1, 2), 2);
+        assert_eq!(map.fetch_add(1, 3), 2);
+        assert_eq!(map.fetch_update(1, |v| Some(v * 2)), Ok(5));
+        assert_eq!(map.increment(1, 0), 10);
+    }
+
+    #[test]
+    fn range() {
+        let map = ShardedSyncMap::with_shards(4);
+        for i in 0..100 {
+            map.store(i, i);
+        }
+        map.retain(|k, _| k % 2 == 0);
+
+        let mut seen: Vec<_> = map.iter().map(|r| *r.key()).collect();
+        seen.sort();
+        assert_eq!(seen, (0..100).step_by(2).collect::<Vec<_>>());
+        assert_eq!(map.snapshot().len(), 50);
+
+        let mut count = 0;
+        map.range(|_, _| {
+            count += 1;
+            count < 10
+        });
+        assert_eq!(count, 10);
+    }
+
+    #[test]
+    fn concurrent() {
+        let map = ShardedSyncMap::with_shards(8);
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        map.store(t * 1000 + i, i);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.iter().count(), 4000);
+    }
+}
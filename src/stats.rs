@@ -0,0 +1,104 @@
+//! Counters describing how lookups are served, to tune the
+//! [promotion policy](crate::policy).
+//!
+//! The counters are only maintained with the `stats` feature enabled;
+//! otherwise recording compiles to nothing.
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of a map's counters, returned by `SyncMap::stats`.
+///
+/// Lookups are the key searches of `load`, `update`, `remove` and the
+/// compare-and-swap family.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Lookups answered by the read map, without taking the lock.
+    pub read_hits: u64,
+
+    /// Lookups answered by the dirty map, with the lock held. Each one counts
+    /// towards the next promotion.
+    pub dirty_hits: u64,
+
+    /// Lookups of keys in neither map.
+    pub misses: u64,
+
+    /// Promotions of the dirty map to the read map.
+    pub promotions: u64,
+
+    /// The number of entries in the dirty map, deleted ones included.
+    pub dirty_len: usize,
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    pub(crate) fn merge(self, other: Stats) -> Stats {
+        Stats {
+            read_hits: self.read_hits + other.read_hits,
+            dirty_hits: self.dirty_hits + other.dirty_hits,
+            misses: self.misses + other.misses,
+            promotions: self.promotions + other.promotions,
+            dirty_len: self.dirty_len + other.dirty_len,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    #[cfg(feature = "stats")]
+    read_hits: AtomicU64,
+    #[cfg(feature = "stats")]
+    dirty_hits: AtomicU64,
+    #[cfg(feature = "stats")]
+    misses: AtomicU64,
+    #[cfg(feature = "stats")]
+    promotions: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl Counters {
+    #[inline]
+    pub(crate) fn read_hit(&self) {
+        self.read_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn dirty_hit(&self) {
+        self.dirty_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn promotion(&self) {
+        self.promotions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, dirty_len: usize) -> Stats {
+        Stats {
+            read_hits: self.read_hits.load(Ordering::Relaxed),
+            dirty_hits: self.dirty_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            promotions: self.promotions.load(Ordering::Relaxed),
+            dirty_len,
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl Counters {
+    #[inline(always)]
+    pub(crate) fn read_hit(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn dirty_hit(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn miss(&self) {}
+
+    #[inline(always)]
+    pub(crate) fn promotion(&self) {}
+}
@@ -0,0 +1,394 @@
+//! A map whose keys can be watched for changes.
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    fmt,
+    future::Future,
+    hash::BuildHasher,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll, Waker},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    map::{MapEntry, Ref, SyncMap},
+    weak::SyncWeakMap,
+};
+
+/// A [`SyncMap`] whose keys can be subscribed to, e.g. for a configuration
+/// registry that would otherwise be polled.
+///
+/// Every write locks its key's entry and updates the key's subscribers before
+/// unlocking it, so subscribers see the writes to a key in the order they
+/// were made, and [`subscribe`] cannot miss a concurrent one.
+///
+/// [`subscribe`]: SyncWatchMap::subscribe
+pub struct SyncWatchMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<K, V, S>,
+
+    // A channel lives as long as one of its receivers does.
+    channels: SyncWeakMap<K, Channel<V>, S>,
+
+    // Every channel, to close when the map is dropped.
+    all: Mutex<Vec<Weak<Channel<V>>>>,
+}
+
+impl<K, V> SyncWatchMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        SyncWatchMap::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V> Default for SyncWatchMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        SyncWatchMap::new()
+    }
+}
+
+impl<K, V, S> SyncWatchMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        SyncWatchMap {
+            map: SyncMap::with_hasher(hash_builder.clone()),
+            channels: SyncWeakMap::with_hasher(hash_builder),
+            all: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the value stored in the map for a key.
+    pub fn load<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.load(key)
+    }
+
+    /// Sets the value for a key and notifies its subscribers.
+    pub fn store(&self, key: K, value: V) {
+        let entry = self.map.entry(key);
+        if let Some(channel) = self.channels.load(entry.key()) {
+            channel.send(Some(value.clone()));
+        }
+        match entry {
+            MapEntry::Occupied(mut e) => {
+                e.insert(value);
+            }
+            MapEntry::Vacant(e) => {
+                e.insert(value);
+            }
+        }
+    }
+
+    /// Deletes the value for a key, returning it, and notifies the key's
+    /// subscribers if there was one.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Ref<'_, V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        let key = self.map.get_key_value(key)?.key().clone();
+        match self.map.entry(key) {
+            MapEntry::Occupied(e) => {
+                if let Some(channel) = self.channels.load::<K>(e.key()) {
+                    channel.send(None);
+                }
+                Some(e.remove())
+            }
+            // Removed since we looked it up.
+            MapEntry::Vacant(_) => None,
+        }
+    }
+
+    /// Returns a receiver that holds the current value for a key and is
+    /// notified every time the key is stored or removed.
+    pub fn subscribe(&self, key: K) -> Receiver<V> {
+        let entry = self.map.entry(key.clone());
+        let value = match &entry {
+            MapEntry::Occupied(e) => Some(e.get().clone()),
+            MapEntry::Vacant(_) => None,
+        };
+        let channel = self.channels.load_or_insert_with(key, || {
+            let channel = Arc::new(Channel::new(value));
+            let mut all = self.all.lock();
+            // Drop the dead channels whenever the list would grow.
+            if all.len() == all.capacity() {
+                all.retain(|c| c.strong_count() > 0);
+            }
+            all.push(Arc::downgrade(&channel));
+            channel
+        });
+        drop(entry);
+
+        let seen = channel.state.lock().version;
+        Receiver { channel, seen }
+    }
+}
+
+impl<K, V, S> Drop for SyncWatchMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn drop(&mut self) {
+        for channel in self.all.get_mut().iter().filter_map(Weak::upgrade) {
+            channel.close();
+        }
+    }
+}
+
+struct Channel<V> {
+    state: Mutex<State<V>>,
+}
+
+struct State<V> {
+    // Bumped by every send.
+    version: u64,
+    value: Option<V>,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+impl<V> Channel<V> {
+    fn new(value: Option<V>) -> Self {
+        Channel {
+            state: Mutex::new(State {
+                version: 0,
+                value,
+                closed: false,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    fn send(&self, value: Option<V>) {
+        let mut state = self.state.lock();
+        state.version += 1;
+        let previous = std::mem::replace(&mut state.value, value);
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+
+        drop(previous);
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Receives the values stored for one key of a [`SyncWatchMap`], created by
+/// [`SyncWatchMap::subscribe`].
+///
+/// Only the latest value is kept: a receiver that falls behind skips the
+/// values stored in between.
+pub struct Receiver<V> {
+    channel: Arc<Channel<V>>,
+
+    // The version the receiver last saw.
+    seen: u64,
+}
+
+impl<V: Clone> Receiver<V> {
+    /// Returns the latest value, without marking it as seen.
+    pub fn borrow(&self) -> Option<V> {
+        self.channel.state.lock().value.clone()
+    }
+
+    /// Returns the latest value and marks it as seen.
+    pub fn borrow_and_update(&mut self) -> Option<V> {
+        let state = self.channel.state.lock();
+        self.seen = state.version;
+        state.value.clone()
+    }
+}
+
+impl<V> Receiver<V> {
+    /// Returns whether a value has been stored or removed since the last one
+    /// was seen.
+    pub fn has_changed(&self) -> bool {
+        self.channel.state.lock().version != self.seen
+    }
+
+    /// Waits for a value to be stored or removed since the last one was
+    /// seen, and marks it as seen.
+    ///
+    /// Fails once the map is dropped and every change has been seen.
+    pub fn changed(&mut self) -> Changed<'_, V> {
+        Changed { receiver: self }
+    }
+}
+
+impl<V> Clone for Receiver<V> {
+    fn clone(&self) -> Self {
+        Receiver {
+            channel: self.channel.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<V> fmt::Debug for Receiver<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`Receiver::changed`].
+pub struct Changed<'a, V> {
+    receiver: &'a mut Receiver<V>,
+}
+
+impl<V> Future for Changed<'_, V> {
+    type Output = Result<(), Closed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let receiver = &mut *self.get_mut().receiver;
+        let mut state = receiver.channel.state.lock();
+        if state.version != receiver.seen {
+            receiver.seen = state.version;
+            Poll::Ready(Ok(()))
+        } else if state.closed {
+            Poll::Ready(Err(Closed))
+        } else {
+            if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl<V> fmt::Debug for Changed<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Changed").finish_non_exhaustive()
+    }
+}
+
+/// The error returned by [`Receiver::changed`] once the map is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the map was dropped")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+#[cfg(test)]
+mod tests {
+    use std::{task::Wake, thread};
+
+    use super::*;
+
+    fn poll<V>(changed: &mut Changed<'_, V>) -> Poll<Result<(), Closed>> {
+        Pin::new(changed).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    // Runs a future to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn subscribe() {
+        let map = SyncWatchMap::new();
+        map.store("a", 1);
+        let mut rx = map.subscribe("a");
+        assert_eq!(rx.borrow(), Some(1));
+        assert!(!rx.has_changed());
+        assert!(poll(&mut rx.changed()).is_pending());
+
+        map.store("a", 2);
+        map.store("a", 3);
+        assert!(rx.has_changed());
+        assert_eq!(poll(&mut rx.changed()), Poll::Ready(Ok(())));
+        assert_eq!(rx.borrow_and_update(), Some(3));
+        assert!(!rx.has_changed());
+
+        assert_eq!(*map.remove("a").unwrap(), 3);
+        assert!(map.remove("a").is_none());
+        assert_eq!(rx.borrow_and_update(), None);
+
+        let mut missing = map.subscribe("b");
+        assert_eq!(missing.borrow(), None);
+        drop(map);
+        assert_eq!(poll(&mut missing.changed()), Poll::Ready(Err(Closed)));
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let map = SyncWatchMap::new();
+        let rx = map.subscribe(1);
+        map.store(1, "a");
+        assert!(map.channels.load(&1).is_some());
+        drop(rx);
+        assert!(map.channels.load(&1).is_none());
+        map.store(1, "b");
+        assert_eq!(*map.load(&1).unwrap(), "b");
+    }
+
+    #[test]
+    fn wake() {
+        let map = Arc::new(SyncWatchMap::new());
+        let mut rx = map.subscribe(1);
+        let writer = thread::spawn({
+            let map = map.clone();
+            move || {
+                for i in 0..1000 {
+                    map.store(1, i);
+                }
+            }
+        });
+
+        // Every wake up sees a newer value than the last.
+        let mut last = None;
+        while last != Some(999) {
+            block_on(rx.changed()).unwrap();
+            let value = rx.borrow_and_update();
+            assert!(value > last);
+            last = value;
+        }
+        writer.join().unwrap();
+        assert_eq!(last, Some(999));
+    }
+}
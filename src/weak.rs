@@ -0,0 +1,180 @@
+//! A map holding weak references to its values.
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    sync::{Arc, Weak},
+};
+
+use crate::map::{Maintenance, MapEntry, SyncMap};
+
+/// A [`SyncMap`] that stores [`Weak`] references and upgrades them on load,
+/// e.g. for a registry of live objects.
+///
+/// A key whose value has been dropped everywhere else reads as absent. The
+/// entry itself is only dropped the next time the dirty map is copied from
+/// the read map, so no sweep is needed.
+pub struct SyncWeakMap<K, V, S = RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    map: SyncMap<K, Weak<V>, S>,
+}
+
+impl<K, V> SyncWeakMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    pub fn new() -> Self {
+        SyncWeakMap::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V> Default for SyncWeakMap<K, V, RandomState>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+{
+    fn default() -> Self {
+        SyncWeakMap::new()
+    }
+}
+
+impl<K, V, S> SyncWeakMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash,
+    S: BuildHasher + Clone,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        let mut map = SyncMap::with_hasher(hash_builder);
+        map.set_stale(|v: &Weak<V>| v.strong_count() == 0);
+        SyncWeakMap { map }
+    }
+
+    /// Returns the value stored for a key, if it is still alive.
+    pub fn load<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.load(key)?.upgrade()
+    }
+
+    /// Sets the value for a key, without keeping it alive.
+    pub fn store(&self, key: K, value: &Arc<V>) {
+        self.map.store(key, Arc::downgrade(value));
+    }
+
+    /// Returns the live value for a key, or stores and returns `f()` if there
+    /// is none. `f` is called with the key locked, at most once.
+    pub fn load_or_insert_with(&self, key: K, f: impl FnOnce() -> Arc<V>) -> Arc<V> {
+        match self.map.entry(key) {
+            MapEntry::Occupied(mut e) => {
+                if let Some(v) = e.get().upgrade() {
+                    return v;
+                }
+                let v = f();
+                e.insert(Arc::downgrade(&v));
+                v
+            }
+            MapEntry::Vacant(e) => {
+                let v = f();
+                e.insert(Arc::downgrade(&v));
+                v
+            }
+        }
+    }
+
+    /// Deletes the value for a key, returning it if it was still alive.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+    {
+        self.map.remove(key)?.upgrade()
+    }
+
+    /// Calls `f` for each key whose value is alive, until it returns false.
+    /// See [`SyncMap::range`].
+    pub fn range(&self, mut f: impl FnMut(&K, Arc<V>) -> bool) {
+        self.map.range(|k, v| match v.upgrade() {
+            Some(v) => f(k, v),
+            None => true,
+        });
+    }
+
+    /// Returns a handle to drop the keys whose values have been dropped, a
+    /// batch at a time. See [`SyncMap::maintenance`].
+    pub fn maintenance(&self) -> Maintenance<'_> {
+        self.map.maintenance()
+    }
+}
+
+impl<K, V, S> fmt::Debug for SyncWeakMap<K, V, S>
+where
+    K: std::cmp::Eq + std::hash::Hash + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        self.range(|k, v| {
+            m.entry(k, &v);
+            true
+        });
+        m.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let map = SyncWeakMap::new();
+        let a = Arc::new(1);
+        map.store("a", &a);
+        assert_eq!(map.load("a"), Some(a.clone()));
+        assert_eq!(format!("{map:?}"), r#"{"a": 1}"#);
+
+        drop(a);
+        assert!(map.load("a").is_none());
+        assert_eq!(format!("{map:?}"), "{}");
+    }
+
+    #[test]
+    fn load_or_insert_with() {
+        let map = SyncWeakMap::new();
+        let a = map.load_or_insert_with(1, || Arc::new("a"));
+        assert!(Arc::ptr_eq(
+            &map.load_or_insert_with(1, || Arc::new("b")),
+            &a
+        ));
+
+        drop(a);
+        assert_eq!(*map.load_or_insert_with(1, || Arc::new("c")), "c");
+    }
+
+    #[test]
+    fn prune() {
+        let map = SyncWeakMap::new();
+        let live: Vec<_> = (0..10).map(Arc::new).collect();
+        for (i, v) in live.iter().enumerate() {
+            map.store(i, v);
+        }
+        let dead = Arc::new(10);
+        map.store(10, &dead);
+        // Promote the dirty map.
+        map.range(|_, _| true);
+        assert!(map.map.is_promoted(&10));
+
+        drop(dead);
+        // Copying the read map into a new dirty map drops the dead entry.
+        map.store(11, &live[0]);
+        map.range(|_, _| true);
+        assert!(!map.map.is_promoted(&10));
+        assert_eq!(map.remove(&0), Some(live[0].clone()));
+        assert!(map.load(&0).is_none());
+    }
+}